@@ -4,9 +4,12 @@
 
 DROP TABLE monitoring CASCADE;
 DROP TABLE slot CASCADE;
+DROP TABLE block_reward CASCADE;
 DROP TABLE account_write CASCADE;
+DROP TABLE transaction CASCADE;
 DROP TABLE pubkey CASCADE;
 DROP TYPE "SlotStatus";
+DROP TYPE "RewardType";
 
 DROP TABLE mango_group_write CASCADE;
 DROP TABLE mango_cache_write CASCADE;
@@ -0,0 +1,41 @@
+/**
+ * Adds the block metadata columns of the slot table and the tables for block
+ * rewards and transactions to databases created before they existed. Safe to
+ * run more than once.
+ */
+
+ALTER TABLE slot ADD COLUMN IF NOT EXISTS blockhash VARCHAR(44);
+ALTER TABLE slot ADD COLUMN IF NOT EXISTS block_time TIMESTAMP WITH TIME ZONE;
+ALTER TABLE slot ADD COLUMN IF NOT EXISTS block_height BIGINT;
+CREATE INDEX IF NOT EXISTS slot_block_time_idx ON slot (block_time);
+
+DO $$ BEGIN
+    CREATE TYPE "RewardType" AS ENUM (
+        'Fee',
+        'Rent',
+        'Staking',
+        'Voting'
+    );
+EXCEPTION
+    WHEN duplicate_object THEN NULL;
+END $$;
+
+CREATE TABLE IF NOT EXISTS block_reward (
+    slot BIGINT NOT NULL,
+    pubkey VARCHAR(44) NOT NULL,
+    lamports BIGINT NOT NULL,
+    post_balance BIGINT NOT NULL,
+    reward_type "RewardType",
+    commission SMALLINT
+);
+CREATE INDEX IF NOT EXISTS block_reward_slot_idx ON block_reward (slot);
+
+CREATE TABLE IF NOT EXISTS transaction (
+    signature VARCHAR(88) NOT NULL,
+    slot BIGINT NOT NULL,
+    err TEXT,
+    account_keys VARCHAR(44)[] NOT NULL,
+    log_messages TEXT[] NOT NULL,
+    PRIMARY KEY (signature, slot)
+);
+CREATE INDEX IF NOT EXISTS transaction_slot_idx ON transaction (slot);
@@ -20,6 +20,7 @@ async fn main() -> anyhow::Result<()> {
         file.read_to_string(&mut contents)?;
         toml::from_str(&contents).unwrap()
     };
+    config.source.validate()?;
 
     solana_logger::setup_with_default("info");
     info!("startup");
@@ -31,29 +32,24 @@ async fn main() -> anyhow::Result<()> {
         Arc::new(mango::MangoCacheTable {}),
     ];
 
-    let metrics_tx = metrics::start();
+    let metrics_tx = metrics::start(config.metrics.clone())?;
 
-    let (account_write_queue_sender, slot_queue_sender) =
-        postgres_target::init(&config.postgres_target, account_tables, metrics_tx.clone()).await?;
+    let (
+        account_write_queue_sender,
+        slot_queue_sender,
+        block_metadata_queue_sender,
+        transaction_queue_sender,
+    ) = postgres_target::init(&config.postgres_target, account_tables, metrics_tx.clone()).await?;
 
     info!("postgres done");
-    let use_geyser = true;
-    if use_geyser {
-        grpc_plugin_source::process_events(
-            &config.source,
-            account_write_queue_sender,
-            slot_queue_sender,
-            metrics_tx,
-        )
+    let source = make_source(
+        &config.source,
+        Some(block_metadata_queue_sender),
+        transaction_queue_sender,
+    );
+    source
+        .run(account_write_queue_sender, slot_queue_sender, metrics_tx)
         .await;
-    } else {
-        websocket_source::process_events(
-            &config.source,
-            account_write_queue_sender,
-            slot_queue_sender,
-        )
-        .await;
-    }
 
     Ok(())
 }
@@ -5,7 +5,16 @@
 CREATE TYPE "SlotStatus" AS ENUM (
     'Rooted',
     'Confirmed',
-    'Processed'
+    'Processed',
+    'Frozen',
+    'Dead'
+);
+
+CREATE TYPE "RewardType" AS ENUM (
+    'Fee',
+    'Rent',
+    'Staking',
+    'Voting'
 );
 
 CREATE TABLE monitoring (
@@ -70,6 +79,32 @@ CREATE TABLE slot (
     slot BIGINT PRIMARY KEY,
     parent BIGINT,
     status "SlotStatus" NOT NULL,
-    uncle BOOL NOT NULL
+    uncle BOOL NOT NULL,
+    blockhash VARCHAR(44),
+    block_time TIMESTAMP WITH TIME ZONE,
+    block_height BIGINT
+);
+CREATE INDEX ON slot (parent);
+CREATE INDEX ON slot (block_time);
+
+-- The table storing the rewards paid out with each block, if enabled
+CREATE TABLE block_reward (
+    slot BIGINT NOT NULL,
+    pubkey VARCHAR(44) NOT NULL,
+    lamports BIGINT NOT NULL,
+    post_balance BIGINT NOT NULL,
+    reward_type "RewardType",
+    commission SMALLINT
+);
+CREATE INDEX ON block_reward (slot);
+
+-- The table storing transactions that reference selected accounts
+CREATE TABLE transaction (
+    signature VARCHAR(88) NOT NULL,
+    slot BIGINT NOT NULL,
+    err TEXT,
+    account_keys VARCHAR(44)[] NOT NULL,
+    log_messages TEXT[] NOT NULL,
+    PRIMARY KEY (signature, slot)
 );
-CREATE INDEX ON slot (parent);
\ No newline at end of file
+CREATE INDEX ON transaction (slot);
\ No newline at end of file
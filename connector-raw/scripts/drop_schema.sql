@@ -4,6 +4,9 @@
 
 DROP TABLE monitoring CASCADE;
 DROP TABLE slot CASCADE;
+DROP TABLE block_reward CASCADE;
 DROP TABLE account_write CASCADE;
+DROP TABLE transaction CASCADE;
 DROP TABLE pubkey CASCADE;
-DROP TYPE "SlotStatus";
\ No newline at end of file
+DROP TYPE "SlotStatus";
+DROP TYPE "RewardType";
\ No newline at end of file
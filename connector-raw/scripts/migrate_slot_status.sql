@@ -0,0 +1,7 @@
+/**
+ * Adds the "Frozen" and "Dead" slot statuses to databases created before they
+ * existed. Run it once, outside of a transaction.
+ */
+
+ALTER TYPE "SlotStatus" ADD VALUE IF NOT EXISTS 'Frozen';
+ALTER TYPE "SlotStatus" ADD VALUE IF NOT EXISTS 'Dead';
@@ -1,4 +1,18 @@
 fn main() {
-    tonic_build::compile_protos("../proto/geyser.proto")
+    tonic_build::configure()
+        .build_server(false)
+        .compile(&["../proto/geyser.proto"], &["../proto"])
+        .unwrap_or_else(|e| panic!("Failed to compile protos {:?}", e));
+
+    // The server streams updates that are encoded once for all subscribers,
+    // see crate::encoding
+    let server_out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("server");
+    std::fs::create_dir_all(&server_out_dir).unwrap();
+    tonic_build::configure()
+        .build_client(false)
+        .out_dir(server_out_dir)
+        .extern_path(".accountsdb.Update", "crate::encoding::EncodedUpdate")
+        .extern_path(".accountsdb", "crate::geyser_plugin_grpc::geyser_proto")
+        .compile(&["../proto/geyser.proto"], &["../proto"])
         .unwrap_or_else(|e| panic!("Failed to compile protos {:?}", e));
 }
@@ -48,6 +48,16 @@ impl AccountsSelector {
     pub fn is_account_selected(&self, account: &[u8], owner: &[u8]) -> bool {
         self.select_all_accounts || self.accounts.contains(account) || self.owners.contains(owner)
     }
+
+    /// Transactions are selected if one of their account keys is a selected
+    /// account or owner (the program id is one of the keys when it's invoked)
+    pub fn is_transaction_selected<'a>(
+        &self,
+        mut account_keys: impl Iterator<Item = &'a [u8]>,
+    ) -> bool {
+        self.select_all_accounts
+            || account_keys.any(|key| self.accounts.contains(key) || self.owners.contains(key))
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,294 @@
+use {
+    crate::geyser_plugin_grpc::geyser_proto::{
+        slot_update::Status as SlotUpdateStatus, update::UpdateOneof, AccountWrite, SlotUpdate,
+        Update,
+    },
+    std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        sync::Arc,
+    },
+};
+
+#[derive(Debug, Default)]
+struct PendingSlot {
+    parent: Option<u64>,
+    slot_update: Option<SlotUpdate>,
+    writes: Vec<Arc<AccountWrite>>,
+}
+
+type RootedAccounts = HashMap<Vec<u8>, Arc<AccountWrite>>;
+
+/// State of all selected accounts that is sent to new subscribers
+pub(crate) struct StateSnapshot {
+    /// All accounts are at this rooted slot
+    pub slot: u64,
+    /// Shared with AccountsState until its next rooted write
+    rooted: Arc<RootedAccounts>,
+    /// Account writes and slot update of each slot after the rooted slot
+    pending: Vec<(Vec<Arc<AccountWrite>>, Option<SlotUpdate>)>,
+}
+
+impl StateSnapshot {
+    pub fn accounts(&self) -> impl Iterator<Item = AccountWrite> + '_ {
+        self.rooted
+            .values()
+            // not selected: account was closed or changed owner
+            .filter(|write| write.is_selected)
+            .map(|write| AccountWrite::clone(write))
+    }
+
+    /// Account writes and slot updates for slots after the rooted slot
+    ///
+    /// They are resent without a seq, so their send_timestamp_us is cleared:
+    /// latencies are only measured for live updates.
+    pub fn pending(&self) -> Vec<Update> {
+        let mut pending = vec![];
+        for (writes, slot_update) in self.pending.iter() {
+            pending.extend(writes.iter().map(|write| Update {
+                update_oneof: Some(UpdateOneof::AccountWrite(AccountWrite {
+                    send_timestamp_us: 0,
+                    ..AccountWrite::clone(write)
+                })),
+                ..Update::default()
+            }));
+            pending.extend(slot_update.iter().map(|slot_update| Update {
+                update_oneof: Some(UpdateOneof::SlotUpdate(SlotUpdate {
+                    send_timestamp_us: 0,
+                    ..slot_update.clone()
+                })),
+                ..Update::default()
+            }));
+        }
+        pending
+    }
+}
+
+/// Latest state of all selected accounts, to serve snapshots to new subscribers
+///
+/// Writes for rooted slots are merged into a single state. Writes for newer slots
+/// are kept separately until their slot is rooted, because it may still turn out
+/// to be on a dead fork.
+#[derive(Debug)]
+pub(crate) struct AccountsState {
+    enabled: bool,
+    newest_rooted_slot: u64,
+    /// Copied on write while snapshots reference it, so taking a snapshot is
+    /// cheap
+    rooted: Arc<RootedAccounts>,
+    pending: BTreeMap<u64, PendingSlot>,
+}
+
+impl AccountsState {
+    pub fn new(enabled: bool) -> Self {
+        AccountsState {
+            enabled,
+            newest_rooted_slot: 0,
+            rooted: Arc::new(HashMap::new()),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, update: &Update) {
+        if !self.enabled {
+            return;
+        }
+        match update.update_oneof.as_ref() {
+            Some(UpdateOneof::AccountWrite(write)) => {
+                // Startup writes come from the validator's rooted snapshot
+                let write = Arc::new(write.clone());
+                if write.is_startup || write.slot <= self.newest_rooted_slot {
+                    self.apply_rooted_write(write);
+                } else {
+                    let pending = self.pending.entry(write.slot).or_default();
+                    pending.writes.push(write);
+                }
+            }
+            Some(UpdateOneof::SlotUpdate(slot_update)) => {
+                if slot_update.slot <= self.newest_rooted_slot {
+                    return;
+                }
+                if slot_update.status == SlotUpdateStatus::Rooted as i32 {
+                    self.root(slot_update.slot, slot_update.parent);
+                } else {
+                    let pending = self.pending.entry(slot_update.slot).or_default();
+                    pending.parent = slot_update.parent.or(pending.parent);
+                    pending.slot_update = Some(SlotUpdate {
+                        parent: pending.parent,
+                        ..slot_update.clone()
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn apply_rooted_write(&mut self, write: Arc<AccountWrite>) {
+        if let Some(existing) = self.rooted.get(&write.pubkey) {
+            if existing.slot > write.slot {
+                return;
+            }
+        }
+        Arc::make_mut(&mut self.rooted).insert(write.pubkey.clone(), write);
+    }
+
+    fn root(&mut self, slot: u64, parent: Option<u64>) {
+        // Find the pending slots that are ancestors of the new root. If the chain
+        // can't be followed down to the previous root, keep all of them.
+        let mut chain = HashSet::new();
+        chain.insert(slot);
+        let mut chain_complete = false;
+        let mut parent = parent.or_else(|| self.pending.get(&slot).and_then(|p| p.parent));
+        while let Some(parent_slot) = parent {
+            if parent_slot <= self.newest_rooted_slot {
+                chain_complete = true;
+                break;
+            }
+            chain.insert(parent_slot);
+            parent = self.pending.get(&parent_slot).and_then(|p| p.parent);
+        }
+
+        let newly_rooted: Vec<u64> = self.pending.range(..=slot).map(|(s, _)| *s).collect();
+        for pending_slot in newly_rooted {
+            let pending = self.pending.remove(&pending_slot).expect("exists");
+            if chain_complete && !chain.contains(&pending_slot) {
+                // dead fork
+                continue;
+            }
+            for write in pending.writes {
+                self.apply_rooted_write(write);
+            }
+        }
+        self.newest_rooted_slot = slot;
+    }
+
+    /// Forgets the writes of accounts that are no longer selected, they are
+    /// left out of snapshots until their next selected write
+    pub fn remove_accounts(&mut self, is_removed: impl Fn(&AccountWrite) -> bool) {
+        Arc::make_mut(&mut self.rooted).retain(|_, write| !is_removed(write));
+        for pending_slot in self.pending.values_mut() {
+            pending_slot.writes.retain(|write| !is_removed(write));
+        }
+    }
+
+    pub fn snapshot(&self) -> Option<StateSnapshot> {
+        if !self.enabled {
+            return None;
+        }
+        let pending = self
+            .pending
+            .values()
+            .map(|pending_slot| {
+                (
+                    pending_slot.writes.clone(),
+                    pending_slot.slot_update.clone(),
+                )
+            })
+            .collect();
+        Some(StateSnapshot {
+            slot: self.newest_rooted_slot,
+            rooted: self.rooted.clone(),
+            pending,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn write(pubkey: u8, slot: u64, lamports: u64) -> Update {
+        Update {
+            update_oneof: Some(UpdateOneof::AccountWrite(AccountWrite {
+                slot,
+                pubkey: vec![pubkey; 32],
+                lamports,
+                is_selected: true,
+                ..AccountWrite::default()
+            })),
+            ..Update::default()
+        }
+    }
+
+    fn slot_update(slot: u64, parent: Option<u64>, status: SlotUpdateStatus) -> Update {
+        Update {
+            update_oneof: Some(UpdateOneof::SlotUpdate(SlotUpdate {
+                slot,
+                parent,
+                status: status as i32,
+                ..SlotUpdate::default()
+            })),
+            ..Update::default()
+        }
+    }
+
+    fn lamports(snapshot: &StateSnapshot, pubkey: u8) -> Option<u64> {
+        snapshot
+            .accounts()
+            .find(|w| w.pubkey == vec![pubkey; 32])
+            .map(|w| w.lamports)
+    }
+
+    #[test]
+    fn test_accounts_state_forks() {
+        let mut state = AccountsState::new(true);
+        state.push(&slot_update(1, None, SlotUpdateStatus::Rooted));
+        state.push(&slot_update(2, Some(1), SlotUpdateStatus::Processed));
+        state.push(&write(1, 2, 20));
+        state.push(&slot_update(3, Some(1), SlotUpdateStatus::Processed));
+        state.push(&write(1, 3, 30));
+        state.push(&write(2, 3, 31));
+        state.push(&slot_update(4, Some(3), SlotUpdateStatus::Processed));
+        state.push(&write(2, 4, 41));
+
+        let snapshot = state.snapshot().unwrap();
+        assert_eq!(snapshot.slot, 1);
+        assert_eq!(snapshot.accounts().count(), 0);
+        assert_eq!(snapshot.pending().len(), 7);
+
+        // slot 2 is on a dead fork
+        state.push(&slot_update(3, None, SlotUpdateStatus::Rooted));
+        let snapshot = state.snapshot().unwrap();
+        assert_eq!(snapshot.slot, 3);
+        assert_eq!(lamports(&snapshot, 1), Some(30));
+        assert_eq!(lamports(&snapshot, 2), Some(31));
+        assert_eq!(
+            snapshot.pending(),
+            vec![
+                write(2, 4, 41),
+                slot_update(4, Some(3), SlotUpdateStatus::Processed)
+            ]
+        );
+
+        // pending updates don't keep their original send time
+        let mut late_write = write(2, 4, 42);
+        if let Some(UpdateOneof::AccountWrite(w)) = late_write.update_oneof.as_mut() {
+            w.send_timestamp_us = 1_000;
+        }
+        state.push(&late_write);
+        assert_eq!(
+            state.snapshot().unwrap().pending(),
+            vec![
+                write(2, 4, 41),
+                write(2, 4, 42),
+                slot_update(4, Some(3), SlotUpdateStatus::Processed)
+            ]
+        );
+
+        // late writes to rooted slots are applied directly
+        state.push(&write(3, 3, 33));
+        let snapshot = state.snapshot().unwrap();
+        assert_eq!(lamports(&snapshot, 3), Some(33));
+
+        // snapshots keep the state they were taken at
+        state.push(&write(3, 3, 34));
+        assert_eq!(lamports(&snapshot, 3), Some(33));
+        assert_eq!(lamports(&state.snapshot().unwrap(), 3), Some(34));
+    }
+
+    #[test]
+    fn test_accounts_state_disabled() {
+        let mut state = AccountsState::new(false);
+        state.push(&write(1, 1, 10));
+        assert!(state.snapshot().is_none());
+    }
+}
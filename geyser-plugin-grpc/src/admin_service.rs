@@ -0,0 +1,443 @@
+use {
+    crate::{
+        accounts_selector::AccountsSelector,
+        encoding::EncodedUpdate,
+        geyser_plugin_grpc::{
+            geyser_proto::{
+                accounts_db_admin_server::AccountsDbAdmin, ListSelectorRequest,
+                ListSubscribersRequest, ListSubscribersResponse, Selector, SelectorChange,
+                SubscriberStats,
+            },
+            UpdateHistory,
+        },
+    },
+    log::*,
+    std::{
+        collections::HashMap,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex, RwLock,
+        },
+    },
+    tokio::sync::mpsc,
+    tonic::{Request, Response, Status},
+};
+
+/// Accounts that saw account writes, with their owner when they were selected
+pub(crate) type ActiveAccounts = Arc<RwLock<HashMap<[u8; 32], [u8; 32]>>>;
+
+/// A connected subscriber of the AccountsDb service
+#[derive(Debug)]
+pub(crate) struct SubscriberInfo {
+    pub remote_addr: Option<SocketAddr>,
+    pub protocol_version: u32,
+    pub connected_timestamp_us: u64,
+    pub updates_sent: AtomicU64,
+    pub backlog_len: AtomicU64,
+    pub dropped_writes: AtomicU64,
+    /// The subscriber's stream and its buffer size
+    pub queue: mpsc::Sender<Result<EncodedUpdate, Status>>,
+    pub queue_size: usize,
+}
+
+impl SubscriberInfo {
+    fn stats(&self, id: u64) -> SubscriberStats {
+        let queued = self.queue_size.saturating_sub(self.queue.capacity()) as u64;
+        SubscriberStats {
+            id,
+            remote_addr: self
+                .remote_addr
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            protocol_version: self.protocol_version,
+            connected_timestamp_us: self.connected_timestamp_us,
+            updates_sent: self.updates_sent.load(Ordering::Relaxed),
+            queue_depth: queued + self.backlog_len.load(Ordering::Relaxed),
+            dropped_writes: self.dropped_writes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The connected subscribers of the AccountsDb service
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Subscribers {
+    next_id: Arc<AtomicU64>,
+    connected: Arc<Mutex<HashMap<u64, Arc<SubscriberInfo>>>>,
+}
+
+impl Subscribers {
+    /// The subscriber is listed until the returned Registration is dropped
+    pub fn register(&self, info: SubscriberInfo) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let info = Arc::new(info);
+        self.connected.lock().unwrap().insert(id, info.clone());
+        Registration {
+            id,
+            info,
+            subscribers: self.clone(),
+        }
+    }
+
+    pub fn stats(&self) -> Vec<SubscriberStats> {
+        let mut stats: Vec<_> = self
+            .connected
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, info)| info.stats(*id))
+            .collect();
+        stats.sort_by_key(|stats| stats.id);
+        stats
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Registration {
+    id: u64,
+    pub info: Arc<SubscriberInfo>,
+    subscribers: Subscribers,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.subscribers.connected.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[derive(Debug)]
+pub struct AdminService {
+    accounts_selector: Arc<RwLock<AccountsSelector>>,
+    active_accounts: ActiveAccounts,
+    history: Arc<Mutex<UpdateHistory>>,
+    subscribers: Subscribers,
+}
+
+impl AdminService {
+    pub(crate) fn new(
+        accounts_selector: Arc<RwLock<AccountsSelector>>,
+        active_accounts: ActiveAccounts,
+        history: Arc<Mutex<UpdateHistory>>,
+        subscribers: Subscribers,
+    ) -> Self {
+        AdminService {
+            accounts_selector,
+            active_accounts,
+            history,
+            subscribers,
+        }
+    }
+}
+
+fn to_selector(selector: &AccountsSelector) -> Selector {
+    let mut accounts: Vec<_> = selector.accounts.iter().cloned().collect();
+    let mut owners: Vec<_> = selector.owners.iter().cloned().collect();
+    accounts.sort();
+    owners.sort();
+    Selector {
+        select_all_accounts: selector.select_all_accounts,
+        accounts,
+        owners,
+    }
+}
+
+/// Checks the pubkeys of a change to a selector that doesn't select all accounts
+fn check_change(change: &SelectorChange, selector: &AccountsSelector) -> Result<(), Status> {
+    if selector.select_all_accounts {
+        return Err(Status::failed_precondition(
+            "all accounts are selected, the selector can't be changed",
+        ));
+    }
+    let mut keys = change.accounts.iter().chain(change.owners.iter());
+    if let Some(key) = keys.find(|key| key.len() != 32) {
+        return Err(Status::invalid_argument(format!(
+            "bad pubkey: {}",
+            bs58::encode(key).into_string()
+        )));
+    }
+    Ok(())
+}
+
+fn describe(change: &SelectorChange) -> String {
+    let encode = |keys: &[Vec<u8>]| -> Vec<String> {
+        keys.iter()
+            .map(|key| bs58::encode(key).into_string())
+            .collect()
+    };
+    format!(
+        "accounts {:?}, owners {:?}",
+        encode(&change.accounts),
+        encode(&change.owners)
+    )
+}
+
+#[tonic::async_trait]
+impl AccountsDbAdmin for AdminService {
+    async fn list_selector(
+        &self,
+        _request: Request<ListSelectorRequest>,
+    ) -> Result<Response<Selector>, Status> {
+        let selector = self.accounts_selector.read().unwrap();
+        Ok(Response::new(to_selector(&selector)))
+    }
+
+    async fn add_to_selector(
+        &self,
+        request: Request<SelectorChange>,
+    ) -> Result<Response<Selector>, Status> {
+        let change = request.into_inner();
+        let mut selector = self.accounts_selector.write().unwrap();
+        check_change(&change, &selector)?;
+        info!("adding to the accounts selector: {}", describe(&change));
+        selector.accounts.extend(change.accounts);
+        selector.owners.extend(change.owners);
+        Ok(Response::new(to_selector(&selector)))
+    }
+
+    async fn remove_from_selector(
+        &self,
+        request: Request<SelectorChange>,
+    ) -> Result<Response<Selector>, Status> {
+        let change = request.into_inner();
+        let mut selector = self.accounts_selector.write().unwrap();
+        check_change(&change, &selector)?;
+        info!("removing from the accounts selector: {}", describe(&change));
+        for account in change.accounts.iter() {
+            selector.accounts.remove(account);
+        }
+        for owner in change.owners.iter() {
+            selector.owners.remove(owner);
+        }
+
+        // Writes to previously selected accounts are sent to catch closures,
+        // stop that too. Accounts the remaining keys still select stay.
+        let is_removed = |account: &[u8], owner: &[u8]| {
+            (change.accounts.iter().any(|key| key[..] == account[..])
+                || change.owners.iter().any(|key| key[..] == owner[..]))
+                && !selector.is_account_selected(account, owner)
+        };
+        let mut active_accounts = self.active_accounts.write().unwrap();
+        active_accounts.retain(|account, owner| !is_removed(account, owner));
+
+        // New subscribers shouldn't get them in snapshots either
+        let mut history = self.history.lock().unwrap();
+        history
+            .accounts_state
+            .remove_accounts(|write| is_removed(&write.pubkey, &write.owner));
+        Ok(Response::new(to_selector(&selector)))
+    }
+
+    async fn list_subscribers(
+        &self,
+        _request: Request<ListSubscribersRequest>,
+    ) -> Result<Response<ListSubscribersResponse>, Status> {
+        Ok(Response::new(ListSubscribersResponse {
+            subscribers: self.subscribers.stats(),
+        }))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::*,
+        crate::{
+            accounts_state::AccountsState,
+            geyser_plugin_grpc::geyser_proto::{update::UpdateOneof, AccountWrite, Update},
+            replay_buffer::ReplayBuffer,
+        },
+        std::convert::TryInto,
+        tonic::Code,
+    };
+
+    fn history() -> Arc<Mutex<UpdateHistory>> {
+        Arc::new(Mutex::new(UpdateHistory {
+            replay_buffer: ReplayBuffer::new(0),
+            accounts_state: AccountsState::new(true),
+            seq: 0,
+        }))
+    }
+
+    fn snapshot_accounts(history: &Mutex<UpdateHistory>) -> Vec<Vec<u8>> {
+        let history = history.lock().unwrap();
+        let mut accounts: Vec<_> = history
+            .accounts_state
+            .snapshot()
+            .unwrap()
+            .accounts()
+            .map(|write| write.pubkey)
+            .collect();
+        accounts.sort();
+        accounts
+    }
+
+    #[tokio::test]
+    async fn test_change_selector() {
+        let owner = "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin";
+        let accounts_selector = Arc::new(RwLock::new(AccountsSelector::new(
+            &[],
+            &[owner.to_string()],
+        )));
+        let owner = bs58::decode(owner).into_vec().unwrap();
+        let active_accounts = ActiveAccounts::default();
+        {
+            let mut active_accounts = active_accounts.write().unwrap();
+            active_accounts.insert([1; 32], owner.clone().try_into().unwrap());
+            active_accounts.insert([2; 32], [3; 32]);
+        }
+        let history = history();
+        for (account, account_owner) in [(1, owner.clone()), (2, vec![3; 32]), (5, vec![4; 32])] {
+            history.lock().unwrap().accounts_state.push(&Update {
+                update_oneof: Some(UpdateOneof::AccountWrite(AccountWrite {
+                    slot: 1,
+                    pubkey: vec![account; 32],
+                    owner: account_owner,
+                    is_selected: true,
+                    is_startup: true,
+                    ..AccountWrite::default()
+                })),
+                ..Update::default()
+            });
+        }
+        let service = AdminService::new(
+            accounts_selector.clone(),
+            active_accounts.clone(),
+            history.clone(),
+            Subscribers::default(),
+        );
+        let change = |accounts: Vec<Vec<u8>>, owners: Vec<Vec<u8>>| {
+            Request::new(SelectorChange { accounts, owners })
+        };
+
+        let selector = service
+            .add_to_selector(change(vec![vec![2; 32]], vec![vec![4; 32]]))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(selector.accounts, vec![vec![2; 32]]);
+        let mut owners = vec![owner.clone(), vec![4; 32]];
+        owners.sort();
+        assert_eq!(selector.owners, owners);
+        assert!(accounts_selector
+            .read()
+            .unwrap()
+            .is_account_selected(&[5; 32], &[4; 32]));
+
+        let err = service
+            .add_to_selector(change(vec![vec![2; 31]], vec![]))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let selector = service
+            .remove_from_selector(change(vec![vec![2; 32]], vec![owner]))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(selector.accounts.is_empty());
+        assert_eq!(selector.owners, vec![vec![4; 32]]);
+        assert!(active_accounts.read().unwrap().is_empty());
+        assert_eq!(snapshot_accounts(&history), vec![vec![5; 32]]);
+
+        let selector = Arc::new(RwLock::new(AccountsSelector::default()));
+        let service = AdminService::new(selector, active_accounts, history, Subscribers::default());
+        let err = service
+            .add_to_selector(change(vec![vec![2; 32]], vec![]))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+    }
+
+    #[tokio::test]
+    async fn test_remove_owner_keeps_selected_accounts() {
+        let owner = vec![3; 32];
+        let accounts_selector = Arc::new(RwLock::new(AccountsSelector::new(
+            &[bs58::encode([1; 32]).into_string()],
+            &[bs58::encode(&owner).into_string()],
+        )));
+        let active_accounts = ActiveAccounts::default();
+        let history = history();
+        for account in [1, 2] {
+            active_accounts
+                .write()
+                .unwrap()
+                .insert([account; 32], owner.clone().try_into().unwrap());
+            history.lock().unwrap().accounts_state.push(&Update {
+                update_oneof: Some(UpdateOneof::AccountWrite(AccountWrite {
+                    slot: 1,
+                    pubkey: vec![account; 32],
+                    owner: owner.clone(),
+                    is_selected: true,
+                    is_startup: true,
+                    ..AccountWrite::default()
+                })),
+                ..Update::default()
+            });
+        }
+        let service = AdminService::new(
+            accounts_selector,
+            active_accounts.clone(),
+            history.clone(),
+            Subscribers::default(),
+        );
+
+        // account 1 is also selected by its pubkey
+        service
+            .remove_from_selector(Request::new(SelectorChange {
+                accounts: vec![],
+                owners: vec![owner],
+            }))
+            .await
+            .unwrap();
+        let active: Vec<_> = active_accounts.read().unwrap().keys().cloned().collect();
+        assert_eq!(active, vec![[1; 32]]);
+        assert_eq!(snapshot_accounts(&history), vec![vec![1; 32]]);
+    }
+
+    #[tokio::test]
+    async fn test_list_subscribers() {
+        let subscribers = Subscribers::default();
+        let service = AdminService::new(
+            Arc::new(RwLock::new(AccountsSelector::default())),
+            ActiveAccounts::default(),
+            history(),
+            subscribers.clone(),
+        );
+        let list = || async {
+            service
+                .list_subscribers(Request::new(ListSubscribersRequest {}))
+                .await
+                .unwrap()
+                .into_inner()
+                .subscribers
+        };
+
+        let (queue, _receiver) = mpsc::channel(10);
+        queue.send(Ok(EncodedUpdate::default())).await.unwrap();
+        let registration = subscribers.register(SubscriberInfo {
+            remote_addr: None,
+            protocol_version: 2,
+            connected_timestamp_us: 1,
+            updates_sent: AtomicU64::new(1),
+            backlog_len: AtomicU64::new(2),
+            dropped_writes: AtomicU64::new(3),
+            queue,
+            queue_size: 10,
+        });
+        assert_eq!(
+            list().await,
+            vec![SubscriberStats {
+                id: 1,
+                remote_addr: String::new(),
+                protocol_version: 2,
+                connected_timestamp_us: 1,
+                updates_sent: 1,
+                queue_depth: 3,
+                dropped_writes: 3,
+            }]
+        );
+
+        drop(registration);
+        assert!(list().await.is_empty());
+    }
+}
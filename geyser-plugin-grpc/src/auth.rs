@@ -0,0 +1,180 @@
+use {
+    serde_derive::Deserialize,
+    solana_sdk::hash::{hash, Hash},
+    std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    },
+    tonic::{Request, Status},
+};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AccessTokenConfig {
+    pub token: String,
+    /// Clients using this token only receive writes to these accounts or to
+    /// accounts owned by these owners. If both are empty, there is no restriction.
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    #[serde(default)]
+    pub owners: Vec<String>,
+}
+
+/// The data that a client is allowed to receive
+///
+/// Attached to requests as an extension by the Authenticator.
+#[derive(Clone, Debug)]
+pub(crate) struct AccessPermissions {
+    accounts: HashSet<Vec<u8>>,
+    owners: HashSet<Vec<u8>>,
+}
+
+impl AccessPermissions {
+    pub fn is_write_permitted(&self, pubkey: &[u8], owner: &[u8]) -> bool {
+        self.accounts.contains(pubkey) || self.owners.contains(owner)
+    }
+
+    /// Transactions are permitted if one of their account keys is a permitted
+    /// account or owner
+    pub fn is_key_permitted(&self, key: &[u8]) -> bool {
+        self.accounts.contains(key) || self.owners.contains(key)
+    }
+}
+
+/// Checks the bearer token of incoming requests
+///
+/// If no tokens are configured, all requests are accepted.
+#[derive(Clone, Debug)]
+pub(crate) struct Authenticator {
+    /// sha256 of the token -> permissions, None for unrestricted tokens
+    ///
+    /// Looking up the digest instead of the token keeps the time a lookup
+    /// takes from telling anything about the configured tokens.
+    tokens: Arc<HashMap<Hash, Option<AccessPermissions>>>,
+}
+
+impl Authenticator {
+    pub fn new(configs: &[AccessTokenConfig]) -> Result<Self, String> {
+        let decode_pubkeys = |keys: &[String]| -> Result<HashSet<Vec<u8>>, String> {
+            keys.iter()
+                .map(|key| match bs58::decode(key).into_vec() {
+                    Ok(bytes) if bytes.len() == 32 => Ok(bytes),
+                    _ => Err(format!("bad pubkey in access token config: {}", key)),
+                })
+                .collect()
+        };
+
+        let mut tokens = HashMap::new();
+        for config in configs {
+            if config.token.is_empty() {
+                return Err("empty access token".to_string());
+            }
+            let permissions = if config.accounts.is_empty() && config.owners.is_empty() {
+                None
+            } else {
+                Some(AccessPermissions {
+                    accounts: decode_pubkeys(&config.accounts)?,
+                    owners: decode_pubkeys(&config.owners)?,
+                })
+            };
+            if tokens
+                .insert(hash(config.token.as_bytes()), permissions)
+                .is_some()
+            {
+                return Err("duplicate access token".to_string());
+            }
+        }
+        Ok(Authenticator {
+            tokens: Arc::new(tokens),
+        })
+    }
+
+    /// Interceptor for the gRPC service
+    pub fn authenticate(&self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if self.tokens.is_empty() {
+            return Ok(request);
+        }
+
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("missing bearer token"))?;
+        let permissions = self
+            .tokens
+            .get(&hash(token.as_bytes()))
+            .ok_or_else(|| Status::unauthenticated("invalid bearer token"))?
+            .clone();
+
+        if let Some(permissions) = permissions {
+            request.extensions_mut().insert(permissions);
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {super::*, tonic::Code};
+
+    fn request(token: Option<&str>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(token) = token {
+            request.metadata_mut().insert(
+                "authorization",
+                format!("Bearer {}", token).parse().unwrap(),
+            );
+        }
+        request
+    }
+
+    #[test]
+    fn test_authenticator() {
+        let owner = "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin";
+        let authenticator = Authenticator::new(&[
+            AccessTokenConfig {
+                token: "admin".to_string(),
+                accounts: vec![],
+                owners: vec![],
+            },
+            AccessTokenConfig {
+                token: "team".to_string(),
+                accounts: vec![],
+                owners: vec![owner.to_string()],
+            },
+        ])
+        .unwrap();
+
+        let code = |result: Result<Request<()>, Status>| result.unwrap_err().code();
+        assert_eq!(
+            code(authenticator.authenticate(request(None))),
+            Code::Unauthenticated
+        );
+        assert_eq!(
+            code(authenticator.authenticate(request(Some("other")))),
+            Code::Unauthenticated
+        );
+
+        let admin = authenticator.authenticate(request(Some("admin"))).unwrap();
+        assert!(admin.extensions().get::<AccessPermissions>().is_none());
+
+        let team = authenticator.authenticate(request(Some("team"))).unwrap();
+        let permissions = team.extensions().get::<AccessPermissions>().unwrap();
+        let owner = bs58::decode(owner).into_vec().unwrap();
+        assert!(permissions.is_write_permitted(&[1; 32], &owner));
+        assert!(!permissions.is_write_permitted(&owner, &[1; 32]));
+    }
+
+    #[test]
+    fn test_authenticator_disabled() {
+        let authenticator = Authenticator::new(&[]).unwrap();
+        assert!(authenticator.authenticate(request(None)).is_ok());
+
+        assert!(Authenticator::new(&[AccessTokenConfig {
+            token: "x".to_string(),
+            accounts: vec!["bad".to_string()],
+            owners: vec![],
+        }])
+        .is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use {
+    crate::geyser_plugin_grpc::geyser_proto::{
+        update::UpdateOneof, AccountDataDelta, AccountWrite, DataRange, Update,
+    },
+    std::collections::HashMap,
+};
+
+/// Unchanged runs shorter than this are sent along instead of starting a new range
+const MERGE_GAP: usize = 8;
+/// Approximate encoded size of a DataRange without its data
+const RANGE_OVERHEAD: usize = 6;
+
+/// Byte ranges of new that differ from old, or extend beyond it
+fn diff(old: &[u8], new: &[u8]) -> Vec<DataRange> {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    let mut i = 0;
+    while i < new.len() {
+        if i < old.len() && old[i] == new[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < new.len() && (i >= old.len() || old[i] != new[i]) {
+            i += 1;
+        }
+        match ranges.last_mut() {
+            Some((_, end)) if start - *end < MERGE_GAP => *end = i,
+            _ => ranges.push((start, i)),
+        }
+    }
+    ranges
+        .into_iter()
+        .map(|(start, end)| DataRange {
+            offset: start as u64,
+            data: new[start..end].to_vec(),
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+struct SentAccount {
+    data: Vec<u8>,
+    /// Deltas sent since the last full write
+    deltas: u64,
+}
+
+/// Replaces the data of account writes to a single subscriber with deltas
+/// against the previous write of the same account to that subscriber
+///
+/// Every keyframe_interval-th write of an account is sent in full, as are
+/// writes where the delta would not be smaller.
+#[derive(Debug)]
+pub(crate) struct DeltaEncoder {
+    keyframe_interval: u64,
+    sent: HashMap<Vec<u8>, SentAccount>,
+}
+
+impl DeltaEncoder {
+    pub fn new(keyframe_interval: u64) -> Self {
+        DeltaEncoder {
+            keyframe_interval,
+            sent: HashMap::new(),
+        }
+    }
+
+    /// The update to send instead, if it's an account write that is sent as a delta
+    pub fn encode(&mut self, update: &Update) -> Option<Update> {
+        match update.update_oneof.as_ref() {
+            Some(UpdateOneof::AccountWrite(write)) => {
+                self.encode_write(write).map(|write| Update {
+                    update_oneof: Some(UpdateOneof::AccountWrite(write)),
+                    seq: update.seq,
+                    skipped: update.skipped,
+                })
+            }
+            Some(UpdateOneof::AccountsSnapshot(snapshot)) => {
+                for write in snapshot.accounts.iter() {
+                    self.sent.insert(
+                        write.pubkey.clone(),
+                        SentAccount {
+                            data: write.data.clone(),
+                            deltas: 0,
+                        },
+                    );
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn encode_write(&mut self, write: &AccountWrite) -> Option<AccountWrite> {
+        let previous = match self.sent.get_mut(&write.pubkey) {
+            Some(previous) if previous.deltas + 1 < self.keyframe_interval => previous,
+            _ => {
+                self.sent.insert(
+                    write.pubkey.clone(),
+                    SentAccount {
+                        data: write.data.clone(),
+                        deltas: 0,
+                    },
+                );
+                return None;
+            }
+        };
+        let ranges = diff(&previous.data, &write.data);
+        let delta_size: usize = ranges.iter().map(|r| r.data.len() + RANGE_OVERHEAD).sum();
+        previous.data.clone_from(&write.data);
+        if delta_size >= write.data.len() {
+            previous.deltas = 0;
+            return None;
+        }
+        previous.deltas += 1;
+        Some(AccountWrite {
+            slot: write.slot,
+            pubkey: write.pubkey.clone(),
+            lamports: write.lamports,
+            owner: write.owner.clone(),
+            executable: write.executable,
+            rent_epoch: write.rent_epoch,
+            data: vec![],
+            write_version: write.write_version,
+            is_startup: write.is_startup,
+            is_selected: write.is_selected,
+            send_timestamp_us: write.send_timestamp_us,
+            data_delta: Some(AccountDataDelta {
+                data_len: write.data.len() as u64,
+                ranges,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn write(pubkey: u8, data: Vec<u8>) -> Update {
+        Update {
+            update_oneof: Some(UpdateOneof::AccountWrite(AccountWrite {
+                pubkey: vec![pubkey; 32],
+                data,
+                ..AccountWrite::default()
+            })),
+            ..Update::default()
+        }
+    }
+
+    fn encode(encoder: &mut DeltaEncoder, pubkey: u8, data: Vec<u8>) -> AccountWrite {
+        let update = write(pubkey, data);
+        match encoder.encode(&update).unwrap_or(update).update_oneof {
+            Some(UpdateOneof::AccountWrite(write)) => write,
+            _ => unreachable!(),
+        }
+    }
+
+    fn range(offset: u64, data: &[u8]) -> DataRange {
+        DataRange {
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(diff(&[1, 2, 3], &[1, 2, 3]), vec![]);
+        assert_eq!(diff(&[1, 2, 3], &[1, 5, 3, 4]), vec![range(1, &[5, 3, 4])]);
+        assert_eq!(diff(&[1, 2, 3], &[]), vec![]);
+
+        let mut new = vec![0; 40];
+        new[0] = 1;
+        new[4] = 1;
+        new[30] = 1;
+        assert_eq!(
+            diff(&[0; 40], &new),
+            vec![range(0, &[1, 0, 0, 0, 1]), range(30, &[1])]
+        );
+    }
+
+    #[test]
+    fn test_delta_encoder() {
+        let mut encoder = DeltaEncoder::new(3);
+        let mut data = vec![0; 100];
+
+        // the first write, and writes to other accounts, are sent in full
+        assert_eq!(encode(&mut encoder, 1, data.clone()).data, data);
+        assert_eq!(encode(&mut encoder, 2, vec![1; 100]).data, vec![1; 100]);
+
+        data[50] = 1;
+        let sent = encode(&mut encoder, 1, data.clone());
+        assert!(sent.data.is_empty());
+        assert_eq!(
+            sent.data_delta,
+            Some(AccountDataDelta {
+                data_len: 100,
+                ranges: vec![range(50, &[1])],
+            })
+        );
+
+        // a shorter account
+        data.truncate(60);
+        let sent = encode(&mut encoder, 1, data.clone());
+        assert_eq!(
+            sent.data_delta,
+            Some(AccountDataDelta {
+                data_len: 60,
+                ranges: vec![],
+            })
+        );
+
+        // keyframe
+        data[0] = 1;
+        assert_eq!(encode(&mut encoder, 1, data.clone()).data, data);
+
+        // not worth a delta
+        let sent = encode(&mut encoder, 1, vec![2; 60]);
+        assert_eq!(sent.data, vec![2; 60]);
+        assert_eq!(sent.data_delta, None);
+    }
+}
@@ -0,0 +1,131 @@
+use {
+    crate::geyser_plugin_grpc::geyser_proto::Update,
+    bytes::{Buf, BufMut, Bytes},
+    prost::{
+        encoding::{self, DecodeContext, WireType},
+        DecodeError, Message,
+    },
+};
+
+/// Field number of Update::skipped
+const SKIPPED_TAG: u32 = 9;
+
+/// An update as broadcast to all subscribers, encoded once
+#[derive(Debug)]
+pub struct SharedUpdate {
+    pub update: Update,
+    pub encoded: Bytes,
+}
+
+impl SharedUpdate {
+    pub fn new(update: Update) -> Self {
+        let encoded = update.encode_to_vec().into();
+        SharedUpdate { update, encoded }
+    }
+}
+
+/// An encoded Update as sent to one subscriber
+///
+/// The gRPC server sends these in place of Update. When a singular scalar field
+/// appears more than once, protobuf decoders keep the last value, so the
+/// per-subscriber skipped field is appended to the shared bytes instead of
+/// encoding the update again.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EncodedUpdate {
+    /// An encoded Update without skipped
+    pub bytes: Bytes,
+    pub skipped: u64,
+}
+
+impl EncodedUpdate {
+    pub fn new(update: &Update) -> Self {
+        let bytes = Update {
+            skipped: 0,
+            ..update.clone()
+        }
+        .encode_to_vec()
+        .into();
+        EncodedUpdate {
+            bytes,
+            skipped: update.skipped,
+        }
+    }
+}
+
+impl Message for EncodedUpdate {
+    fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.bytes);
+        if self.skipped != 0 {
+            encoding::uint64::encode(SKIPPED_TAG, &self.skipped, buf);
+        }
+    }
+
+    fn merge_field<B: Buf>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        // only ever sent
+        encoding::skip_field(wire_type, tag, buf, ctx)
+    }
+
+    fn encoded_len(&self) -> usize {
+        let skipped_len = if self.skipped != 0 {
+            encoding::uint64::encoded_len(SKIPPED_TAG, &self.skipped)
+        } else {
+            0
+        };
+        self.bytes.len() + skipped_len
+    }
+
+    fn clear(&mut self) {
+        *self = EncodedUpdate::default();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::*,
+        crate::geyser_plugin_grpc::geyser_proto::{update::UpdateOneof, AccountWrite},
+    };
+
+    #[test]
+    fn test_encoded_update() {
+        let update = Update {
+            update_oneof: Some(UpdateOneof::AccountWrite(AccountWrite {
+                slot: 1,
+                data: vec![1, 2, 3],
+                ..AccountWrite::default()
+            })),
+            seq: 5,
+            ..Update::default()
+        };
+        let shared = SharedUpdate::new(update.clone());
+        let encoded = EncodedUpdate {
+            bytes: shared.encoded,
+            skipped: 2,
+        };
+        assert_eq!(
+            Update::decode(encoded.encode_to_vec().as_slice()).unwrap(),
+            Update {
+                skipped: 2,
+                ..update.clone()
+            }
+        );
+
+        // skipped is only encoded once
+        let update = Update {
+            skipped: 3,
+            ..update
+        };
+        let encoded = EncodedUpdate::new(&update);
+        assert_eq!(Update::decode(encoded.bytes.clone()).unwrap().skipped, 0);
+        assert_eq!(
+            Update::decode(encoded.encode_to_vec().as_slice()).unwrap(),
+            update
+        );
+    }
+}
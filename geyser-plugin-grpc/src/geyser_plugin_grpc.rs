@@ -1,96 +1,692 @@
 use {
-    crate::accounts_selector::AccountsSelector,
+    crate::{
+        accounts_selector::AccountsSelector,
+        accounts_state::AccountsState,
+        admin_service::{ActiveAccounts, AdminService, SubscriberInfo, Subscribers},
+        auth::{AccessPermissions, AccessTokenConfig, Authenticator},
+        delta_encoder::DeltaEncoder,
+        encoding::{EncodedUpdate, SharedUpdate},
+        replay_buffer::ReplayBuffer,
+        slow_subscriber::{Backlog, SlowSubscriberPolicy, WriteKey},
+        subscription_filter::SubscriptionFilter,
+    },
     bs58,
     geyser_proto::{
-        slot_update::Status as SlotUpdateStatus, update::UpdateOneof, AccountWrite, Ping,
-        SlotUpdate, SubscribeRequest, SubscribeResponse, Update,
+        accounts_db_admin_server::AccountsDbAdminServer, reward::RewardType as ProtoRewardType,
+        slot_update::Status as SlotUpdateStatus, update::UpdateOneof, AccountWrite, BlockMetadata,
+        Ping, Reward, SlotUpdate, SubscribeRequest, SubscribeResponse, Transaction, Update,
     },
     log::*,
     serde_derive::Deserialize,
     serde_json,
     solana_geyser_plugin_interface::geyser_plugin_interface::{
-        GeyserPlugin, GeyserPluginError, ReplicaAccountInfoVersions, Result as PluginResult,
-        SlotStatus,
+        GeyserPlugin, GeyserPluginError, ReplicaAccountInfoVersions, ReplicaBlockInfoVersions,
+        ReplicaTransactionInfoVersions, Result as PluginResult, SlotStatus,
     },
-    std::collections::HashSet,
+    solana_transaction_status::RewardType,
     std::convert::TryInto,
     std::sync::atomic::{AtomicU64, Ordering},
-    std::sync::RwLock,
+    std::sync::{Mutex, RwLock},
     std::{fs::File, io::Read, sync::Arc},
     tokio::sync::{broadcast, mpsc},
-    tonic::transport::Server,
+    tonic::{
+        service::interceptor::InterceptedService,
+        transport::{Certificate, Identity, Server, ServerTlsConfig},
+    },
 };
 
 pub mod geyser_proto {
     tonic::include_proto!("accountsdb");
+    include!(concat!(env!("OUT_DIR"), "/server/accountsdb.rs"));
+}
+
+/// SubscribeResponse::protocol_version of this build
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Data about past updates, to bring new subscribers up to date
+#[derive(Debug)]
+pub(crate) struct UpdateHistory {
+    pub replay_buffer: ReplayBuffer,
+    pub accounts_state: AccountsState,
+    /// Update::seq of the latest update
+    pub seq: u64,
+}
+
+impl UpdateHistory {
+    fn push(&mut self, update: &Arc<SharedUpdate>) {
+        self.replay_buffer.push(update);
+        self.accounts_state.push(&update.update);
+    }
 }
 
 pub mod geyser_service {
     use super::*;
     use {
-        geyser_proto::accounts_db_server::AccountsDb,
+        geyser_proto::{
+            accounts_db_server::{AccountsDb, AccountsDbServer},
+            slow_subscriber::Policy,
+            AccountsSnapshot, Capability, SlowSubscriber,
+        },
+        prost::Message,
+        std::borrow::Cow,
+        tokio::sync::mpsc::error::TrySendError,
         tokio_stream::wrappers::ReceiverStream,
         tonic::{Code, Request, Response, Status},
     };
 
+    /// Approximate amount of account data per snapshot message
+    const SNAPSHOT_CHUNK_BYTES: usize = 1024 * 1024;
+
+    /// Encoding for the update stream, used for clients that accept it
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Compression {
+        Gzip,
+    }
+
     #[derive(Clone, Debug, Deserialize)]
     pub struct ServiceConfig {
         broadcast_buffer_size: usize,
         subscriber_buffer_size: usize,
+        /// Number of recent updates kept for subscribers resuming from a slot (0 to disable)
+        #[serde(default)]
+        replay_buffer_size: usize,
+        /// Keep the state of all selected accounts, to send snapshots to new subscribers
+        #[serde(default)]
+        serve_snapshots: bool,
+        #[serde(default)]
+        compression: Option<Compression>,
+        /// Send every n-th write of an account in full to subscribers that ask
+        /// for delta_account_data, and deltas in between (0 to disable)
+        #[serde(default)]
+        delta_keyframe_interval: u64,
+        /// For subscribers that fall behind by more than subscriber_buffer_size
+        /// updates, see SlowSubscriberPolicy. Other policies than disconnect
+        /// keep up to broadcast_buffer_size further updates per subscriber.
+        #[serde(default)]
+        slow_subscriber_policy: SlowSubscriberPolicy,
     }
 
     #[derive(Debug)]
     pub struct Service {
-        pub sender: broadcast::Sender<Update>,
+        pub sender: broadcast::Sender<Arc<SharedUpdate>>,
+        queue: mpsc::Sender<QueuedUpdate>,
+        /// Number of times the queue was full, see UpdateSender::queue_update
+        queue_full_count: Arc<AtomicU64>,
         pub config: ServiceConfig,
         pub highest_write_slot: Arc<AtomicU64>,
+        pub(crate) history: Arc<Mutex<UpdateHistory>>,
+        /// Transactions are broadcast, see PluginConfig::transaction_notifications
+        pub transaction_notifications: bool,
+        pub(crate) subscribers: Subscribers,
     }
 
     impl Service {
         pub fn new(config: ServiceConfig, highest_write_slot: Arc<AtomicU64>) -> Self {
             let (tx, _) = broadcast::channel(config.broadcast_buffer_size);
+            let history = Arc::new(Mutex::new(UpdateHistory {
+                replay_buffer: ReplayBuffer::new(config.replay_buffer_size),
+                accounts_state: AccountsState::new(config.serve_snapshots),
+                seq: 0,
+            }));
+            let (queue, queue_receiver) = mpsc::channel(config.broadcast_buffer_size);
+            {
+                let sender = tx.clone();
+                let history = history.clone();
+                std::thread::Builder::new()
+                    .name("geyserGrpcEncoder".to_string())
+                    .spawn(move || publish_updates(queue_receiver, sender, history))
+                    .unwrap();
+            }
             Self {
                 sender: tx,
+                queue,
+                queue_full_count: Arc::new(AtomicU64::new(0)),
                 config,
                 highest_write_slot,
+                history,
+                transaction_notifications: false,
+                subscribers: Subscribers::default(),
+            }
+        }
+
+        /// Features supported with the current configuration
+        pub fn capabilities(&self) -> Vec<Capability> {
+            let mut capabilities = vec![
+                Capability::Filters,
+                Capability::BlockMetadata,
+                Capability::SequenceNumbers,
+            ];
+            if self.config.replay_buffer_size > 0 {
+                capabilities.push(Capability::Replay);
+            }
+            if self.config.serve_snapshots {
+                capabilities.push(Capability::Snapshot);
+            }
+            if self.transaction_notifications {
+                capabilities.push(Capability::Transactions);
+            }
+            if self.config.compression == Some(Compression::Gzip) {
+                capabilities.push(Capability::Gzip);
+            }
+            if self.config.delta_keyframe_interval > 0 {
+                capabilities.push(Capability::DeltaAccountData);
+            }
+            capabilities
+        }
+
+        /// The gRPC service, with the configured compression
+        pub fn into_server(self) -> AccountsDbServer<Self> {
+            let compression = self.config.compression;
+            let server = AccountsDbServer::new(self);
+            match compression {
+                Some(Compression::Gzip) => server.send_gzip(),
+                None => server,
+            }
+        }
+
+        /// Handle for sending updates to this service's subscribers
+        pub fn update_sender(&self) -> UpdateSender {
+            UpdateSender {
+                queue: self.queue.clone(),
+                queue_full_count: self.queue_full_count.clone(),
+                sender: self.sender.clone(),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct QueuedUpdate {
+        update: Update,
+        broadcast: bool,
+    }
+
+    /// Numbers, encodes and records queued updates and broadcasts them
+    ///
+    /// Runs on its own thread, so the validator's threads only queue updates.
+    /// Stops when all UpdateSenders are dropped.
+    fn publish_updates(
+        mut queue: mpsc::Receiver<QueuedUpdate>,
+        sender: broadcast::Sender<Arc<SharedUpdate>>,
+        history: Arc<Mutex<UpdateHistory>>,
+    ) {
+        let mut seq = history.lock().unwrap().seq;
+        while let Some(QueuedUpdate {
+            mut update,
+            broadcast,
+        }) = queue.blocking_recv()
+        {
+            seq += 1;
+            update.seq = seq;
+            let update = Arc::new(SharedUpdate::new(update));
+
+            // Keep the lock while sending, see Service::subscribe()
+            let mut history = history.lock().unwrap();
+            history.seq = seq;
+            history.push(&update);
+            if broadcast {
+                // Don't care about the error that happens when there are no receivers.
+                let _ = sender.send(update);
+            }
+        }
+    }
+
+    /// Sends updates to all subscribers of a Service and records them in its history
+    #[derive(Clone, Debug)]
+    pub struct UpdateSender {
+        queue: mpsc::Sender<QueuedUpdate>,
+        queue_full_count: Arc<AtomicU64>,
+        sender: broadcast::Sender<Arc<SharedUpdate>>,
+    }
+
+    impl UpdateSender {
+        /// Waits when broadcast_buffer_size updates are queued already, dropping
+        /// them would leave the history incomplete. Blocks the validator thread,
+        /// async contexts use send_async instead.
+        pub fn send(&self, update: Update) {
+            let queued = QueuedUpdate {
+                update,
+                broadcast: true,
+            };
+            let queued = match self.queue.try_send(queued) {
+                Err(TrySendError::Full(queued)) => queued,
+                // fails only when the service is shutting down
+                _ => return,
+            };
+            let count = self.queue_full_count.fetch_add(1, Ordering::Relaxed) + 1;
+            if count.is_power_of_two() {
+                warn!(
+                    "the encoder queue was full {} times, the validator waited for it",
+                    count
+                );
+            }
+            let _ = self.queue.blocking_send(queued);
+        }
+
+        /// Like send, for async contexts
+        pub async fn send_async(&self, update: Update) {
+            self.queue_async(update, true).await;
+        }
+
+        /// Numbers and records the update, but doesn't broadcast it, like an
+        /// update that failed to be delivered
+        pub(crate) async fn lose_async(&self, update: Update) {
+            self.queue_async(update, false).await;
+        }
+
+        async fn queue_async(&self, update: Update, broadcast: bool) {
+            // fails only when the service is shutting down
+            let _ = self.queue.send(QueuedUpdate { update, broadcast }).await;
+        }
+
+        /// Sends a ping to all subscribers, which is not numbered or recorded
+        pub fn ping(&self) {
+            let _ = self.sender.send(Arc::new(SharedUpdate::new(Update {
+                update_oneof: Some(UpdateOneof::Ping(Ping {})),
+                ..Update::default()
+            })));
+        }
+
+        /// Number of currently connected subscribers
+        pub fn subscriber_count(&self) -> usize {
+            self.sender.receiver_count()
+        }
+    }
+
+    /// Fills in Update::skipped for the updates sent to one subscriber
+    #[derive(Debug, Default)]
+    pub(crate) struct SequenceTracker {
+        /// seq of the previous numbered update sent
+        prev_seq: Option<u64>,
+        /// Number of numbered updates left out on purpose since then
+        skipped: u64,
+    }
+
+    impl SequenceTracker {
+        /// The update with this seq is left out on purpose
+        pub fn skip(&mut self, seq: u64) {
+            if seq != 0 {
+                self.skipped += 1;
+            }
+        }
+
+        /// This many numbered updates are left out on purpose
+        pub fn skip_count(&mut self, count: u64) {
+            self.skipped += count;
+        }
+
+        /// All updates up to seq that were not sent are left out on purpose
+        pub fn skip_to(&mut self, seq: u64) {
+            if let Some(prev_seq) = self.prev_seq {
+                self.skipped = seq.saturating_sub(prev_seq);
+            }
+        }
+
+        /// Call for each update before sending it, in order. Returns its skipped field.
+        pub fn prepare(&mut self, seq: u64) -> u64 {
+            if seq == 0 {
+                return 0;
             }
+            self.prev_seq = Some(seq);
+            std::mem::take(&mut self.skipped)
         }
     }
 
+    /// An update selected for a single subscriber
+    struct Selected {
+        shared: Arc<SharedUpdate>,
+        /// Replaces the update, if the subscriber's filter changed it
+        changed: Option<Update>,
+    }
+
+    impl Selected {
+        fn update(&self) -> &Update {
+            self.changed.as_ref().unwrap_or(&self.shared.update)
+        }
+
+        fn write_key(&self) -> Option<WriteKey> {
+            match self.update().update_oneof.as_ref() {
+                Some(UpdateOneof::AccountWrite(write)) => Some(WriteKey {
+                    pubkey: write.pubkey.clone(),
+                    slot: write.slot,
+                }),
+                _ => None,
+            }
+        }
+    }
+
+    /// Turns broadcast updates into the ones sent to a single subscriber
+    struct SubscriberStream {
+        filter: SubscriptionFilter,
+        delta_encoder: Option<DeltaEncoder>,
+        sequence: SequenceTracker,
+        /// Does the subscriber understand SlowSubscriber?
+        slow_subscriber_reports: bool,
+    }
+
+    impl SubscriberStream {
+        /// None if the update is left out for this subscriber
+        fn select(&mut self, shared: Arc<SharedUpdate>) -> Option<Selected> {
+            let changed = match self.filter.apply(&shared.update) {
+                Some(Cow::Borrowed(_)) => None,
+                Some(Cow::Owned(update)) => Some(update),
+                None => {
+                    self.sequence.skip(shared.update.seq);
+                    return None;
+                }
+            };
+            Some(Selected { shared, changed })
+        }
+
+        /// Call for each selected update that is sent, in order. Reuses the
+        /// shared encoding, unless the update is changed for this subscriber.
+        fn encode(&mut self, selected: &Selected) -> EncodedUpdate {
+            let delta = self
+                .delta_encoder
+                .as_mut()
+                .and_then(|delta_encoder| delta_encoder.encode(selected.update()));
+            let bytes = match (delta, &selected.changed) {
+                (Some(update), _) => update.encode_to_vec().into(),
+                (None, Some(update)) => update.encode_to_vec().into(),
+                (None, None) => selected.shared.encoded.clone(),
+            };
+            EncodedUpdate {
+                bytes,
+                skipped: self.sequence.prepare(selected.shared.update.seq),
+            }
+        }
+
+        /// The update that tells the subscriber about the slow subscriber policy
+        fn report(&self, report: SlowSubscriber) -> Option<Selected> {
+            if !self.slow_subscriber_reports {
+                return None;
+            }
+            Some(Selected {
+                shared: Arc::new(SharedUpdate::new(Update {
+                    update_oneof: Some(UpdateOneof::SlowSubscriber(report)),
+                    ..Update::default()
+                })),
+                changed: None,
+            })
+        }
+    }
+
+    /// Splits the snapshot accounts into messages, leaving out filtered accounts
+    fn snapshot_chunks(
+        slot: u64,
+        accounts: Vec<AccountWrite>,
+        filter: &mut SubscriptionFilter,
+    ) -> Vec<Update> {
+        let mut chunks = vec![AccountsSnapshot {
+            slot,
+            accounts: vec![],
+            is_last: false,
+        }];
+        let mut chunk_bytes = 0;
+        for mut write in accounts {
+            if !filter.select(&mut write) {
+                continue;
+            }
+            if chunk_bytes >= SNAPSHOT_CHUNK_BYTES {
+                chunks.push(AccountsSnapshot {
+                    slot,
+                    accounts: vec![],
+                    is_last: false,
+                });
+                chunk_bytes = 0;
+            }
+            chunk_bytes += write.data.len();
+            chunks.last_mut().unwrap().accounts.push(write);
+        }
+        chunks.last_mut().unwrap().is_last = true;
+        chunks
+            .into_iter()
+            .map(|chunk| Update {
+                update_oneof: Some(UpdateOneof::AccountsSnapshot(chunk)),
+                ..Update::default()
+            })
+            .collect()
+    }
+
+    /// Tells the subscriber that updates were lost, and ends its stream
+    async fn disconnect(
+        stream: &mut SubscriberStream,
+        tx: &mpsc::Sender<Result<EncodedUpdate, Status>>,
+    ) {
+        let report = stream.report(SlowSubscriber {
+            policy: Policy::Disconnect as i32,
+            ..SlowSubscriber::default()
+        });
+        if let Some(report) = report {
+            let _ = tx.send(Ok(stream.encode(&report))).await;
+        }
+        let _ = tx
+            .send(Err(Status::new(
+                Code::ResourceExhausted,
+                "subscriber fell behind, updates were lost",
+            )))
+            .await;
+    }
+
     #[tonic::async_trait]
     impl AccountsDb for Service {
-        type SubscribeStream = ReceiverStream<Result<Update, Status>>;
+        type SubscribeStream = ReceiverStream<Result<EncodedUpdate, Status>>;
 
         async fn subscribe(
             &self,
-            _request: Request<SubscribeRequest>,
+            request: Request<SubscribeRequest>,
         ) -> Result<Response<Self::SubscribeStream>, Status> {
-            info!("new subscriber");
+            info!(
+                "new subscriber with protocol version {}",
+                request.get_ref().protocol_version
+            );
+            let capabilities: Vec<i32> =
+                self.capabilities().into_iter().map(|c| c as i32).collect();
+            let missing: Vec<String> = request
+                .get_ref()
+                .capabilities
+                .iter()
+                .filter(|c| !capabilities.contains(c))
+                .map(|c| match Capability::from_i32(*c) {
+                    Some(c) => format!("{:?}", c),
+                    None => format!("unknown capability {}", c),
+                })
+                .collect();
+            if !missing.is_empty() {
+                let msg = format!(
+                    "the plugin does not support {} with its current configuration",
+                    missing.join(", ")
+                );
+                warn!("refusing subscriber: {}", msg);
+                return Err(Status::new(Code::FailedPrecondition, msg));
+            }
+            let permissions = request.extensions().get::<AccessPermissions>().cloned();
+            let filter = SubscriptionFilter::new(request.get_ref(), permissions)
+                .map_err(|err| Status::new(Code::InvalidArgument, err))?;
+            let delta_encoder = if request.get_ref().delta_account_data
+                && self.config.delta_keyframe_interval > 0
+            {
+                Some(DeltaEncoder::new(self.config.delta_keyframe_interval))
+            } else {
+                None
+            };
+            let mut stream = SubscriberStream {
+                filter,
+                delta_encoder,
+                sequence: SequenceTracker::default(),
+                slow_subscriber_reports: request.get_ref().protocol_version >= 2,
+            };
+            let policy = self.config.slow_subscriber_policy;
+            let mut backlog = Backlog::new(policy, self.config.broadcast_buffer_size);
             let (tx, rx) = mpsc::channel(self.config.subscriber_buffer_size);
-            let mut broadcast_rx = self.sender.subscribe();
+            let registration = self.subscribers.register(SubscriberInfo {
+                remote_addr: request.remote_addr(),
+                protocol_version: request.get_ref().protocol_version,
+                connected_timestamp_us: timestamp_us(),
+                // the SubscribeResponse below
+                updates_sent: AtomicU64::new(1),
+                backlog_len: AtomicU64::new(0),
+                dropped_writes: AtomicU64::new(0),
+                queue: tx.clone(),
+                queue_size: self.config.subscriber_buffer_size,
+            });
+            let stats = registration.info.clone();
+
+            // Subscribing while holding the history lock guarantees that each
+            // update is either part of the replay or snapshot, or received live,
+            // but not both. Taking the snapshot only references the state.
+            let (mut broadcast_rx, subscribed_seq, replay, snapshot) = {
+                let history = self.history.lock().unwrap();
+                let replay = request
+                    .get_ref()
+                    .from_slot
+                    .and_then(|slot| history.replay_buffer.replay_from(slot));
+                let snapshot = if replay.is_none() && request.get_ref().snapshot {
+                    history.accounts_state.snapshot()
+                } else {
+                    None
+                };
+                (self.sender.subscribe(), history.seq, replay, snapshot)
+            };
+            if let Some(from_slot) = request.get_ref().from_slot {
+                info!(
+                    "subscriber requested replay from slot {}, replaying: {}",
+                    from_slot,
+                    replay.is_some()
+                );
+            }
 
-            tx.send(Ok(Update {
+            tx.send(Ok(EncodedUpdate::new(&Update {
                 update_oneof: Some(UpdateOneof::SubscribeResponse(SubscribeResponse {
                     highest_write_slot: self.highest_write_slot.load(Ordering::SeqCst),
+                    replaying: replay.is_some(),
+                    snapshot: snapshot.is_some(),
+                    protocol_version: PROTOCOL_VERSION,
+                    capabilities,
+                    catchup_seq: if replay.is_some() || snapshot.is_some() {
+                        subscribed_seq
+                    } else {
+                        0
+                    },
                 })),
-            }))
+                ..Update::default()
+            })))
             .await
             .unwrap();
 
             tokio::spawn(async move {
-                let mut exit = false;
-                while !exit {
-                    let fwd = broadcast_rx.recv().await.map_err(|err| {
-                        // Note: If we can't keep up pulling from the broadcast
-                        // channel here, there'll be a Lagged error, and we'll
-                        // close the connection because data was lost.
-                        warn!("error while receiving message to be broadcast: {:?}", err);
-                        exit = true;
-                        Status::new(Code::Internal, err.to_string())
-                    });
-                    if let Err(_err) = tx.send(fwd).await {
-                        info!("subscriber stream closed");
-                        exit = true;
+                // listed as long as the stream is open
+                let _registration = registration;
+                let mut catchup = replay.unwrap_or_default();
+                if let Some(snapshot) = snapshot {
+                    // copies the account data, outside of the history lock
+                    let accounts: Vec<_> = snapshot.accounts().collect();
+                    info!(
+                        "sending snapshot with {} accounts at slot {}",
+                        accounts.len(),
+                        snapshot.slot
+                    );
+                    catchup = snapshot_chunks(snapshot.slot, accounts, &mut stream.filter)
+                        .into_iter()
+                        .chain(snapshot.pending())
+                        .map(|update| Arc::new(SharedUpdate::new(update)))
+                        .collect();
+                }
+                for update in catchup {
+                    // the replay leaves out older updates on purpose
+                    stream.sequence.skip_to(update.update.seq.saturating_sub(1));
+                    if let Some(selected) = stream.select(update) {
+                        if tx.send(Ok(stream.encode(&selected))).await.is_err() {
+                            info!("subscriber stream closed");
+                            return;
+                        }
+                        stats.updates_sent.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+
+                // received live from here on
+                stream.sequence.skip_to(subscribed_seq);
+
+                loop {
+                    tokio::select! {
+                        permit = tx.reserve(), if !backlog.is_empty() => {
+                            let permit = match permit {
+                                Ok(permit) => permit,
+                                Err(_) => {
+                                    info!("subscriber stream closed");
+                                    break;
+                                }
+                            };
+                            let (skipped, selected) = backlog.pop().expect("backlog is not empty");
+                            stream.sequence.skip_count(skipped);
+                            if let Some(selected) = selected {
+                                permit.send(Ok(stream.encode(&selected)));
+                                stats.updates_sent.fetch_add(1, Ordering::Relaxed);
+                            }
+                            if backlog.is_empty() {
+                                if let Some(report) = backlog.take_report() {
+                                    info!(
+                                        "subscriber caught up, {} account writes were left out",
+                                        report.dropped_writes
+                                    );
+                                    stats.dropped_writes.fetch_add(report.dropped_writes, Ordering::Relaxed);
+                                    if let Some(report) = stream.report(report) {
+                                        backlog.push(report, None);
+                                    }
+                                }
+                            }
+                            stats.backlog_len.store(backlog.waiting() as u64, Ordering::Relaxed);
+                        },
+                        received = broadcast_rx.recv() => {
+                            let selected = match received {
+                                Ok(update) => match stream.select(update) {
+                                    Some(selected) => selected,
+                                    None => continue,
+                                },
+                                Err(broadcast::error::RecvError::Lagged(count)) => {
+                                    // We couldn't keep up pulling from the broadcast channel.
+                                    warn!("subscriber lagged, {} updates were lost", count);
+                                    disconnect(&mut stream, &tx).await;
+                                    break;
+                                }
+                                Err(err) => {
+                                    warn!("error while receiving message to be broadcast: {:?}", err);
+                                    let _ = tx.send(Err(Status::new(Code::Internal, err.to_string()))).await;
+                                    break;
+                                }
+                            };
+                            if policy == SlowSubscriberPolicy::Disconnect {
+                                if tx.send(Ok(stream.encode(&selected))).await.is_err() {
+                                    info!("subscriber stream closed");
+                                    break;
+                                }
+                                stats.updates_sent.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                            if backlog.is_empty() {
+                                match tx.try_reserve() {
+                                    Ok(permit) => {
+                                        permit.send(Ok(stream.encode(&selected)));
+                                        stats.updates_sent.fetch_add(1, Ordering::Relaxed);
+                                        continue;
+                                    }
+                                    Err(TrySendError::Full(_)) => {
+                                        info!("subscriber fell behind, applying the {:?} policy", policy);
+                                    }
+                                    Err(TrySendError::Closed(_)) => {
+                                        info!("subscriber stream closed");
+                                        break;
+                                    }
+                                }
+                            }
+                            let write_key = selected.write_key();
+                            if !backlog.push(selected, write_key) {
+                                warn!("subscriber fell behind by too many updates");
+                                disconnect(&mut stream, &tx).await;
+                                break;
+                            }
+                            stats.backlog_len.store(backlog.waiting() as u64, Ordering::Relaxed);
+                        },
                     }
                 }
             });
@@ -101,18 +697,19 @@ pub mod geyser_service {
 
 pub struct PluginData {
     runtime: Option<tokio::runtime::Runtime>,
-    server_broadcast: broadcast::Sender<Update>,
+    update_sender: geyser_service::UpdateSender,
     server_exit_sender: Option<broadcast::Sender<()>>,
-    accounts_selector: AccountsSelector,
+    accounts_selector: Arc<RwLock<AccountsSelector>>,
+    transaction_notifications: bool,
 
     /// Largest slot that an account write was processed for
     highest_write_slot: Arc<AtomicU64>,
 
-    /// Accounts that saw account writes
+    /// Accounts that saw account writes, with their owner when they were selected
     ///
     /// Needed to catch writes that signal account closure, where
     /// lamports=0 and owner=system-program.
-    active_accounts: RwLock<HashSet<[u8; 32]>>,
+    active_accounts: ActiveAccounts,
 }
 
 #[derive(Default)]
@@ -127,17 +724,44 @@ impl std::fmt::Debug for Plugin {
     }
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// Require client certificates signed by this CA
+    pub client_ca_cert_path: Option<String>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct PluginConfig {
     pub bind_address: String,
     pub service_config: geyser_service::ServiceConfig,
+    /// Send transactions that reference selected accounts
+    #[serde(default)]
+    pub transaction_notifications: bool,
+    pub tls: Option<TlsConfig>,
+    /// Clients must send one of these as bearer token (no authentication if empty)
+    #[serde(default)]
+    pub access_tokens: Vec<AccessTokenConfig>,
+    /// Serve the AccountsDbAdmin service to clients that send one of these as
+    /// bearer token (disabled if empty)
+    #[serde(default)]
+    pub admin_access_tokens: Vec<String>,
+}
+
+/// Current wall-clock time in microseconds since the unix epoch
+pub(crate) fn timestamp_us() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
 }
 
 impl PluginData {
     fn broadcast(&self, update: UpdateOneof) {
-        // Don't care about the error that happens when there are no receivers.
-        let _ = self.server_broadcast.send(Update {
+        self.update_sender.send(Update {
             update_oneof: Some(update),
+            ..Update::default()
         });
     }
 }
@@ -160,7 +784,10 @@ impl GeyserPlugin for Plugin {
         file.read_to_string(&mut contents)?;
 
         let result: serde_json::Value = serde_json::from_str(&contents).unwrap();
-        let accounts_selector = Self::create_accounts_selector_from_config(&result);
+        let accounts_selector = Arc::new(RwLock::new(Self::create_accounts_selector_from_config(
+            &result,
+        )));
+        let active_accounts = ActiveAccounts::default();
 
         let config: PluginConfig = serde_json::from_str(&contents).map_err(|err| {
             GeyserPluginError::ConfigFileReadError {
@@ -180,27 +807,66 @@ impl GeyserPlugin for Plugin {
                 })?;
 
         let highest_write_slot = Arc::new(AtomicU64::new(0));
-        let service =
+        let mut service =
             geyser_service::Service::new(config.service_config, highest_write_slot.clone());
+        service.transaction_notifications = config.transaction_notifications;
         let (server_exit_sender, mut server_exit_receiver) = broadcast::channel::<()>(1);
-        let server_broadcast = service.sender.clone();
+        let update_sender = service.update_sender();
+        let ping_sender = service.update_sender();
+
+        let authenticator = Authenticator::new(&config.access_tokens)
+            .map_err(|msg| GeyserPluginError::ConfigFileReadError { msg })?;
+        let admin_server = if config.admin_access_tokens.is_empty() {
+            None
+        } else {
+            let admin_tokens: Vec<_> = config
+                .admin_access_tokens
+                .iter()
+                .map(|token| AccessTokenConfig {
+                    token: token.clone(),
+                    accounts: vec![],
+                    owners: vec![],
+                })
+                .collect();
+            let admin_authenticator = Authenticator::new(&admin_tokens)
+                .map_err(|msg| GeyserPluginError::ConfigFileReadError { msg })?;
+            let admin_service = AdminService::new(
+                accounts_selector.clone(),
+                active_accounts.clone(),
+                service.history.clone(),
+                service.subscribers.clone(),
+            );
+            Some(InterceptedService::new(
+                AccountsDbAdminServer::new(admin_service),
+                move |request| admin_authenticator.authenticate(request),
+            ))
+        };
+        let server = InterceptedService::new(service.into_server(), move |request| {
+            authenticator.authenticate(request)
+        });
+
+        let mut server_builder = Server::builder();
+        if let Some(tls) = &config.tls {
+            server_builder = server_builder
+                .tls_config(Self::make_server_tls_config(tls)?)
+                .map_err(|err| GeyserPluginError::ConfigFileReadError {
+                    msg: format!("Error setting up TLS: {:?}", err),
+                })?;
+        }
 
-        let server = geyser_proto::accounts_db_server::AccountsDbServer::new(service);
         let runtime = tokio::runtime::Runtime::new().unwrap();
-        runtime.spawn(Server::builder().add_service(server).serve_with_shutdown(
-            addr,
-            async move {
-                let _ = server_exit_receiver.recv().await;
-            },
-        ));
-        let server_broadcast_c = server_broadcast.clone();
+        runtime.spawn(
+            server_builder
+                .add_service(server)
+                .add_optional_service(admin_server)
+                .serve_with_shutdown(addr, async move {
+                    let _ = server_exit_receiver.recv().await;
+                }),
+        );
         let mut server_exit_receiver = server_exit_sender.subscribe();
         runtime.spawn(async move {
             loop {
-                // Don't care about the error if there are no receivers.
-                let _ = server_broadcast_c.send(Update {
-                    update_oneof: Some(UpdateOneof::Ping(Ping {})),
-                });
+                ping_sender.ping();
 
                 tokio::select! {
                     _ = server_exit_receiver.recv() => { break; },
@@ -211,11 +877,12 @@ impl GeyserPlugin for Plugin {
 
         self.data = Some(PluginData {
             runtime: Some(runtime),
-            server_broadcast,
+            update_sender,
             server_exit_sender: Some(server_exit_sender),
             accounts_selector,
+            transaction_notifications: config.transaction_notifications,
             highest_write_slot,
-            active_accounts: RwLock::new(HashSet::new()),
+            active_accounts,
         });
 
         Ok(())
@@ -256,22 +923,27 @@ impl GeyserPlugin for Plugin {
 
                 // Select only accounts configured to look at, plus writes to accounts
                 // that were previously selected (to catch closures and account reuse)
-                let is_selected = data
-                    .accounts_selector
-                    .is_account_selected(account.pubkey, account.owner);
+                let accounts_selector = data.accounts_selector.read().unwrap();
+                let is_selected =
+                    accounts_selector.is_account_selected(account.pubkey, account.owner);
                 let previously_selected = {
                     let read = data.active_accounts.read().unwrap();
-                    read.contains(&account.pubkey[0..32])
+                    read.contains_key(&account.pubkey[0..32])
                 };
                 if !is_selected && !previously_selected {
                     return Ok(());
                 }
 
-                // If the account is newly selected, add it
+                // If the account is newly selected, add it. Keep the selector locked
+                // until then, the admin service removes accounts from both.
                 if !previously_selected {
                     let mut write = data.active_accounts.write().unwrap();
-                    write.insert(account.pubkey.try_into().unwrap());
+                    write.insert(
+                        account.pubkey.try_into().unwrap(),
+                        account.owner.try_into().unwrap(),
+                    );
                 }
+                drop(accounts_selector);
 
                 data.highest_write_slot.fetch_max(slot, Ordering::SeqCst);
 
@@ -293,6 +965,8 @@ impl GeyserPlugin for Plugin {
                     rent_epoch: account.rent_epoch,
                     data: account.data.to_vec(),
                     is_selected,
+                    send_timestamp_us: timestamp_us(),
+                    data_delta: None,
                 }));
             }
         }
@@ -317,6 +991,7 @@ impl GeyserPlugin for Plugin {
             slot,
             parent,
             status: status as i32,
+            send_timestamp_us: timestamp_us(),
         }));
 
         Ok(())
@@ -325,9 +1000,126 @@ impl GeyserPlugin for Plugin {
     fn notify_end_of_startup(&mut self) -> PluginResult<()> {
         Ok(())
     }
+
+    fn notify_transaction(
+        &mut self,
+        transaction: ReplicaTransactionInfoVersions,
+        slot: u64,
+    ) -> PluginResult<()> {
+        let data = self.data.as_ref().expect("plugin must be initialized");
+        match transaction {
+            ReplicaTransactionInfoVersions::V0_0_1(transaction) => {
+                if transaction.is_vote {
+                    return Ok(());
+                }
+
+                let account_keys: Vec<Vec<u8>> = transaction
+                    .transaction
+                    .message()
+                    .account_keys_iter()
+                    .map(|key| key.to_bytes().to_vec())
+                    .collect();
+                let is_selected = data
+                    .accounts_selector
+                    .read()
+                    .unwrap()
+                    .is_transaction_selected(account_keys.iter().map(|key| key.as_slice()));
+                let references_active_account = || {
+                    let read = data.active_accounts.read().unwrap();
+                    account_keys.iter().any(|key| read.contains_key(&key[..]))
+                };
+                if !is_selected && !references_active_account() {
+                    return Ok(());
+                }
+
+                debug!(
+                    "Updating transaction {} at slot {:?}",
+                    transaction.signature, slot
+                );
+
+                let meta = transaction.transaction_status_meta;
+                data.broadcast(UpdateOneof::Transaction(Transaction {
+                    slot,
+                    signature: transaction.signature.as_ref().to_vec(),
+                    account_keys,
+                    err: meta.status.as_ref().err().map(|err| err.to_string()),
+                    log_messages: meta.log_messages.clone().unwrap_or_default(),
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    fn notify_block_metadata(&mut self, blockinfo: ReplicaBlockInfoVersions) -> PluginResult<()> {
+        let data = self.data.as_ref().expect("plugin must be initialized");
+        match blockinfo {
+            ReplicaBlockInfoVersions::V0_0_1(blockinfo) => {
+                debug!("Updating block metadata for slot {:?}", blockinfo.slot);
+
+                let rewards = blockinfo
+                    .rewards
+                    .iter()
+                    .filter_map(|reward| {
+                        let pubkey = match bs58::decode(&reward.pubkey).into_vec() {
+                            Ok(pubkey) => pubkey,
+                            Err(err) => {
+                                error!("bad reward pubkey {}: {:?}", reward.pubkey, err);
+                                return None;
+                            }
+                        };
+                        let reward_type = match reward.reward_type {
+                            None => ProtoRewardType::Unspecified,
+                            Some(RewardType::Fee) => ProtoRewardType::Fee,
+                            Some(RewardType::Rent) => ProtoRewardType::Rent,
+                            Some(RewardType::Staking) => ProtoRewardType::Staking,
+                            Some(RewardType::Voting) => ProtoRewardType::Voting,
+                        };
+                        Some(Reward {
+                            pubkey,
+                            lamports: reward.lamports,
+                            post_balance: reward.post_balance,
+                            reward_type: reward_type as i32,
+                            commission: reward.commission.map(|c| c as u32),
+                        })
+                    })
+                    .collect();
+
+                data.broadcast(UpdateOneof::BlockMetadata(BlockMetadata {
+                    slot: blockinfo.slot,
+                    blockhash: blockinfo.blockhash.to_string(),
+                    block_time: blockinfo.block_time,
+                    block_height: blockinfo.block_height,
+                    rewards,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    fn transaction_notifications_enabled(&self) -> bool {
+        self.data
+            .as_ref()
+            .map(|data| data.transaction_notifications)
+            .unwrap_or(false)
+    }
 }
 
 impl Plugin {
+    fn make_server_tls_config(config: &TlsConfig) -> PluginResult<ServerTlsConfig> {
+        let read = |path: &str| {
+            std::fs::read(path).map_err(|err| GeyserPluginError::ConfigFileReadError {
+                msg: format!("Error reading {}: {:?}", path, err),
+            })
+        };
+        let identity = Identity::from_pem(read(&config.cert_path)?, read(&config.key_path)?);
+        let mut tls_config = ServerTlsConfig::new().identity(identity);
+        if let Some(client_ca_cert_path) = &config.client_ca_cert_path {
+            tls_config =
+                tls_config.client_ca_root(Certificate::from_pem(read(client_ca_cert_path)?));
+        }
+        Ok(tls_config)
+    }
+
     fn create_accounts_selector_from_config(config: &serde_json::Value) -> AccountsSelector {
         let accounts_selector = &config["accounts_selector"];
 
@@ -385,4 +1177,147 @@ pub(crate) mod tests {
         let config: serde_json::Value = serde_json::from_str(config).unwrap();
         Plugin::create_accounts_selector_from_config(&config);
     }
+
+    #[test]
+    fn test_sequence_tracker() {
+        let mut sequence = geyser_service::SequenceTracker::default();
+        let send = |sequence: &mut geyser_service::SequenceTracker, seq| sequence.prepare(seq);
+        // replayed, without the updates in between
+        assert_eq!(send(&mut sequence, 5), 0);
+        sequence.skip_to(7);
+        assert_eq!(send(&mut sequence, 8), 2);
+        // live, after the replay up to 10
+        sequence.skip_to(10);
+        assert_eq!(send(&mut sequence, 0), 0);
+        sequence.skip(11);
+        assert_eq!(send(&mut sequence, 12), 3);
+        // 13 was lost: the subscriber can tell
+        assert_eq!(send(&mut sequence, 14), 0);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_checks_capabilities() {
+        use {
+            geyser_proto::{accounts_db_server::AccountsDb, Capability, SubscribeRequest},
+            prost::Message,
+            tokio_stream::StreamExt,
+        };
+
+        let config = serde_json::from_str(
+            "{ \"broadcast_buffer_size\": 10, \"subscriber_buffer_size\": 10 }",
+        )
+        .unwrap();
+        let service = geyser_service::Service::new(config, Arc::new(AtomicU64::new(0)));
+        let subscribe = |capabilities: Vec<Capability>| {
+            service.subscribe(tonic::Request::new(SubscribeRequest {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: capabilities.into_iter().map(|c| c as i32).collect(),
+                ..SubscribeRequest::default()
+            }))
+        };
+
+        let err = subscribe(vec![Capability::Filters, Capability::Transactions])
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+
+        let mut stream = subscribe(vec![Capability::Filters])
+            .await
+            .unwrap()
+            .into_inner();
+        let encoded = stream.next().await.unwrap().unwrap().encode_to_vec();
+        match Update::decode(encoded.as_slice()).unwrap().update_oneof {
+            Some(UpdateOneof::SubscribeResponse(response)) => {
+                assert_eq!(response.protocol_version, PROTOCOL_VERSION);
+                // no replay or snapshot was requested
+                assert_eq!(response.catchup_seq, 0);
+                assert!(!response
+                    .capabilities
+                    .contains(&(Capability::Transactions as i32)));
+            }
+            _ => panic!("expected a SubscribeResponse"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_conflates_writes() {
+        use {
+            geyser_proto::{accounts_db_server::AccountsDb, SlowSubscriber, SubscribeRequest},
+            prost::Message,
+            tokio_stream::StreamExt,
+        };
+
+        let config = serde_json::from_str(
+            "{ \"broadcast_buffer_size\": 10, \"subscriber_buffer_size\": 1, \
+               \"slow_subscriber_policy\": \"conflate\" }",
+        )
+        .unwrap();
+        let service = geyser_service::Service::new(config, Arc::new(AtomicU64::new(0)));
+        let mut stream = service
+            .subscribe(tonic::Request::new(SubscribeRequest {
+                protocol_version: PROTOCOL_VERSION,
+                ..SubscribeRequest::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        async fn next(
+            stream: &mut <geyser_service::Service as AccountsDb>::SubscribeStream,
+        ) -> (u64, u64, UpdateOneof) {
+            let encoded = stream.next().await.unwrap().unwrap().encode_to_vec();
+            let update = Update::decode(encoded.as_slice()).unwrap();
+            (update.seq, update.skipped, update.update_oneof.unwrap())
+        }
+        assert!(matches!(
+            next(&mut stream).await.2,
+            UpdateOneof::SubscribeResponse(_)
+        ));
+
+        let write = |pubkey: u8, lamports| {
+            UpdateOneof::AccountWrite(AccountWrite {
+                pubkey: vec![pubkey; 32],
+                lamports,
+                ..AccountWrite::default()
+            })
+        };
+        let slot = UpdateOneof::SlotUpdate(SlotUpdate {
+            slot: 1,
+            ..SlotUpdate::default()
+        });
+        let update_sender = service.update_sender();
+        for update_oneof in [
+            write(1, 1),
+            write(1, 2),
+            slot.clone(),
+            write(1, 4),
+            write(2, 5),
+        ] {
+            update_sender
+                .send_async(Update {
+                    update_oneof: Some(update_oneof),
+                    ..Update::default()
+                })
+                .await;
+        }
+        // the subscriber reads nothing until all are received
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+        assert_eq!(next(&mut stream).await, (1, 0, write(1, 1)));
+        assert_eq!(next(&mut stream).await, (3, 1, slot));
+        assert_eq!(next(&mut stream).await, (4, 0, write(1, 4)));
+        assert_eq!(next(&mut stream).await, (5, 0, write(2, 5)));
+        assert_eq!(
+            next(&mut stream).await,
+            (
+                0,
+                0,
+                UpdateOneof::SlowSubscriber(SlowSubscriber {
+                    policy: geyser_proto::slow_subscriber::Policy::Conflate as i32,
+                    dropped_writes: 1,
+                    stale_accounts: vec![],
+                })
+            )
+        );
+    }
 }
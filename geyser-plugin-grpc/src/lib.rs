@@ -1,2 +1,11 @@
 pub mod accounts_selector;
+pub mod accounts_state;
+pub mod admin_service;
+pub mod auth;
+pub mod delta_encoder;
+pub mod encoding;
 pub mod geyser_plugin_grpc;
+pub mod replay_buffer;
+pub mod scenario;
+pub mod slow_subscriber;
+pub mod subscription_filter;
@@ -0,0 +1,156 @@
+use {
+    crate::{
+        encoding::SharedUpdate,
+        geyser_plugin_grpc::geyser_proto::{update::UpdateOneof, Update},
+    },
+    std::{collections::VecDeque, sync::Arc},
+};
+
+fn update_slot(update: &Update) -> Option<u64> {
+    match update.update_oneof.as_ref()? {
+        UpdateOneof::AccountWrite(write) => Some(write.slot),
+        UpdateOneof::SlotUpdate(slot_update) => Some(slot_update.slot),
+        UpdateOneof::Transaction(transaction) => Some(transaction.slot),
+        UpdateOneof::BlockMetadata(block_metadata) => Some(block_metadata.slot),
+        _ => None,
+    }
+}
+
+/// Bounded buffer of the most recent updates that belong to a slot
+///
+/// Allows reconnecting subscribers to resume from a slot instead of
+/// needing a new snapshot.
+#[derive(Debug)]
+pub(crate) struct ReplayBuffer {
+    updates: VecDeque<Arc<SharedUpdate>>,
+    max_size: usize,
+
+    /// All updates for slots >= this slot are still in the buffer
+    complete_from_slot: u64,
+}
+
+impl ReplayBuffer {
+    pub fn new(max_size: usize) -> Self {
+        ReplayBuffer {
+            updates: VecDeque::with_capacity(max_size),
+            max_size,
+            complete_from_slot: 0,
+        }
+    }
+
+    pub fn push(&mut self, update: &Arc<SharedUpdate>) {
+        if self.max_size == 0 || update_slot(&update.update).is_none() {
+            return;
+        }
+        if self.updates.len() == self.max_size {
+            let evicted_slot = self
+                .updates
+                .pop_front()
+                .and_then(|u| update_slot(&u.update))
+                .expect("only updates with slots are buffered");
+            self.complete_from_slot = self.complete_from_slot.max(evicted_slot + 1);
+        }
+        self.updates.push_back(update.clone());
+    }
+
+    /// Updates to send to a subscriber that has all data for slots before `from_slot`
+    ///
+    /// Starts at the first buffered update for `from_slot` or newer, and leaves out
+    /// later account writes and transactions for older slots. Returns None if updates for `from_slot`
+    /// were already evicted.
+    pub fn replay_from(&self, from_slot: u64) -> Option<Vec<Arc<SharedUpdate>>> {
+        if self.max_size == 0 || from_slot < self.complete_from_slot {
+            return None;
+        }
+        let start = self
+            .updates
+            .iter()
+            .position(|u| update_slot(&u.update).expect("has slot") >= from_slot)
+            .unwrap_or(self.updates.len());
+        Some(
+            self.updates
+                .iter()
+                .skip(start)
+                .filter(|u| match u.update.update_oneof.as_ref() {
+                    Some(UpdateOneof::AccountWrite(write)) => write.slot >= from_slot,
+                    Some(UpdateOneof::Transaction(transaction)) => transaction.slot >= from_slot,
+                    _ => true,
+                })
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::*,
+        crate::geyser_plugin_grpc::geyser_proto::{AccountWrite, Ping, SlotUpdate},
+    };
+
+    fn write(slot: u64) -> Update {
+        Update {
+            update_oneof: Some(UpdateOneof::AccountWrite(AccountWrite {
+                slot,
+                ..AccountWrite::default()
+            })),
+            ..Update::default()
+        }
+    }
+
+    fn slot_update(slot: u64) -> Update {
+        Update {
+            update_oneof: Some(UpdateOneof::SlotUpdate(SlotUpdate {
+                slot,
+                ..SlotUpdate::default()
+            })),
+            ..Update::default()
+        }
+    }
+
+    fn push(buffer: &mut ReplayBuffer, update: Update) {
+        buffer.push(&Arc::new(SharedUpdate::new(update)));
+    }
+
+    fn replay_from(buffer: &ReplayBuffer, from_slot: u64) -> Option<Vec<Update>> {
+        buffer
+            .replay_from(from_slot)
+            .map(|updates| updates.iter().map(|u| u.update.clone()).collect())
+    }
+
+    #[test]
+    fn test_replay_buffer() {
+        let mut buffer = ReplayBuffer::new(4);
+        push(&mut buffer, write(1));
+        push(
+            &mut buffer,
+            Update {
+                update_oneof: Some(UpdateOneof::Ping(Ping {})),
+                ..Update::default()
+            },
+        );
+        push(&mut buffer, write(2));
+        push(&mut buffer, slot_update(1));
+        push(&mut buffer, write(1));
+        assert_eq!(
+            replay_from(&buffer, 2),
+            Some(vec![write(2), slot_update(1)])
+        );
+        assert_eq!(replay_from(&buffer, 3), Some(vec![]));
+
+        push(&mut buffer, write(3));
+        assert_eq!(replay_from(&buffer, 1), None);
+        assert_eq!(
+            replay_from(&buffer, 2),
+            Some(vec![write(2), slot_update(1), write(3)])
+        );
+    }
+
+    #[test]
+    fn test_replay_buffer_disabled() {
+        let mut buffer = ReplayBuffer::new(0);
+        push(&mut buffer, write(1));
+        assert_eq!(replay_from(&buffer, 1), None);
+    }
+}
@@ -0,0 +1,283 @@
+use {
+    crate::geyser_plugin_grpc::{
+        geyser_proto::{
+            slot_update::Status as SlotUpdateStatus, update::UpdateOneof, AccountWrite, SlotUpdate,
+            Update,
+        },
+        geyser_service::{Service, ServiceConfig, UpdateSender},
+        timestamp_us,
+    },
+    log::*,
+    serde_derive::Deserialize,
+    std::{
+        net::{Shutdown, SocketAddr},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    },
+    tokio::net::{TcpListener, TcpStream},
+    tonic::transport::Server,
+};
+
+/// A scripted sequence of updates, see example-scenario.json
+#[derive(Clone, Debug, Deserialize)]
+pub struct Scenario {
+    pub bind_address: String,
+    pub service_config: ServiceConfig,
+    pub steps: Vec<Step>,
+}
+
+impl Scenario {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Error reading {}: {:?}", path, err))?;
+        serde_json::from_str(&contents)
+            .map_err(|err| format!("The scenario {} is not valid: {:?}", path, err))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScenarioSlotStatus {
+    Processed,
+    Confirmed,
+    Rooted,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    /// Block until this many clients are subscribed
+    WaitForSubscribers {
+        count: usize,
+    },
+    /// A slot status update. Forks are slots that share a parent, uncles are
+    /// slots that never get rooted.
+    Slot {
+        slot: u64,
+        parent: Option<u64>,
+        status: ScenarioSlotStatus,
+    },
+    AccountWrite {
+        slot: u64,
+        /// base58
+        pubkey: String,
+        /// base58
+        owner: String,
+        #[serde(default)]
+        lamports: u64,
+        /// base58
+        #[serde(default)]
+        data: String,
+        /// Like the validator's write version: defaults to a global counter
+        write_version: Option<u64>,
+        /// Set on writes that are not selected any more (closed accounts)
+        #[serde(default)]
+        not_selected: bool,
+        /// Kept for replays, but not sent to subscribers, as if the plugin
+        /// failed to deliver it
+        #[serde(default)]
+        lost: bool,
+    },
+    Sleep {
+        millis: u64,
+    },
+    /// Close all client connections, like a network failure would
+    Disconnect,
+}
+
+fn decode_pubkey(key: &str) -> Result<Vec<u8>, String> {
+    match bs58::decode(key).into_vec() {
+        Ok(bytes) if bytes.len() == 32 => Ok(bytes),
+        _ => Err(format!("bad pubkey in scenario: {}", key)),
+    }
+}
+
+/// Serves scripted updates through the plugin's gRPC service
+///
+/// Unlike the plugin, clients can be disconnected at will.
+pub struct TestServer {
+    pub local_addr: SocketAddr,
+    update_sender: UpdateSender,
+    highest_write_slot: Arc<AtomicU64>,
+    /// Duplicates of the sockets of all accepted connections, for disconnecting
+    connections: Arc<Mutex<Vec<std::net::TcpStream>>>,
+    write_version: u64,
+}
+
+impl TestServer {
+    /// Starts serving on the current tokio runtime, use port 0 to pick a free port
+    pub async fn start(addr: SocketAddr, config: ServiceConfig) -> std::io::Result<Self> {
+        let highest_write_slot = Arc::new(AtomicU64::new(0));
+        let service = Service::new(config, highest_write_slot.clone());
+        let update_sender = service.update_sender();
+        let ping_sender = service.update_sender();
+
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let connections = Arc::new(Mutex::new(vec![]));
+        let incoming = {
+            let connections = connections.clone();
+            async_stream::stream! {
+                loop {
+                    yield listener.accept().await.and_then(|(stream, _)| {
+                        let stream = stream.into_std()?;
+                        connections.lock().unwrap().push(stream.try_clone()?);
+                        TcpStream::from_std(stream)
+                    });
+                }
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(err) = Server::builder()
+                .add_service(service.into_server())
+                .serve_with_incoming(incoming)
+                .await
+            {
+                error!("test server error: {:?}", err);
+            }
+        });
+
+        // Like the plugin, ping regularly so clients don't time out
+        tokio::spawn(async move {
+            loop {
+                ping_sender.ping();
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+
+        info!("test server listening on {}", local_addr);
+        Ok(TestServer {
+            local_addr,
+            update_sender,
+            highest_write_slot,
+            connections,
+            write_version: 0,
+        })
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.update_sender.subscriber_count()
+    }
+
+    /// Closes the connections of all current clients
+    pub fn disconnect(&self) {
+        let connections = std::mem::take(&mut *self.connections.lock().unwrap());
+        info!("disconnecting {} connections", connections.len());
+        for connection in connections {
+            // fails for connections the client closed already
+            let _ = connection.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub async fn run(&mut self, steps: &[Step]) -> Result<(), String> {
+        for step in steps {
+            self.run_step(step).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn run_step(&mut self, step: &Step) -> Result<(), String> {
+        debug!("scenario step {:?}", step);
+        match step {
+            Step::WaitForSubscribers { count } => {
+                while self.subscriber_count() < *count {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+            Step::Slot {
+                slot,
+                parent,
+                status,
+            } => {
+                let status = match status {
+                    ScenarioSlotStatus::Processed => SlotUpdateStatus::Processed,
+                    ScenarioSlotStatus::Confirmed => SlotUpdateStatus::Confirmed,
+                    ScenarioSlotStatus::Rooted => SlotUpdateStatus::Rooted,
+                };
+                self.send(UpdateOneof::SlotUpdate(SlotUpdate {
+                    slot: *slot,
+                    parent: *parent,
+                    status: status as i32,
+                    send_timestamp_us: timestamp_us(),
+                }))
+                .await;
+            }
+            Step::AccountWrite {
+                slot,
+                pubkey,
+                owner,
+                lamports,
+                data,
+                write_version,
+                not_selected,
+                lost,
+            } => {
+                let data = bs58::decode(data)
+                    .into_vec()
+                    .map_err(|err| format!("bad account data in scenario: {:?}", err))?;
+                self.write_version += 1;
+                let write_version = write_version.unwrap_or(self.write_version);
+                self.highest_write_slot.fetch_max(*slot, Ordering::SeqCst);
+                let write = UpdateOneof::AccountWrite(AccountWrite {
+                    slot: *slot,
+                    pubkey: decode_pubkey(pubkey)?,
+                    lamports: *lamports,
+                    owner: decode_pubkey(owner)?,
+                    data,
+                    write_version,
+                    is_selected: !not_selected,
+                    send_timestamp_us: timestamp_us(),
+                    ..AccountWrite::default()
+                });
+                if *lost {
+                    self.update_sender
+                        .lose_async(Update {
+                            update_oneof: Some(write),
+                            ..Update::default()
+                        })
+                        .await;
+                } else {
+                    self.send(write).await;
+                }
+            }
+            Step::Sleep { millis } => {
+                tokio::time::sleep(Duration::from_millis(*millis)).await;
+            }
+            Step::Disconnect => self.disconnect(),
+        }
+        Ok(())
+    }
+
+    pub async fn send(&self, update: UpdateOneof) {
+        self.update_sender
+            .send_async(Update {
+                update_oneof: Some(update),
+                ..Update::default()
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_example_scenario() {
+        let scenario: Scenario =
+            serde_json::from_str(include_str!("../example-scenario.json")).unwrap();
+        assert!(scenario
+            .steps
+            .iter()
+            .any(|step| matches!(step, Step::Disconnect)));
+        for step in scenario.steps {
+            if let Step::AccountWrite { pubkey, owner, .. } = step {
+                decode_pubkey(&pubkey).unwrap();
+                decode_pubkey(&owner).unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use {
+    crate::geyser_plugin_grpc::geyser_proto::{slow_subscriber::Policy, SlowSubscriber},
+    serde_derive::Deserialize,
+    std::collections::{HashMap, HashSet, VecDeque},
+};
+
+/// What to do with a subscriber that doesn't receive updates as fast as they
+/// are sent
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowSubscriberPolicy {
+    /// Wait for it, and close its stream once updates are lost
+    Disconnect,
+    /// Leave out account writes until it caught up, but send everything else
+    SkipWrites,
+    /// Only send the latest write of each account in each slot until it
+    /// caught up
+    Conflate,
+}
+
+impl Default for SlowSubscriberPolicy {
+    fn default() -> Self {
+        SlowSubscriberPolicy::Disconnect
+    }
+}
+
+impl SlowSubscriberPolicy {
+    pub fn to_proto(self) -> Policy {
+        match self {
+            SlowSubscriberPolicy::Disconnect => Policy::Disconnect,
+            SlowSubscriberPolicy::SkipWrites => Policy::SkipWrites,
+            SlowSubscriberPolicy::Conflate => Policy::Conflate,
+        }
+    }
+}
+
+/// The account writes that conflation merges: those to one account in one
+/// slot. Merging across slots would lose the write of the older slot if the
+/// newer one turns out to be on a dead fork.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct WriteKey {
+    pub pubkey: Vec<u8>,
+    pub slot: u64,
+}
+
+#[derive(Debug)]
+enum Entry<T> {
+    /// An update to send, with the key for account writes
+    Waiting(T, Option<WriteKey>),
+    /// Number of numbered updates that were left out
+    Skipped(u64),
+}
+
+/// Updates waiting for a slow subscriber, with account writes left out
+/// according to the policy
+#[derive(Debug)]
+pub(crate) struct Backlog<T> {
+    policy: SlowSubscriberPolicy,
+    /// Most updates that may be waiting
+    max_len: usize,
+    entries: VecDeque<Entry<T>>,
+    /// Number of waiting updates in entries
+    len: usize,
+    /// Entries popped since the last compaction, entries[0] is at this position
+    popped: u64,
+    /// Position of the waiting write of each account and slot
+    writes: HashMap<WriteKey, u64>,
+    dropped_writes: u64,
+    stale_accounts: HashSet<Vec<u8>>,
+}
+
+impl<T> Backlog<T> {
+    pub fn new(policy: SlowSubscriberPolicy, max_len: usize) -> Self {
+        Backlog {
+            policy,
+            max_len,
+            entries: VecDeque::new(),
+            len: 0,
+            popped: 0,
+            writes: HashMap::new(),
+            dropped_writes: 0,
+            stale_accounts: HashSet::new(),
+        }
+    }
+
+    /// Is the subscriber caught up?
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of waiting updates
+    pub fn waiting(&self) -> usize {
+        self.len
+    }
+
+    /// Queues an update, write_key is set for account writes. False if
+    /// more than max_len updates are waiting.
+    pub fn push(&mut self, update: T, write_key: Option<WriteKey>) -> bool {
+        match (self.policy, &write_key) {
+            (SlowSubscriberPolicy::SkipWrites, Some(key)) => {
+                self.dropped_writes += 1;
+                self.stale_accounts.insert(key.pubkey.clone());
+                match self.entries.back_mut() {
+                    Some(Entry::Skipped(count)) => *count += 1,
+                    _ => self.entries.push_back(Entry::Skipped(1)),
+                }
+                return true;
+            }
+            (SlowSubscriberPolicy::Conflate, Some(key)) => {
+                let position = self.popped + self.entries.len() as u64;
+                if let Some(previous) = self.writes.insert(key.clone(), position) {
+                    self.entries[(previous - self.popped) as usize] = Entry::Skipped(1);
+                    self.len -= 1;
+                    self.dropped_writes += 1;
+                }
+            }
+            _ => {}
+        }
+        self.entries.push_back(Entry::Waiting(update, write_key));
+        self.len += 1;
+        if self.entries.len() > 2 * self.len + 1 {
+            self.compact();
+        }
+        self.len <= self.max_len
+    }
+
+    /// Merges adjacent left out updates
+    fn compact(&mut self) {
+        let mut entries = VecDeque::with_capacity(self.entries.len());
+        for entry in self.entries.drain(..) {
+            match (entries.back_mut(), entry) {
+                (Some(Entry::Skipped(count)), Entry::Skipped(more)) => *count += more,
+                (_, entry) => entries.push_back(entry),
+            }
+        }
+        self.entries = entries;
+        self.popped = 0;
+        self.writes.clear();
+        for (position, entry) in self.entries.iter().enumerate() {
+            if let Entry::Waiting(_, Some(key)) = entry {
+                self.writes.insert(key.clone(), position as u64);
+            }
+        }
+    }
+
+    /// Number of left out updates before the next waiting update, and that update
+    pub fn pop(&mut self) -> Option<(u64, Option<T>)> {
+        let mut skipped = 0;
+        while let Some(entry) = self.entries.pop_front() {
+            let position = self.popped;
+            self.popped += 1;
+            match entry {
+                Entry::Skipped(count) => skipped += count,
+                Entry::Waiting(update, key) => {
+                    self.len -= 1;
+                    if let Some(key) = key {
+                        if self.writes.get(&key) == Some(&position) {
+                            self.writes.remove(&key);
+                        }
+                    }
+                    return Some((skipped, Some(update)));
+                }
+            }
+        }
+        if skipped > 0 {
+            Some((skipped, None))
+        } else {
+            None
+        }
+    }
+
+    /// What happened since the last report, if writes were left out
+    pub fn take_report(&mut self) -> Option<SlowSubscriber> {
+        if self.dropped_writes == 0 {
+            return None;
+        }
+        Some(SlowSubscriber {
+            policy: self.policy.to_proto() as i32,
+            dropped_writes: std::mem::take(&mut self.dropped_writes),
+            stale_accounts: self.stale_accounts.drain().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn key(pubkey: u8, slot: u64) -> Option<WriteKey> {
+        Some(WriteKey {
+            pubkey: vec![pubkey],
+            slot,
+        })
+    }
+
+    fn pop_all(backlog: &mut Backlog<u64>) -> Vec<(u64, Option<u64>)> {
+        std::iter::from_fn(|| backlog.pop()).collect()
+    }
+
+    #[test]
+    fn test_skip_writes() {
+        let mut backlog = Backlog::new(SlowSubscriberPolicy::SkipWrites, 2);
+        assert!(backlog.push(1, None));
+        assert!(backlog.push(2, key(1, 1)));
+        assert!(backlog.push(3, key(2, 1)));
+        assert!(backlog.push(4, None));
+        assert!(backlog.push(5, key(1, 1)));
+        assert!(!backlog.push(6, None));
+
+        assert_eq!(
+            pop_all(&mut backlog),
+            vec![(0, Some(1)), (2, Some(4)), (1, Some(6))]
+        );
+        assert!(backlog.is_empty());
+        let mut report = backlog.take_report().unwrap();
+        report.stale_accounts.sort();
+        assert_eq!(
+            report,
+            SlowSubscriber {
+                policy: Policy::SkipWrites as i32,
+                dropped_writes: 3,
+                stale_accounts: vec![vec![1], vec![2]],
+            }
+        );
+        assert_eq!(backlog.take_report(), None);
+    }
+
+    #[test]
+    fn test_conflate() {
+        let mut backlog = Backlog::new(SlowSubscriberPolicy::Conflate, 3);
+        assert!(backlog.push(1, key(1, 1)));
+        assert!(backlog.push(2, None));
+        assert!(backlog.push(3, key(1, 1)));
+        assert!(backlog.push(4, key(2, 1)));
+        assert_eq!(backlog.pop(), Some((1, Some(2))));
+
+        // compacts the left out writes
+        for i in 5..20 {
+            assert!(backlog.push(i, key(1, 1)));
+        }
+        assert!(backlog.entries.len() <= 2 * backlog.len + 1);
+        assert!(backlog.push(20, key(3, 1)));
+        assert!(!backlog.push(21, None));
+
+        assert_eq!(
+            pop_all(&mut backlog),
+            vec![(1, Some(4)), (14, Some(19)), (0, Some(20)), (0, Some(21))]
+        );
+        // not waiting anymore
+        assert!(backlog.push(22, key(1, 1)));
+        assert_eq!(pop_all(&mut backlog), vec![(0, Some(22))]);
+
+        // writes to the same account in another slot are kept
+        assert!(backlog.push(23, key(1, 1)));
+        assert!(backlog.push(24, key(1, 2)));
+        assert_eq!(pop_all(&mut backlog), vec![(0, Some(23)), (0, Some(24))]);
+        assert_eq!(
+            backlog.take_report(),
+            Some(SlowSubscriber {
+                policy: Policy::Conflate as i32,
+                dropped_writes: 16,
+                stale_accounts: vec![],
+            })
+        );
+    }
+}
@@ -0,0 +1,345 @@
+use {
+    crate::auth::AccessPermissions,
+    crate::geyser_plugin_grpc::geyser_proto::{
+        account_filter::FilterOneof, update::UpdateOneof, AccountWrite, SubscribeRequest,
+        Transaction, Update,
+    },
+    std::{borrow::Cow, collections::HashSet},
+};
+
+#[derive(Debug)]
+enum DataFilter {
+    DataSize(u64),
+    Memcmp { offset: usize, bytes: Vec<u8> },
+}
+
+impl DataFilter {
+    fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            DataFilter::DataSize(size) => data.len() as u64 == *size,
+            DataFilter::Memcmp { offset, bytes } => data
+                .get(*offset..)
+                .map(|tail| tail.starts_with(bytes))
+                .unwrap_or(false),
+        }
+    }
+}
+
+enum WriteSelection {
+    Selected,
+    /// Forwarded because the account was forwarded before
+    NotSelected {
+        clear_data: bool,
+    },
+    Skipped,
+}
+
+fn mark_not_selected(write: &mut AccountWrite, clear_data: bool) {
+    write.is_selected = false;
+    if clear_data {
+        write.data.clear();
+    }
+}
+
+/// Decides which account writes are forwarded to a single subscriber
+#[derive(Debug)]
+pub(crate) struct SubscriptionFilter {
+    accounts: HashSet<Vec<u8>>,
+    owners: HashSet<Vec<u8>>,
+    filters: Vec<DataFilter>,
+    transactions: bool,
+    block_metadata: bool,
+
+    /// Restrictions from the client's access token, if any
+    permissions: Option<AccessPermissions>,
+
+    /// Accounts that were forwarded to this subscriber before
+    ///
+    /// Writes to these are always forwarded, to catch account closure
+    /// and reuse, like the plugin-wide AccountsSelector does.
+    active_accounts: HashSet<Vec<u8>>,
+}
+
+impl SubscriptionFilter {
+    pub fn new(
+        request: &SubscribeRequest,
+        permissions: Option<AccessPermissions>,
+    ) -> Result<Self, String> {
+        if let Some(key) = request
+            .accounts
+            .iter()
+            .chain(request.owners.iter())
+            .find(|key| key.len() != 32)
+        {
+            return Err(format!("bad pubkey length: {}", key.len()));
+        }
+
+        let filters = request
+            .filters
+            .iter()
+            .map(|filter| match &filter.filter_oneof {
+                Some(FilterOneof::DataSize(size)) => Ok(DataFilter::DataSize(*size)),
+                Some(FilterOneof::Memcmp(memcmp)) => Ok(DataFilter::Memcmp {
+                    offset: memcmp.offset as usize,
+                    bytes: memcmp.bytes.clone(),
+                }),
+                None => Err("empty account filter".to_string()),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(SubscriptionFilter {
+            accounts: request.accounts.iter().cloned().collect(),
+            owners: request.owners.iter().cloned().collect(),
+            filters,
+            transactions: request.transactions,
+            block_metadata: request.block_metadata,
+            permissions,
+            active_accounts: HashSet::new(),
+        })
+    }
+
+    fn is_write_selected(&self, write: &AccountWrite) -> bool {
+        let key_selected = (self.accounts.is_empty() && self.owners.is_empty())
+            || self.accounts.contains(&write.pubkey)
+            || self.owners.contains(&write.owner);
+        key_selected && self.filters.iter().all(|f| f.matches(&write.data))
+    }
+
+    fn is_write_permitted(&self, write: &AccountWrite) -> bool {
+        self.permissions
+            .as_ref()
+            .map(|p| p.is_write_permitted(&write.pubkey, &write.owner))
+            .unwrap_or(true)
+    }
+
+    fn is_transaction_permitted(&self, transaction: &Transaction) -> bool {
+        self.permissions
+            .as_ref()
+            .map(|p| {
+                transaction
+                    .account_keys
+                    .iter()
+                    .any(|key| p.is_key_permitted(key))
+            })
+            .unwrap_or(true)
+    }
+
+    fn is_transaction_selected(&self, transaction: &Transaction) -> bool {
+        self.transactions
+            && self.is_transaction_permitted(transaction)
+            && ((self.accounts.is_empty() && self.owners.is_empty())
+                || transaction.account_keys.iter().any(|key| {
+                    self.accounts.contains(key)
+                        || self.owners.contains(key)
+                        || self.active_accounts.contains(key)
+                }))
+    }
+
+    fn classify_write(&mut self, write: &AccountWrite) -> WriteSelection {
+        let permitted = self.is_write_permitted(write);
+        if permitted && self.is_write_selected(write) {
+            if !self.active_accounts.contains(&write.pubkey) {
+                self.active_accounts.insert(write.pubkey.clone());
+            }
+            WriteSelection::Selected
+        } else if self.active_accounts.contains(&write.pubkey) {
+            // the account changed owner, the client may see that but not the data
+            WriteSelection::NotSelected {
+                clear_data: !permitted,
+            }
+        } else {
+            WriteSelection::Skipped
+        }
+    }
+
+    /// Returns true if the write should be sent to the subscriber
+    pub fn select(&mut self, write: &mut AccountWrite) -> bool {
+        match self.classify_write(write) {
+            WriteSelection::Selected => true,
+            WriteSelection::NotSelected { clear_data } => {
+                mark_not_selected(write, clear_data);
+                true
+            }
+            WriteSelection::Skipped => false,
+        }
+    }
+
+    /// Returns the update if it should be sent to the subscriber, a changed
+    /// copy if it needs to be changed for this subscriber
+    ///
+    /// Account writes and transactions are filtered, block metadata is only sent
+    /// if requested and all other updates pass.
+    pub fn apply<'a>(&mut self, update: &'a Update) -> Option<Cow<'a, Update>> {
+        let selected = match update.update_oneof.as_ref() {
+            Some(UpdateOneof::AccountWrite(write)) => match self.classify_write(write) {
+                WriteSelection::Selected => true,
+                WriteSelection::NotSelected { clear_data } => {
+                    let mut update = update.clone();
+                    if let Some(UpdateOneof::AccountWrite(write)) = update.update_oneof.as_mut() {
+                        mark_not_selected(write, clear_data);
+                    }
+                    return Some(Cow::Owned(update));
+                }
+                WriteSelection::Skipped => false,
+            },
+            Some(UpdateOneof::Transaction(transaction)) => {
+                self.is_transaction_selected(transaction)
+            }
+            Some(UpdateOneof::BlockMetadata(_)) => self.block_metadata,
+            _ => true,
+        };
+        if selected {
+            Some(Cow::Borrowed(update))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::*,
+        crate::auth::{AccessTokenConfig, Authenticator},
+        crate::geyser_plugin_grpc::geyser_proto::{AccountFilter, Memcmp},
+    };
+
+    fn write(pubkey: u8, owner: u8, data: &[u8]) -> Update {
+        Update {
+            update_oneof: Some(UpdateOneof::AccountWrite(AccountWrite {
+                pubkey: vec![pubkey; 32],
+                owner: vec![owner; 32],
+                data: data.to_vec(),
+                is_selected: true,
+                ..AccountWrite::default()
+            })),
+            ..Update::default()
+        }
+    }
+
+    fn is_selected(update: Option<Cow<Update>>) -> Option<bool> {
+        match &update?.update_oneof {
+            Some(UpdateOneof::AccountWrite(write)) => Some(write.is_selected),
+            _ => panic!("not an account write"),
+        }
+    }
+
+    #[test]
+    fn test_subscription_filter() {
+        let mut filter = SubscriptionFilter::new(
+            &SubscribeRequest {
+                accounts: vec![vec![1; 32]],
+                owners: vec![vec![2; 32]],
+                filters: vec![
+                    AccountFilter {
+                        filter_oneof: Some(FilterOneof::DataSize(3)),
+                    },
+                    AccountFilter {
+                        filter_oneof: Some(FilterOneof::Memcmp(Memcmp {
+                            offset: 1,
+                            bytes: vec![5, 6],
+                        })),
+                    },
+                ],
+                ..SubscribeRequest::default()
+            },
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            is_selected(filter.apply(&write(1, 0, &[4, 5, 6]))),
+            Some(true)
+        );
+        assert_eq!(
+            is_selected(filter.apply(&write(3, 2, &[4, 5, 6]))),
+            Some(true)
+        );
+        assert_eq!(is_selected(filter.apply(&write(4, 0, &[4, 5, 6]))), None);
+        assert_eq!(is_selected(filter.apply(&write(5, 2, &[4, 5]))), None);
+        assert_eq!(is_selected(filter.apply(&write(5, 2, &[4, 5, 7]))), None);
+
+        // previously forwarded accounts keep being forwarded
+        assert_eq!(is_selected(filter.apply(&write(3, 0, &[]))), Some(false));
+    }
+
+    #[test]
+    fn test_subscription_filter_transactions() {
+        let transaction = |keys: &[u8]| Update {
+            update_oneof: Some(UpdateOneof::Transaction(Transaction {
+                account_keys: keys.iter().map(|key| vec![*key; 32]).collect(),
+                ..Transaction::default()
+            })),
+            ..Update::default()
+        };
+
+        let mut filter = SubscriptionFilter::new(
+            &SubscribeRequest {
+                owners: vec![vec![2; 32]],
+                transactions: true,
+                ..SubscribeRequest::default()
+            },
+            None,
+        )
+        .unwrap();
+        assert!(filter.apply(&transaction(&[1, 2])).is_some());
+        assert!(filter.apply(&transaction(&[1, 3])).is_none());
+        // accounts that were forwarded before select transactions too
+        assert!(filter.apply(&write(3, 2, &[])).is_some());
+        assert!(filter.apply(&transaction(&[1, 3])).is_some());
+
+        // transactions must be requested
+        let mut filter = SubscriptionFilter::new(&SubscribeRequest::default(), None).unwrap();
+        assert!(filter.apply(&transaction(&[1])).is_none());
+    }
+
+    #[test]
+    fn test_subscription_filter_permissions() {
+        let permissions = Authenticator::new(&[AccessTokenConfig {
+            token: "team".to_string(),
+            accounts: vec![],
+            owners: vec![bs58::encode([2; 32]).into_string()],
+        }])
+        .unwrap()
+        .authenticate({
+            let mut request = tonic::Request::new(());
+            request
+                .metadata_mut()
+                .insert("authorization", "Bearer team".parse().unwrap());
+            request
+        })
+        .unwrap()
+        .extensions()
+        .get::<AccessPermissions>()
+        .cloned();
+        let mut filter =
+            SubscriptionFilter::new(&SubscribeRequest::default(), permissions).unwrap();
+
+        assert_eq!(is_selected(filter.apply(&write(1, 2, &[1]))), Some(true));
+        assert_eq!(is_selected(filter.apply(&write(3, 4, &[1]))), None);
+
+        // an account that moves to another owner is sent without data
+        match &filter.apply(&write(1, 4, &[1])).unwrap().update_oneof {
+            Some(UpdateOneof::AccountWrite(write)) => {
+                assert!(!write.is_selected);
+                assert!(write.data.is_empty());
+            }
+            _ => panic!("not an account write"),
+        }
+    }
+
+    #[test]
+    fn test_subscription_filter_empty() {
+        let mut filter = SubscriptionFilter::new(&SubscribeRequest::default(), None).unwrap();
+        assert_eq!(is_selected(filter.apply(&write(1, 0, &[]))), Some(true));
+
+        assert!(SubscriptionFilter::new(
+            &SubscribeRequest {
+                owners: vec![vec![2; 31]],
+                ..SubscribeRequest::default()
+            },
+            None
+        )
+        .is_err());
+    }
+}
@@ -1,12 +1,18 @@
 use {
-    solana_sdk::account::AccountSharedData, solana_sdk::pubkey::Pubkey, std::collections::HashMap,
+    solana_sdk::account::AccountSharedData,
+    solana_sdk::pubkey::Pubkey,
+    std::collections::{HashMap, HashSet},
 };
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SlotStatus {
     Rooted,
     Confirmed,
+    /// The bank is complete, but not confirmed yet
+    Frozen,
     Processed,
+    /// The slot failed to replay, it and its descendants will never be rooted
+    Dead,
 }
 
 #[derive(Clone, Debug)]
@@ -48,6 +54,29 @@ impl ChainData {
     }
 
     pub fn update_slot(&mut self, new_slot: SlotData) {
+        // descendants of dead slots die with them
+        let dead_parent = new_slot
+            .parent
+            .and_then(|parent| self.slots.get(&parent))
+            .map(|parent| parent.status == SlotStatus::Dead)
+            .unwrap_or(false);
+        if new_slot.status == SlotStatus::Dead || dead_parent {
+            self.update_dead_slot(SlotData {
+                status: SlotStatus::Dead,
+                ..new_slot
+            });
+            return;
+        }
+        // dead slots stay dead
+        if self
+            .slots
+            .get(&new_slot.slot)
+            .map(|s| s.status == SlotStatus::Dead)
+            .unwrap_or(false)
+        {
+            return;
+        }
+
         let new_processed_head = new_slot.slot > self.newest_processed_slot;
         if new_processed_head {
             self.newest_processed_slot = new_slot.slot;
@@ -70,26 +99,15 @@ impl ChainData {
                 let v = o.into_mut();
                 parent_update = v.parent != new_slot.parent && new_slot.parent.is_some();
                 v.parent = v.parent.or(new_slot.parent);
-                v.status = new_slot.status;
+                // a late Frozen notification must not undo a confirmation
+                if new_slot.status != SlotStatus::Frozen || v.status == SlotStatus::Processed {
+                    v.status = new_slot.status;
+                }
             }
         };
 
         if new_processed_head || parent_update {
-            // update the "chain" field down to the first rooted slot
-            let mut slot = self.newest_processed_slot;
-            loop {
-                if let Some(data) = self.slots.get_mut(&slot) {
-                    data.chain = self.newest_processed_slot;
-                    if data.status == SlotStatus::Rooted {
-                        break;
-                    }
-                    if let Some(parent) = data.parent {
-                        slot = parent;
-                        continue;
-                    }
-                }
-                break;
-            }
+            self.update_chain();
         }
 
         if new_rooted_head {
@@ -128,7 +146,84 @@ impl ChainData {
         }
     }
 
+    /// Updates the "chain" field down to the first rooted slot
+    fn update_chain(&mut self) {
+        let mut slot = self.newest_processed_slot;
+        loop {
+            if let Some(data) = self.slots.get_mut(&slot) {
+                data.chain = self.newest_processed_slot;
+                if data.status == SlotStatus::Rooted {
+                    break;
+                }
+                if let Some(parent) = data.parent {
+                    slot = parent;
+                    continue;
+                }
+            }
+            break;
+        }
+    }
+
+    /// Marks the slot and its known descendants as dead and drops their account writes
+    fn update_dead_slot(&mut self, new_slot: SlotData) {
+        if new_slot.slot <= self.newest_rooted_slot {
+            return;
+        }
+
+        let mut dead_slots = HashSet::new();
+        dead_slots.insert(new_slot.slot);
+        loop {
+            let children: Vec<u64> = self
+                .slots
+                .values()
+                .filter(|s| {
+                    !dead_slots.contains(&s.slot)
+                        && s.parent.map(|p| dead_slots.contains(&p)).unwrap_or(false)
+                })
+                .map(|s| s.slot)
+                .collect();
+            if children.is_empty() {
+                break;
+            }
+            dead_slots.extend(children);
+        }
+
+        self.slots.entry(new_slot.slot).or_insert(new_slot);
+        for slot in dead_slots.iter() {
+            if let Some(data) = self.slots.get_mut(slot) {
+                data.status = SlotStatus::Dead;
+            }
+        }
+
+        for writes in self.accounts.values_mut() {
+            writes.retain(|w| !dead_slots.contains(&w.slot));
+        }
+        self.accounts.retain(|_, writes| !writes.is_empty());
+
+        // the head may have died, pick the newest live slot instead
+        if dead_slots.contains(&self.newest_processed_slot) {
+            self.newest_processed_slot = self
+                .slots
+                .values()
+                .filter(|s| s.status != SlotStatus::Dead)
+                .map(|s| s.slot)
+                .max()
+                .unwrap_or(self.newest_rooted_slot);
+            self.update_chain();
+        }
+    }
+
     pub fn update_account(&mut self, pubkey: Pubkey, account: AccountData) {
+        // writes may arrive after the slot was reported dead
+        if self
+            .slots
+            .get(&account.slot)
+            .map(|s| s.status == SlotStatus::Dead)
+            .unwrap_or(false)
+        {
+            return;
+        }
+
         use std::collections::hash_map::Entry;
         match self.accounts.entry(pubkey) {
             Entry::Vacant(v) => {
@@ -159,7 +254,10 @@ impl ChainData {
         self.slots
             .get(&write.slot)
             // either the slot is rooted or in the current chain
-            .map(|s| s.status == SlotStatus::Rooted || s.chain == self.newest_processed_slot)
+            .map(|s| {
+                s.status == SlotStatus::Rooted
+                    || (s.chain == self.newest_processed_slot && s.status != SlotStatus::Dead)
+            })
             // if the slot can't be found but preceeds newest rooted, use it too (old rooted slots are removed)
             .unwrap_or(
                 write.slot <= self.newest_rooted_slot || write.slot > self.newest_processed_slot,
@@ -191,3 +289,100 @@ impl ChainData {
             .ok_or(anyhow::anyhow!("account {} has no live data", pubkey))
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::*,
+        solana_sdk::account::{ReadableAccount, WritableAccount},
+    };
+
+    fn slot(slot: u64, parent: Option<u64>, status: SlotStatus) -> SlotData {
+        SlotData {
+            slot,
+            parent,
+            status,
+            chain: 0,
+        }
+    }
+
+    fn write(slot: u64, lamports: u64) -> AccountData {
+        AccountData {
+            slot,
+            write_version: 1,
+            account: AccountSharedData::create(lamports, vec![], Pubkey::default(), false, 0),
+        }
+    }
+
+    fn lamports(chain_data: &ChainData, pubkey: &Pubkey) -> Option<u64> {
+        chain_data
+            .account(pubkey)
+            .ok()
+            .map(|data| data.account.lamports())
+    }
+
+    #[test]
+    fn test_dead_slot() {
+        let a = Pubkey::new_unique();
+        let b = Pubkey::new_unique();
+        let mut chain_data = ChainData::new();
+        chain_data.update_slot(slot(10, None, SlotStatus::Rooted));
+        chain_data.update_slot(slot(11, Some(10), SlotStatus::Processed));
+        chain_data.update_slot(slot(12, Some(10), SlotStatus::Processed));
+        chain_data.update_slot(slot(13, Some(11), SlotStatus::Frozen));
+        chain_data.update_account(a, write(10, 1));
+        chain_data.update_account(a, write(12, 2));
+        chain_data.update_account(a, write(13, 3));
+        chain_data.update_account(b, write(11, 5));
+        assert_eq!(lamports(&chain_data, &a), Some(3));
+        assert_eq!(lamports(&chain_data, &b), Some(5));
+
+        // the dead slot's descendants die with it, their writes are dropped and
+        // the other fork becomes the head
+        chain_data.update_slot(slot(11, None, SlotStatus::Dead));
+        assert_eq!(chain_data.slots[&13].status, SlotStatus::Dead);
+        assert_eq!(chain_data.newest_processed_slot, 12);
+        assert_eq!(lamports(&chain_data, &a), Some(2));
+        assert_eq!(lamports(&chain_data, &b), None);
+        assert_eq!(chain_data.accounts[&a].len(), 2);
+        assert!(!chain_data.accounts.contains_key(&b));
+
+        // late writes and slot updates for dead slots are ignored
+        chain_data.update_account(a, write(13, 4));
+        chain_data.update_slot(slot(13, Some(11), SlotStatus::Confirmed));
+        assert_eq!(chain_data.slots[&13].status, SlotStatus::Dead);
+        assert_eq!(lamports(&chain_data, &a), Some(2));
+
+        // so are children of the dead slot that arrive later
+        chain_data.update_slot(slot(14, Some(13), SlotStatus::Processed));
+        chain_data.update_account(a, write(14, 4));
+        assert_eq!(chain_data.slots[&14].status, SlotStatus::Dead);
+        assert_eq!(chain_data.newest_processed_slot, 12);
+        assert_eq!(lamports(&chain_data, &a), Some(2));
+
+        // rooted slots can't die
+        chain_data.update_slot(slot(10, None, SlotStatus::Dead));
+        assert_eq!(chain_data.slots[&10].status, SlotStatus::Rooted);
+        assert_eq!(lamports(&chain_data, &a), Some(2));
+
+        // the other fork goes on
+        chain_data.update_slot(slot(15, Some(12), SlotStatus::Processed));
+        chain_data.update_account(a, write(15, 6));
+        assert_eq!(lamports(&chain_data, &a), Some(6));
+    }
+
+    #[test]
+    fn test_frozen_slot() {
+        let mut chain_data = ChainData::new();
+        chain_data.update_slot(slot(10, None, SlotStatus::Rooted));
+        chain_data.update_slot(slot(11, Some(10), SlotStatus::Processed));
+        chain_data.update_slot(slot(11, None, SlotStatus::Frozen));
+        assert_eq!(chain_data.slots[&11].status, SlotStatus::Frozen);
+
+        // a late Frozen notification doesn't undo the confirmation
+        chain_data.update_slot(slot(12, Some(11), SlotStatus::Processed));
+        chain_data.update_slot(slot(12, None, SlotStatus::Confirmed));
+        chain_data.update_slot(slot(12, None, SlotStatus::Frozen));
+        assert_eq!(chain_data.slots[&12].status, SlotStatus::Confirmed);
+    }
+}
@@ -1,73 +1,634 @@
 use jsonrpc_core::futures::StreamExt;
-use jsonrpc_core_client::transports::http;
 
-use solana_account_decoder::UiAccountEncoding;
-use solana_client::rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig};
-use solana_client::rpc_response::{Response, RpcKeyedAccount};
-use solana_rpc::{rpc::rpc_accounts::AccountsDataClient, rpc::OptionalContext};
-use solana_sdk::{account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey};
+use solana_sdk::{
+    account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Signature,
+};
 
-use futures::{future, future::FutureExt};
+use futures::{
+    future,
+    future::{FusedFuture, FutureExt},
+};
 use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
 
 use log::*;
-use std::{collections::HashMap, str::FromStr, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 pub mod geyser_proto {
     tonic::include_proto!("accountsdb");
 }
-use geyser_proto::accounts_db_client::AccountsDbClient;
+use geyser_proto::{
+    accounts_db_client::AccountsDbClient, slow_subscriber::Policy as SlowSubscriberPolicy,
+    Capability,
+};
+
+/// SubscribeRequest::protocol_version of this build
+const PROTOCOL_VERSION: u32 = 3;
 
 use crate::{
-    metrics, AccountWrite, AnyhowWrap, GrpcSourceConfig, SlotStatus, SlotUpdate,
-    SnapshotSourceConfig, SourceConfig, TlsConfig,
+    metrics, recording,
+    snapshot::{self, AccountSelection, SnapshotData},
+    snapshot_archive::MAX_ACCOUNT_DATA_LEN,
+    AccountFilterConfig, AccountWrite, AnyhowWrap, BlockMetadata, Compression, GrpcSourceConfig,
+    Reward, RewardType, SlotStatus, SlotUpdate, SnapshotSourceConfig, Source, SourceConfig,
+    SubscriptionFilterConfig, TlsConfig, TransactionUpdate,
 };
 
-type SnapshotData = Response<Vec<RpcKeyedAccount>>;
-
-enum Message {
-    GrpcUpdate(geyser_proto::Update),
+pub(crate) enum Message {
+    GrpcUpdate {
+        /// Index of the source in SourceConfig::grpc_sources
+        source: usize,
+        update: geyser_proto::Update,
+        receive_timestamp: SystemTime,
+    },
     Snapshot(SnapshotData),
 }
 
-async fn get_snapshot(
-    rpc_http_url: String,
-    program_id: Pubkey,
-) -> anyhow::Result<OptionalContext<Vec<RpcKeyedAccount>>> {
-    let rpc_client = http::connect_with_options::<AccountsDataClient>(&rpc_http_url, true)
-        .await
-        .map_err_anyhow()?;
+/// Converts a send_timestamp_us from the plugin, where 0 means unknown
+fn timestamp_from_us(us: u64) -> Option<SystemTime> {
+    if us == 0 {
+        None
+    } else {
+        Some(UNIX_EPOCH + Duration::from_micros(us))
+    }
+}
+
+pub(crate) fn account_from_write(write: geyser_proto::AccountWrite) -> (Pubkey, Account) {
+    (
+        Pubkey::new(&write.pubkey),
+        Account {
+            lamports: write.lamports,
+            data: write.data,
+            owner: Pubkey::new(&write.owner),
+            executable: write.executable,
+            rent_epoch: write.rent_epoch,
+        },
+    )
+}
 
-    let account_info_config = RpcAccountInfoConfig {
-        encoding: Some(UiAccountEncoding::Base64),
-        commitment: Some(CommitmentConfig::finalized()),
-        data_slice: None,
+fn make_subscribe_request(
+    filter: Option<&SubscriptionFilterConfig>,
+) -> anyhow::Result<geyser_proto::SubscribeRequest> {
+    use geyser_proto::{account_filter::FilterOneof, AccountFilter, Memcmp};
+
+    let filter = match filter {
+        Some(filter) => filter,
+        None => return Ok(geyser_proto::SubscribeRequest::default()),
     };
-    let program_accounts_config = RpcProgramAccountsConfig {
-        filters: None,
-        with_context: Some(true),
-        account_config: account_info_config.clone(),
+    let decode_pubkeys = |keys: &[String]| -> anyhow::Result<Vec<Vec<u8>>> {
+        keys.iter()
+            .map(|key| Ok(Pubkey::from_str(key)?.to_bytes().to_vec()))
+            .collect()
     };
+    let filters = filter
+        .filters
+        .iter()
+        .map(|filter| {
+            let filter_oneof = match filter {
+                AccountFilterConfig::DataSize(size) => FilterOneof::DataSize(*size),
+                AccountFilterConfig::Memcmp(memcmp) => FilterOneof::Memcmp(Memcmp {
+                    offset: memcmp.offset,
+                    bytes: bs58::decode(&memcmp.bytes).into_vec().map_err_anyhow()?,
+                }),
+            };
+            Ok(AccountFilter {
+                filter_oneof: Some(filter_oneof),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(geyser_proto::SubscribeRequest {
+        accounts: decode_pubkeys(&filter.accounts)?,
+        owners: decode_pubkeys(&filter.owners)?,
+        filters,
+        ..geyser_proto::SubscribeRequest::default()
+    })
+}
 
-    info!("requesting snapshot");
-    let account_snapshot = rpc_client
-        .get_program_accounts(
-            program_id.to_string(),
-            Some(program_accounts_config.clone()),
-        )
+/// Account writes whose write_version can't be mapped reliably
+#[derive(Clone, Copy, Debug)]
+enum WriteAnomaly {
+    /// For a slot so far behind the newest rooted slot that its write version
+    /// mapping was already dropped
+    StaleSlot,
+    /// With a lower write_version than an earlier write to the same account in
+    /// the same slot
+    WriteVersionRegression,
+}
+
+/// Per-source counters for problems with the stream
+pub(crate) struct StreamMetrics {
+    /// grpc_source_write_anomalies{kind}
+    stale_slot: metrics::MetricU64,
+    write_version_regression: metrics::MetricU64,
+    /// grpc_source_sequence_gaps
+    sequence_gaps: metrics::MetricU64,
+    /// grpc_source_slow_subscriber_reports
+    slow_subscriber_reports: metrics::MetricU64,
+    /// grpc_source_failed_resyncs
+    failed_resyncs: metrics::MetricU64,
+    /// grpc_source_snapshot_decode_errors, for rpc snapshots and resyncs
+    snapshot_decode_errors: metrics::MetricU64,
+}
+
+impl StreamMetrics {
+    pub(crate) fn new(metrics_sender: &metrics::Metrics, source: &str) -> Self {
+        let anomalies = metrics_sender.register_u64_family(
+            "grpc_source_write_anomalies".into(),
+            metrics::MetricType::Counter,
+        );
+        let sequence_gaps = metrics_sender.register_u64_family(
+            "grpc_source_sequence_gaps".into(),
+            metrics::MetricType::Counter,
+        );
+        let slow_subscriber_reports = metrics_sender.register_u64_family(
+            "grpc_source_slow_subscriber_reports".into(),
+            metrics::MetricType::Counter,
+        );
+        let failed_resyncs = metrics_sender.register_u64_family(
+            "grpc_source_failed_resyncs".into(),
+            metrics::MetricType::Counter,
+        );
+        let snapshot_decode_errors = metrics_sender.register_u64_family(
+            "grpc_source_snapshot_decode_errors".into(),
+            metrics::MetricType::Counter,
+        );
+        StreamMetrics {
+            stale_slot: anomalies.with_labels(&[("source", source), ("kind", "stale_slot")]),
+            write_version_regression: anomalies
+                .with_labels(&[("source", source), ("kind", "write_version_regression")]),
+            sequence_gaps: sequence_gaps.with_labels(&[("source", source)]),
+            slow_subscriber_reports: slow_subscriber_reports.with_labels(&[("source", source)]),
+            failed_resyncs: failed_resyncs.with_labels(&[("source", source)]),
+            snapshot_decode_errors: snapshot_decode_errors.with_labels(&[("source", source)]),
+        }
+    }
+
+    fn count_anomaly(&mut self, anomaly: WriteAnomaly) {
+        match anomaly {
+            WriteAnomaly::StaleSlot => self.stale_slot.increment(),
+            WriteAnomaly::WriteVersionRegression => self.write_version_regression.increment(),
+        }
+    }
+}
+
+/// Updates after the newest rooted slot may be missing, resume from there
+fn resume_after_lost_updates(resume_slot: &mut Option<u64>, max_rooted_slot: u64) {
+    *resume_slot = match *resume_slot {
+        Some(slot) if max_rooted_slot > 0 => Some(slot.min(max_rooted_slot + 1)),
+        _ => None,
+    };
+}
+
+/// Fetches accounts from rpc and sends them like a snapshot
+async fn resync_accounts(
+    rpc_http_urls: Vec<String>,
+    pubkeys: Vec<Pubkey>,
+    sender: async_channel::Sender<Message>,
+    mut recorder: Option<recording::Recorder>,
+    metric_decode_errors: metrics::MetricU64,
+) -> anyhow::Result<()> {
+    info!(
+        "fetching {} accounts with dropped writes again",
+        pubkeys.len()
+    );
+    let selection = AccountSelection::from_pubkeys(pubkeys);
+    let snapshot = snapshot::get_snapshot_from_rpc(
+        &rpc_http_urls,
+        &selection,
+        CommitmentConfig::finalized(),
+        metric_decode_errors,
+    )
+    .await?;
+    if let Some(recorder) = recorder.as_mut() {
+        recorder.record_snapshot(&snapshot).await;
+    }
+    sender
+        .send(Message::Snapshot(snapshot))
         .await
-        .map_err_anyhow()?;
-    info!("snapshot received");
-    Ok(account_snapshot)
+        .expect("send success");
+    Ok(())
+}
+
+/// The data of a write sent with delta_account_data, from the previous data of its account
+fn apply_data_delta(
+    previous: Option<&Vec<u8>>,
+    delta: &geyser_proto::AccountDataDelta,
+) -> anyhow::Result<Vec<u8>> {
+    if delta.data_len > MAX_ACCOUNT_DATA_LEN {
+        anyhow::bail!("delta data_len {} is too large", delta.data_len);
+    }
+    let mut data = previous
+        .ok_or_else(|| anyhow::anyhow!("delta for an account without previous data"))?
+        .clone();
+    data.resize(delta.data_len as usize, 0);
+    for range in delta.ranges.iter() {
+        let start = range.offset as usize;
+        let target = start
+            .checked_add(range.data.len())
+            .and_then(|end| data.get_mut(start..end))
+            .ok_or_else(|| anyhow::anyhow!("delta range beyond data_len"))?;
+        target.copy_from_slice(&range.data);
+    }
+    Ok(data)
+}
+
+/// Logs how the plugin's protocol version and capabilities differ from what was
+/// requested, fails if the stream would be unusable
+fn check_protocol(
+    response: &geyser_proto::SubscribeResponse,
+    request: &geyser_proto::SubscribeRequest,
+    compression: Option<Compression>,
+) -> anyhow::Result<()> {
+    if response.protocol_version == 0 {
+        // such a plugin ignores the required capabilities
+        if !request.capabilities.is_empty() {
+            anyhow::bail!(
+                "plugin predates protocol versioning, can't check required capabilities {:?}",
+                request
+                    .capabilities
+                    .iter()
+                    .filter_map(|c| Capability::from_i32(*c))
+                    .collect::<Vec<_>>()
+            );
+        }
+        warn!("plugin predates protocol versioning, can't check its capabilities");
+        return Ok(());
+    }
+    if response.protocol_version != PROTOCOL_VERSION {
+        info!(
+            "plugin speaks protocol version {}, connector {}",
+            response.protocol_version, PROTOCOL_VERSION
+        );
+    }
+    let supports = |capability: Capability| response.capabilities.contains(&(capability as i32));
+    let missing: Vec<_> = request
+        .capabilities
+        .iter()
+        .filter(|c| !response.capabilities.contains(c))
+        .filter_map(|c| Capability::from_i32(*c))
+        .collect();
+    if !missing.is_empty() {
+        anyhow::bail!("plugin lacks required capabilities {:?}", missing);
+    }
+    if request.snapshot && !supports(Capability::Snapshot) {
+        warn!("plugin does not serve snapshots, using rpc instead");
+    }
+    if request.from_slot.is_some() && !supports(Capability::Replay) {
+        warn!("plugin has no replay buffer, a new snapshot is needed");
+    }
+    if request.transactions && !supports(Capability::Transactions) {
+        warn!("plugin does not send transactions, enable transaction_notifications");
+    }
+    if request.block_metadata && !supports(Capability::BlockMetadata) {
+        warn!("plugin does not send block metadata");
+    }
+    if compression == Some(Compression::Gzip) && !supports(Capability::Gzip) {
+        info!("plugin does not compress the stream");
+    }
+    if request.delta_account_data && !supports(Capability::DeltaAccountData) {
+        info!("plugin does not send account data deltas");
+    }
+    Ok(())
+}
+
+/// Optional kinds of updates to request from the plugin
+#[derive(Clone, Copy)]
+struct RequestedUpdates {
+    transactions: bool,
+    block_metadata: bool,
+}
+
+// Data for slots will arrive out of order. This value defines how many
+// slots after a slot was marked "rooted" we assume it'll not receive
+// any more account write information.
+//
+// This is important for the write_version mapping (to know when slots can
+// be dropped).
+const MAX_OUT_OF_ORDER_SLOTS: u64 = 40;
+
+struct WriteVersion {
+    // Write version seen on-chain
+    global: u64,
+    // The per-pubkey per-slot write version
+    slot: u32,
+}
+
+/// Turns the updates of one connection to the plugin into messages for
+/// process_messages
+///
+/// Replays of a recording feed the recorded updates through it again.
+pub(crate) struct StreamState {
+    /// SubscribeRequest::from_slot of the connection
+    from_slot: Option<u64>,
+    delta_account_data: bool,
+    strict_write_versions: bool,
+
+    // The first slot that we will receive _all_ account writes for
+    first_full_slot: u64,
+
+    // If a snapshot should be performed when ready.
+    snapshot_needed: bool,
+
+    // The highest "rooted" slot that has been seen.
+    max_rooted_slot: u64,
+
+    // Accounts from the plugin's snapshot messages, until the last one arrives
+    plugin_snapshot_accounts: Vec<(Pubkey, Account)>,
+
+    // Highest slot that an account write came in for.
+    newest_write_slot: u64,
+
+    // map slot -> (pubkey -> WriteVersion)
+    //
+    // Since the write_version is a private indentifier per node it can't be used
+    // to deduplicate events from multiple nodes. Here we rewrite it such that each
+    // pubkey and each slot has a consecutive numbering of writes starting at 1.
+    //
+    // That number will be consistent for each node.
+    slot_pubkey_writes: HashMap<u64, HashMap<[u8; 32], WriteVersion>>,
+
+    // Accounts that had writes dropped, to be fetched again from rpc with the
+    // next rooted slot. Without rpc, the source reconnects for a new snapshot.
+    pub resync_pubkeys: HashSet<Pubkey>,
+
+    // Update::seq of the latest numbered update
+    last_seq: Option<u64>,
+
+    // SubscribeResponse::catchup_seq, updates up to it were sent before
+    // this connection
+    catchup_seq: u64,
+
+    // Data of the latest write of each account, that deltas apply to
+    delta_base_data: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl StreamState {
+    pub(crate) fn new(
+        request: &geyser_proto::SubscribeRequest,
+        strict_write_versions: bool,
+    ) -> Self {
+        StreamState {
+            from_slot: request.from_slot,
+            delta_account_data: request.delta_account_data,
+            strict_write_versions,
+            first_full_slot: u64::MAX,
+            snapshot_needed: true,
+            max_rooted_slot: 0,
+            plugin_snapshot_accounts: vec![],
+            newest_write_slot: 0,
+            slot_pubkey_writes: HashMap::new(),
+            resync_pubkeys: HashSet::new(),
+            last_seq: None,
+            catchup_seq: 0,
+            delta_base_data: HashMap::new(),
+        }
+    }
+
+    /// Slot to resume from on reconnect: the oldest one that isn't rooted yet,
+    /// writes in later slots may still be missing
+    fn resume_from_slot(&self) -> u64 {
+        (self.max_rooted_slot + 1).max(self.first_full_slot)
+    }
+
+    /// Checks and rewrites an update from the plugin, returns the message to
+    /// pass on. An error ends the connection.
+    pub(crate) fn process(
+        &mut self,
+        mut update: geyser_proto::Update,
+        receive_timestamp: SystemTime,
+        source_index: usize,
+        resume_slot: &mut Option<u64>,
+        stream_metrics: &mut StreamMetrics,
+    ) -> anyhow::Result<Option<Message>> {
+        use geyser_proto::{slot_update::Status, update::UpdateOneof};
+        if update.seq != 0 {
+            if let Some(last_seq) = self.last_seq {
+                if update.seq != last_seq + update.skipped + 1 {
+                    // Updates were lost. Everything before the newest rooted slot
+                    // is complete, ask the plugin to replay the rest.
+                    stream_metrics.sequence_gaps.increment();
+                    resume_after_lost_updates(resume_slot, self.max_rooted_slot);
+                    anyhow::bail!(
+                        "sequence gap: got update {} after {}, skipped {}",
+                        update.seq,
+                        last_seq,
+                        update.skipped
+                    );
+                }
+            }
+            self.last_seq = Some(update.seq);
+        }
+        let catchup = update.seq != 0 && update.seq <= self.catchup_seq;
+        match update.update_oneof.as_mut().expect("invalid grpc") {
+            UpdateOneof::SubscribeResponse(subscribe_response) => {
+                self.catchup_seq = subscribe_response.catchup_seq;
+                if subscribe_response.replaying {
+                    let from_slot = self.from_slot.ok_or_else(|| {
+                        anyhow::anyhow!("plugin is replaying, but no replay was requested")
+                    })?;
+                    info!(
+                        "plugin is replaying from slot {}, no snapshot needed",
+                        from_slot
+                    );
+                    self.first_full_slot = from_slot;
+                    self.snapshot_needed = false;
+                } else {
+                    *resume_slot = None;
+                    self.first_full_slot = subscribe_response.highest_write_slot + 1;
+                    if subscribe_response.snapshot {
+                        info!("plugin is sending a snapshot");
+                        self.snapshot_needed = false;
+                    }
+                }
+            }
+            UpdateOneof::AccountsSnapshot(chunk) => {
+                // All later writes are for slots after the snapshot slot
+                self.first_full_slot = chunk.slot + 1;
+                if self.delta_account_data {
+                    for write in chunk.accounts.iter() {
+                        self.delta_base_data
+                            .insert(write.pubkey.clone(), write.data.clone());
+                    }
+                }
+                self.plugin_snapshot_accounts
+                    .extend(chunk.accounts.drain(..).map(account_from_write));
+                if !chunk.is_last {
+                    return Ok(None);
+                }
+                info!(
+                    "plugin snapshot is for slot {} with {} accounts",
+                    chunk.slot,
+                    self.plugin_snapshot_accounts.len()
+                );
+                *resume_slot = Some(self.resume_from_slot());
+                return Ok(Some(Message::Snapshot(SnapshotData {
+                    slot: chunk.slot,
+                    accounts: std::mem::take(&mut self.plugin_snapshot_accounts),
+                })));
+            }
+            UpdateOneof::SlotUpdate(slot_update) => {
+                if catchup {
+                    // its send time is long gone, don't count it as latency
+                    slot_update.send_timestamp_us = 0;
+                }
+                if slot_update.status == Status::Rooted as i32
+                    && slot_update.slot > self.max_rooted_slot
+                {
+                    self.max_rooted_slot = slot_update.slot;
+                    if resume_slot.is_some() {
+                        *resume_slot = Some(self.resume_from_slot());
+                    }
+
+                    // drop data for slots that are well beyond rooted
+                    let oldest_slot = self.max_rooted_slot.saturating_sub(MAX_OUT_OF_ORDER_SLOTS);
+                    self.slot_pubkey_writes.retain(|&k, _| k >= oldest_slot);
+                }
+            }
+            UpdateOneof::AccountWrite(write) => {
+                if catchup {
+                    write.send_timestamp_us = 0;
+                }
+                if let Some(delta) = write.data_delta.take() {
+                    write.data = apply_data_delta(self.delta_base_data.get(&write.pubkey), &delta)?;
+                }
+                if self.delta_account_data {
+                    self.delta_base_data
+                        .insert(write.pubkey.clone(), write.data.clone());
+                }
+                if write.slot < self.first_full_slot {
+                    // Don't try to process data for slots where we may have missed writes:
+                    // We could not map the write_version correctly for them.
+                    return Ok(None);
+                }
+
+                let pubkey = Pubkey::new(&write.pubkey);
+                let mut anomaly = None;
+                if write.slot > self.newest_write_slot {
+                    self.newest_write_slot = write.slot;
+                } else if self.max_rooted_slot > 0
+                    && write.slot < self.max_rooted_slot.saturating_sub(MAX_OUT_OF_ORDER_SLOTS)
+                {
+                    // Its slot's write versions are gone. Drop the write, later
+                    // writes supersede it, and fetch the account again in case
+                    // there are none.
+                    if self.strict_write_versions {
+                        anyhow::bail!(
+                            "received write {} slots back from max rooted slot {}",
+                            self.max_rooted_slot - write.slot,
+                            self.max_rooted_slot
+                        );
+                    }
+                    self.resync_pubkeys.insert(pubkey);
+                    anomaly = Some(WriteAnomaly::StaleSlot);
+                }
+
+                if anomaly.is_none() {
+                    let pubkey_writes = self.slot_pubkey_writes.entry(write.slot).or_default();
+                    let write_version_mapping =
+                        pubkey_writes
+                            .entry(pubkey.to_bytes())
+                            .or_insert(WriteVersion {
+                                global: write.write_version,
+                                slot: 1, // write version 0 is reserved for snapshots
+                            });
+
+                    // We assume we will receive write versions for each pubkey in sequence.
+                    // If this is not the case, a later write arrived first and was already
+                    // forwarded: this one is outdated and can be dropped.
+                    if write.write_version < write_version_mapping.global {
+                        if self.strict_write_versions {
+                            anyhow::bail!(
+                                "unexpected write version: got {}, expected >= {}",
+                                write.write_version,
+                                write_version_mapping.global
+                            );
+                        }
+                        anomaly = Some(WriteAnomaly::WriteVersionRegression);
+                    } else {
+                        write_version_mapping.global = write.write_version;
+
+                        // Rewrite the update to use the local write version and bump it
+                        write.write_version = write_version_mapping.slot as u64;
+                        write_version_mapping.slot += 1;
+                    }
+                }
+
+                if let Some(anomaly) = anomaly {
+                    debug!(
+                        "dropping write to {} in slot {}: {:?}",
+                        pubkey, write.slot, anomaly
+                    );
+                    stream_metrics.count_anomaly(anomaly);
+                    return Ok(None);
+                }
+            }
+            UpdateOneof::SlowSubscriber(report) => {
+                stream_metrics.slow_subscriber_reports.increment();
+                if report.policy == SlowSubscriberPolicy::Disconnect as i32 {
+                    // the plugin ends the stream next
+                    warn!("plugin is disconnecting, this source fell behind");
+                    resume_after_lost_updates(resume_slot, self.max_rooted_slot);
+                } else {
+                    warn!(
+                        "plugin left out {} account writes while this source was behind",
+                        report.dropped_writes
+                    );
+                    // Their data is outdated until the next write, fetch it
+                    // again like for stale slots.
+                    self.resync_pubkeys.extend(
+                        report
+                            .stale_accounts
+                            .iter()
+                            .map(|pubkey| Pubkey::new(pubkey)),
+                    );
+                }
+                return Ok(None);
+            }
+            UpdateOneof::Transaction(_) => {}
+            UpdateOneof::BlockMetadata(_) => {}
+            UpdateOneof::Ping(_) => {}
+        }
+        Ok(Some(Message::GrpcUpdate {
+            source: source_index,
+            update,
+            receive_timestamp,
+        }))
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn feed_data_geyser(
     grpc_config: &GrpcSourceConfig,
     tls_config: Option<ClientTlsConfig>,
     snapshot_config: &SnapshotSourceConfig,
     sender: async_channel::Sender<Message>,
+    resume_slot: &mut Option<u64>,
+    source_index: usize,
+    requested: RequestedUpdates,
+    stream_metrics: &mut StreamMetrics,
+    mut recorder: Option<recording::Recorder>,
 ) -> anyhow::Result<()> {
-    let program_id = Pubkey::from_str(&snapshot_config.program_id)?;
+    let selection = AccountSelection::from_config(snapshot_config)?;
+    let mut subscribe_request = make_subscribe_request(grpc_config.filter.as_ref())?;
+
+    // If all data up to resume_slot was sent on a previous connection, ask
+    // the plugin to replay from there, avoiding a new snapshot.
+    subscribe_request.from_slot = *resume_slot;
+    subscribe_request.snapshot = grpc_config.snapshot_from_plugin;
+    subscribe_request.transactions = requested.transactions;
+    subscribe_request.block_metadata = requested.block_metadata;
+    subscribe_request.protocol_version = PROTOCOL_VERSION;
+    subscribe_request.delta_account_data = grpc_config.delta_account_data;
+    if grpc_config.filter.is_some() {
+        // an unfiltered stream would reach the targets unnoticed
+        subscribe_request
+            .capabilities
+            .push(Capability::Filters as i32);
+    }
+    let request_fields = subscribe_request.clone();
 
     let endpoint = Endpoint::from_str(&grpc_config.connection_string)?;
     let channel = if let Some(tls) = tls_config {
@@ -78,11 +639,21 @@ async fn feed_data_geyser(
     .connect()
     .await?;
     let mut client = AccountsDbClient::new(channel);
+    if grpc_config.compression == Some(Compression::Gzip) {
+        client = client.accept_gzip();
+    }
 
-    let mut update_stream = client
-        .subscribe(geyser_proto::SubscribeRequest {})
-        .await?
-        .into_inner();
+    let mut subscribe_request = tonic::Request::new(subscribe_request);
+    if let Some(access_token) = &grpc_config.access_token {
+        subscribe_request
+            .metadata_mut()
+            .insert("authorization", format!("Bearer {}", access_token).parse()?);
+    }
+
+    let mut update_stream = client.subscribe(subscribe_request).await?.into_inner();
+    if let Some(recorder) = recorder.as_mut() {
+        recorder.record_request(source_index, &request_fields).await;
+    }
 
     // We can't get a snapshot immediately since the finalized snapshot would be for a
     // slot in the past and we'd be missing intermediate updates.
@@ -92,23 +663,7 @@ async fn feed_data_geyser(
     // finalized slot, so add a number of slots as a buffer.
     //
     // If that buffer isn't sufficient, there'll be a retry.
-
-    // The first slot that we will receive _all_ account writes for
-    let mut first_full_slot: u64 = u64::MAX;
-
-    // If a snapshot should be performed when ready.
-    let mut snapshot_needed = true;
-
-    // The highest "rooted" slot that has been seen.
-    let mut max_rooted_slot = 0;
-
-    // Data for slots will arrive out of order. This value defines how many
-    // slots after a slot was marked "rooted" we assume it'll not receive
-    // any more account write information.
-    //
-    // This is important for the write_version mapping (to know when slots can
-    // be dropped).
-    let max_out_of_order_slots = 40;
+    let mut state = StreamState::new(&request_fields, grpc_config.strict_write_versions);
 
     // Number of slots that we expect "finalized" commitment to lag
     // behind "rooted". This matters for getProgramAccounts based snapshots,
@@ -117,109 +672,89 @@ async fn feed_data_geyser(
 
     let mut snapshot_future = future::Fuse::terminated();
 
+    // Fetching accounts with dropped writes again, one batch at a time
+    let mut resync_future = future::Fuse::terminated();
+
     // The plugin sends a ping every 5s or so
     let fatal_idle_timeout = Duration::from_secs(60);
 
-    // Highest slot that an account write came in for.
-    let mut newest_write_slot: u64 = 0;
-
-    struct WriteVersion {
-        // Write version seen on-chain
-        global: u64,
-        // The per-pubkey per-slot write version
-        slot: u32,
-    }
-
-    // map slot -> (pubkey -> WriteVersion)
-    //
-    // Since the write_version is a private indentifier per node it can't be used
-    // to deduplicate events from multiple nodes. Here we rewrite it such that each
-    // pubkey and each slot has a consecutive numbering of writes starting at 1.
-    //
-    // That number will be consistent for each node.
-    let mut slot_pubkey_writes = HashMap::<u64, HashMap<[u8; 32], WriteVersion>>::new();
-
     loop {
         tokio::select! {
             update = update_stream.next() => {
                 use geyser_proto::{update::UpdateOneof, slot_update::Status};
-                let mut update = update.ok_or(anyhow::anyhow!("geyser plugin has closed the stream"))??;
-                match update.update_oneof.as_mut().expect("invalid grpc") {
+                let update = update.ok_or(anyhow::anyhow!("geyser plugin has closed the stream"))??;
+                let receive_timestamp = SystemTime::now();
+                // as received, replays process it again
+                if let Some(recorder) = recorder.as_mut() {
+                    recorder.record_update(source_index, &update, receive_timestamp).await;
+                }
+                let mut rooted = false;
+                match update.update_oneof.as_ref().expect("invalid grpc") {
                     UpdateOneof::SubscribeResponse(subscribe_response) => {
-                        first_full_slot = subscribe_response.highest_write_slot + 1;
+                        check_protocol(subscribe_response, &request_fields, grpc_config.compression)?;
                     },
                     UpdateOneof::SlotUpdate(slot_update) => {
-                        let status = slot_update.status;
-                        if status == Status::Rooted as i32 {
-                            if slot_update.slot > max_rooted_slot {
-                                max_rooted_slot = slot_update.slot;
-
-                                // drop data for slots that are well beyond rooted
-                                slot_pubkey_writes.retain(|&k, _| k >= max_rooted_slot - max_out_of_order_slots);
-                            }
-                            if snapshot_needed && max_rooted_slot - rooted_to_finalized_slots > first_full_slot {
-                                snapshot_needed = false;
-                                snapshot_future = tokio::spawn(get_snapshot(snapshot_config.rpc_http_url.clone(), program_id)).fuse();
-                            }
-                        }
+                        rooted = slot_update.status == Status::Rooted as i32;
                     },
-                    UpdateOneof::AccountWrite(write) => {
-                        if write.slot < first_full_slot {
-                            // Don't try to process data for slots where we may have missed writes:
-                            // We could not map the write_version correctly for them.
-                            continue;
-                        }
-
-                        if write.slot > newest_write_slot {
-                            newest_write_slot = write.slot;
-                        } else if max_rooted_slot > 0 && write.slot < max_rooted_slot - max_out_of_order_slots {
-                            anyhow::bail!("received write {} slots back from max rooted slot {}", max_rooted_slot - write.slot, max_rooted_slot);
-                        }
-
-                        let pubkey_writes = slot_pubkey_writes.entry(write.slot).or_default();
-
-                        let pubkey_bytes = Pubkey::new(&write.pubkey).to_bytes();
-                        let write_version_mapping = pubkey_writes.entry(pubkey_bytes).or_insert(WriteVersion {
-                            global: write.write_version,
-                            slot: 1, // write version 0 is reserved for snapshots
-                        });
-
-                        // We assume we will receive write versions for each pubkey in sequence.
-                        // If this is not the case, logic here does not work correctly because
-                        // a later write could arrive first.
-                        if write.write_version < write_version_mapping.global {
-                            anyhow::bail!("unexpected write version: got {}, expected >= {}", write.write_version, write_version_mapping.global);
+                    _ => {},
+                }
+                if let Some(message) = state.process(update, receive_timestamp, source_index, resume_slot, stream_metrics)? {
+                    sender.send(message).await.expect("send success");
+                }
+                if rooted {
+                    if !state.resync_pubkeys.is_empty() && resync_future.is_terminated() {
+                        let rpc_http_urls = snapshot_config.all_rpc_http_urls();
+                        if rpc_http_urls.is_empty() {
+                            // a new snapshot has the current data of all accounts
+                            stream_metrics.failed_resyncs.increment();
+                            *resume_slot = None;
+                            anyhow::bail!("no rpc to fetch {} accounts with dropped writes from, reconnecting for a new snapshot", state.resync_pubkeys.len());
                         }
-
-                        // Rewrite the update to use the local write version and bump it
-                        write.write_version = write_version_mapping.slot as u64;
-                        write_version_mapping.slot += 1;
-                    },
-                    geyser_proto::update::UpdateOneof::Ping(_) => {},
+                        resync_future = tokio::spawn(resync_accounts(
+                            rpc_http_urls,
+                            state.resync_pubkeys.drain().collect(),
+                            sender.clone(),
+                            recorder.clone(),
+                            stream_metrics.snapshot_decode_errors.clone(),
+                        )).fuse();
+                    }
+                    if state.snapshot_needed && state.max_rooted_slot - rooted_to_finalized_slots > state.first_full_slot {
+                        state.snapshot_needed = false;
+                        snapshot_future = tokio::spawn(snapshot::get_snapshot(snapshot_config.clone(), selection.clone(), state.first_full_slot, stream_metrics.snapshot_decode_errors.clone())).fuse();
+                    }
+                }
+            },
+            resync = &mut resync_future => {
+                if let Err(err) = resync? {
+                    stream_metrics.failed_resyncs.increment();
+                    *resume_slot = None;
+                    anyhow::bail!("could not fetch accounts with dropped writes, reconnecting for a new snapshot: {:?}", err);
                 }
-                sender.send(Message::GrpcUpdate(update)).await.expect("send success");
             },
             snapshot = &mut snapshot_future => {
-                let snapshot = snapshot??;
-                if let OptionalContext::Context(snapshot_data) = snapshot {
-                    info!("snapshot is for slot {}, first full slot was {}", snapshot_data.context.slot, first_full_slot);
-                    if snapshot_data.context.slot >= first_full_slot {
-                        sender
-                        .send(Message::Snapshot(snapshot_data))
-                        .await
-                        .expect("send success");
-                    } else {
-                        info!(
-                            "snapshot is too old: has slot {}, expected {} minimum",
-                            snapshot_data.context.slot,
-                            first_full_slot
-                        );
-                        // try again in another 10 slots
-                        snapshot_needed = true;
-                        rooted_to_finalized_slots += 10;
+                let snapshot_data = snapshot??;
+                info!("snapshot is for slot {}, first full slot was {}", snapshot_data.slot, state.first_full_slot);
+                if snapshot_data.slot >= state.first_full_slot {
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.record_snapshot(&snapshot_data).await;
                     }
+                    sender
+                    .send(Message::Snapshot(snapshot_data))
+                    .await
+                    .expect("send success");
+
+                    // All data from here on is complete, a reconnect can resume
+                    *resume_slot = Some(state.resume_from_slot());
                 } else {
-                    anyhow::bail!("bad snapshot format");
+                    info!(
+                        "snapshot is too old: has slot {}, expected {} minimum",
+                        snapshot_data.slot,
+                        state.first_full_slot
+                    );
+                    // Only rpc snapshots can be too old, their finalized slot
+                    // lags behind rooted. Try again in another 10 slots.
+                    state.snapshot_needed = true;
+                    rooted_to_finalized_slots += 10;
                 }
             },
             _ = tokio::time::sleep(fatal_idle_timeout) => {
@@ -246,25 +781,50 @@ pub async fn process_events(
     config: &SourceConfig,
     account_write_queue_sender: async_channel::Sender<AccountWrite>,
     slot_queue_sender: async_channel::Sender<SlotUpdate>,
+    block_metadata_queue_sender: Option<async_channel::Sender<BlockMetadata>>,
+    transaction_queue_sender: Option<async_channel::Sender<TransactionUpdate>>,
+    recorder: Option<recording::Recorder>,
     metrics_sender: metrics::Metrics,
 ) {
     // Subscribe to geyser
     let (msg_sender, msg_receiver) = async_channel::bounded::<Message>(config.dedup_queue_size);
-    for grpc_source in config.grpc_sources.clone() {
+    let requested = RequestedUpdates {
+        transactions: transaction_queue_sender.is_some(),
+        block_metadata: block_metadata_queue_sender.is_some(),
+    };
+    let metric_retries_family = metrics_sender.register_u64_family(
+        "grpc_source_connection_retries".into(),
+        metrics::MetricType::Counter,
+    );
+    let metric_status_family = metrics_sender.register_string_family("grpc_source_status".into());
+    let source_names: Vec<String> = config
+        .grpc_sources
+        .iter()
+        .map(|source| source.name.clone())
+        .collect();
+
+    // Record the updates of all sources before they are checked and rewritten
+    let recorder = recorder.or_else(|| {
+        config.record_path.as_ref().map(|path| {
+            recording::Recorder::create(path, &source_names)
+                .expect("recording file must be writable")
+        })
+    });
+    for (source_index, grpc_source) in config.grpc_sources.clone().into_iter().enumerate() {
         let msg_sender = msg_sender.clone();
+        let recorder = recorder.clone();
         let snapshot_source = config.snapshot.clone();
-        let metrics_sender = metrics_sender.clone();
+        let mut metric_retries =
+            metric_retries_family.with_labels(&[("source", &grpc_source.name)]);
+        let metric_status = metric_status_family.with_labels(&[("source", &grpc_source.name)]);
+        let mut stream_metrics = StreamMetrics::new(&metrics_sender, &grpc_source.name);
 
         // Make TLS config if configured
         let tls_config = grpc_source.tls.as_ref().map(make_tls_config);
 
         tokio::spawn(async move {
-            let mut metric_retries = metrics_sender.register_u64(format!(
-                "grpc_source_{}_connection_retries",
-                grpc_source.name
-            ));
-            let metric_status =
-                metrics_sender.register_string(format!("grpc_source_{}_status", grpc_source.name));
+            // Slot to ask the plugin to replay from after a reconnect
+            let mut resume_slot = None;
 
             // Continuously reconnect on failure
             loop {
@@ -274,6 +834,11 @@ pub async fn process_events(
                     tls_config.clone(),
                     &snapshot_source,
                     msg_sender.clone(),
+                    &mut resume_slot,
+                    source_index,
+                    requested,
+                    &mut stream_metrics,
+                    recorder.clone(),
                 );
                 let result = out.await;
                 assert!(result.is_err());
@@ -295,6 +860,31 @@ pub async fn process_events(
         });
     }
 
+    process_messages(
+        &source_names,
+        msg_receiver,
+        account_write_queue_sender,
+        slot_queue_sender,
+        block_metadata_queue_sender,
+        transaction_queue_sender,
+        metrics_sender,
+    )
+    .await;
+}
+
+/// Deduplicates and converts the messages from all sources and forwards them
+/// into the target queues
+///
+/// The source index of each message refers to source_names.
+pub(crate) async fn process_messages(
+    source_names: &[String],
+    msg_receiver: async_channel::Receiver<Message>,
+    account_write_queue_sender: async_channel::Sender<AccountWrite>,
+    slot_queue_sender: async_channel::Sender<SlotUpdate>,
+    block_metadata_queue_sender: Option<async_channel::Sender<BlockMetadata>>,
+    transaction_queue_sender: Option<async_channel::Sender<TransactionUpdate>>,
+    metrics_sender: metrics::Metrics,
+) {
     // slot -> (pubkey -> write_version)
     //
     // To avoid unnecessarily sending requests to SQL, we track the latest write_version
@@ -305,19 +895,75 @@ pub async fn process_events(
     // Number of slots to retain in latest_write
     let latest_write_retention = 50;
 
-    let mut metric_account_writes = metrics_sender.register_u64("grpc_account_writes".into());
-    let mut metric_account_queue = metrics_sender.register_u64("account_write_queue".into());
-    let mut metric_slot_queue = metrics_sender.register_u64("slot_update_queue".into());
-    let mut metric_slot_updates = metrics_sender.register_u64("grpc_slot_updates".into());
-    let mut metric_snapshots = metrics_sender.register_u64("grpc_snapshots".into());
-    let mut metric_snapshot_account_writes =
-        metrics_sender.register_u64("grpc_snapshot_account_writes".into());
+    let mut metric_account_writes =
+        metrics_sender.register_u64("grpc_account_writes".into(), metrics::MetricType::Counter);
+    let mut metric_account_queue =
+        metrics_sender.register_u64("account_write_queue".into(), metrics::MetricType::Gauge);
+    let mut metric_slot_queue =
+        metrics_sender.register_u64("slot_update_queue".into(), metrics::MetricType::Gauge);
+    let mut metric_slot_updates =
+        metrics_sender.register_u64("grpc_slot_updates".into(), metrics::MetricType::Counter);
+    let mut metric_transactions =
+        metrics_sender.register_u64("grpc_transactions".into(), metrics::MetricType::Counter);
+    let mut metric_block_metadata =
+        metrics_sender.register_u64("grpc_block_metadata".into(), metrics::MetricType::Counter);
+    let mut metric_snapshots =
+        metrics_sender.register_u64("grpc_snapshots".into(), metrics::MetricType::Counter);
+    let mut metric_snapshot_account_writes = metrics_sender.register_u64(
+        "grpc_snapshot_account_writes".into(),
+        metrics::MetricType::Counter,
+    );
+
+    // How many slots each source is behind the newest slot seen from any source
+    let metric_slot_lag_family = metrics_sender
+        .register_u64_family("grpc_source_slot_lag".into(), metrics::MetricType::Gauge);
+    let mut metric_slot_lags: Vec<_> = source_names
+        .iter()
+        .map(|name| metric_slot_lag_family.with_labels(&[("source", name)]))
+        .collect();
+    let mut source_newest_slots = vec![0u64; source_names.len()];
+
+    // Time from the plugin sending an update to it being received, per source
+    let metric_latency_family = metrics_sender.register_histogram_family(
+        "grpc_source_latency_seconds".into(),
+        metrics::LATENCY_BUCKETS,
+    );
+    let make_latency_metrics = |update: &str| -> Vec<metrics::MetricHistogram> {
+        source_names
+            .iter()
+            .map(|name| metric_latency_family.with_labels(&[("source", name), ("update", update)]))
+            .collect()
+    };
+    let metric_account_write_latencies = make_latency_metrics("account_write");
+    let metric_slot_update_latencies = make_latency_metrics("slot_update");
+    let observe_latency = |metric: &metrics::MetricHistogram,
+                           send_timestamp: Option<SystemTime>,
+                           receive_timestamp: SystemTime| {
+        if let Some(send_timestamp) = send_timestamp {
+            // clocks may be skewed, never report negative latencies
+            metric.observe_duration(
+                receive_timestamp
+                    .duration_since(send_timestamp)
+                    .unwrap_or_default(),
+            );
+        }
+    };
 
     loop {
-        let msg = msg_receiver.recv().await.expect("sender must not close");
+        let msg = match msg_receiver.recv().await {
+            Ok(msg) => msg,
+            Err(_) => {
+                info!("all sources have stopped");
+                return;
+            }
+        };
 
         match msg {
-            Message::GrpcUpdate(update) => {
+            Message::GrpcUpdate {
+                source,
+                update,
+                receive_timestamp,
+            } => {
                 match update.update_oneof.expect("invalid grpc") {
                     geyser_proto::update::UpdateOneof::AccountWrite(update) => {
                         assert!(update.pubkey.len() == 32);
@@ -325,6 +971,12 @@ pub async fn process_events(
 
                         metric_account_writes.increment();
                         metric_account_queue.set(account_write_queue_sender.len() as u64);
+                        let send_timestamp = timestamp_from_us(update.send_timestamp_us);
+                        observe_latency(
+                            &metric_account_write_latencies[source],
+                            send_timestamp,
+                            receive_timestamp,
+                        );
 
                         // Skip writes that a different server has already sent
                         let pubkey_writes = latest_write.entry(update.slot).or_default();
@@ -347,6 +999,8 @@ pub async fn process_events(
                                 rent_epoch: update.rent_epoch,
                                 data: update.data,
                                 is_selected: update.is_selected,
+                                send_timestamp,
+                                receive_timestamp: Some(receive_timestamp),
                             })
                             .await
                             .expect("send success");
@@ -354,6 +1008,19 @@ pub async fn process_events(
                     geyser_proto::update::UpdateOneof::SlotUpdate(update) => {
                         metric_slot_updates.increment();
                         metric_slot_queue.set(slot_queue_sender.len() as u64);
+                        let send_timestamp = timestamp_from_us(update.send_timestamp_us);
+                        observe_latency(
+                            &metric_slot_update_latencies[source],
+                            send_timestamp,
+                            receive_timestamp,
+                        );
+
+                        source_newest_slots[source] = source_newest_slots[source].max(update.slot);
+                        let newest_slot = source_newest_slots.iter().max().cloned().unwrap_or(0);
+                        for (metric, slot) in metric_slot_lags.iter_mut().zip(&source_newest_slots)
+                        {
+                            metric.set(newest_slot - slot);
+                        }
 
                         use geyser_proto::slot_update::Status;
                         let status = Status::from_i32(update.status).map(|v| match v {
@@ -369,6 +1036,8 @@ pub async fn process_events(
                             slot: update.slot,
                             parent: update.parent,
                             status: status.expect("qed"),
+                            send_timestamp,
+                            receive_timestamp: Some(receive_timestamp),
                         };
 
                         slot_queue_sender
@@ -376,22 +1045,91 @@ pub async fn process_events(
                             .await
                             .expect("send success");
                     }
+                    geyser_proto::update::UpdateOneof::Transaction(update) => {
+                        let transaction_queue_sender = match &transaction_queue_sender {
+                            Some(sender) => sender,
+                            None => continue,
+                        };
+                        assert!(update.signature.len() == 64);
+                        assert!(update.account_keys.iter().all(|key| key.len() == 32));
+
+                        metric_transactions.increment();
+
+                        // Duplicates from multiple servers are dropped on insertion
+                        transaction_queue_sender
+                            .send(TransactionUpdate {
+                                signature: Signature::new(&update.signature),
+                                slot: update.slot,
+                                account_keys: update
+                                    .account_keys
+                                    .iter()
+                                    .map(|key| Pubkey::new(key))
+                                    .collect(),
+                                err: update.err,
+                                log_messages: update.log_messages,
+                            })
+                            .await
+                            .expect("send success");
+                    }
+                    geyser_proto::update::UpdateOneof::BlockMetadata(update) => {
+                        let block_metadata_queue_sender = match &block_metadata_queue_sender {
+                            Some(sender) => sender,
+                            None => continue,
+                        };
+                        assert!(update
+                            .rewards
+                            .iter()
+                            .all(|reward| reward.pubkey.len() == 32));
+
+                        metric_block_metadata.increment();
+
+                        use geyser_proto::reward::RewardType as ProtoRewardType;
+                        let rewards = update
+                            .rewards
+                            .iter()
+                            .map(|reward| Reward {
+                                pubkey: Pubkey::new(&reward.pubkey),
+                                lamports: reward.lamports,
+                                post_balance: reward.post_balance,
+                                reward_type: ProtoRewardType::from_i32(reward.reward_type)
+                                    .and_then(|v| match v {
+                                        ProtoRewardType::Unspecified => None,
+                                        ProtoRewardType::Fee => Some(RewardType::Fee),
+                                        ProtoRewardType::Rent => Some(RewardType::Rent),
+                                        ProtoRewardType::Staking => Some(RewardType::Staking),
+                                        ProtoRewardType::Voting => Some(RewardType::Voting),
+                                    }),
+                                commission: reward.commission.map(|c| c as u8),
+                            })
+                            .collect();
+
+                        // Duplicates from multiple servers are idempotent updates
+                        block_metadata_queue_sender
+                            .send(BlockMetadata {
+                                slot: update.slot,
+                                blockhash: update.blockhash,
+                                block_time: update.block_time,
+                                block_height: update.block_height,
+                                rewards,
+                            })
+                            .await
+                            .expect("send success");
+                    }
                     geyser_proto::update::UpdateOneof::Ping(_) => {}
+                    geyser_proto::update::UpdateOneof::SlowSubscriber(_) => {}
                     geyser_proto::update::UpdateOneof::SubscribeResponse(_) => {}
+                    geyser_proto::update::UpdateOneof::AccountsSnapshot(_) => {}
                 }
             }
             Message::Snapshot(update) => {
                 metric_snapshots.increment();
                 info!("processing snapshot...");
-                for keyed_account in update.value {
+                for (pubkey, account) in update.accounts {
                     metric_snapshot_account_writes.increment();
                     metric_account_queue.set(account_write_queue_sender.len() as u64);
 
-                    // TODO: Resnapshot on invalid data?
-                    let account: Account = keyed_account.account.decode().unwrap();
-                    let pubkey = Pubkey::from_str(&keyed_account.pubkey).unwrap();
                     account_write_queue_sender
-                        .send(AccountWrite::from(pubkey, update.context.slot, 0, account))
+                        .send(AccountWrite::from(pubkey, update.slot, 0, account))
                         .await
                         .expect("send success");
                 }
@@ -400,3 +1138,157 @@ pub async fn process_events(
         }
     }
 }
+
+/// Streams from the geyser plugins in `config.grpc_sources`
+pub struct GrpcSource {
+    pub config: SourceConfig,
+    pub block_metadata_queue_sender: Option<async_channel::Sender<BlockMetadata>>,
+    pub transaction_queue_sender: Option<async_channel::Sender<TransactionUpdate>>,
+    /// Records to this recording instead of one created at `config.record_path`
+    pub recorder: Option<recording::Recorder>,
+}
+
+#[async_trait::async_trait]
+impl Source for GrpcSource {
+    async fn run(
+        &self,
+        account_write_queue_sender: async_channel::Sender<AccountWrite>,
+        slot_queue_sender: async_channel::Sender<SlotUpdate>,
+        metrics_sender: metrics::Metrics,
+    ) {
+        process_events(
+            &self.config,
+            account_write_queue_sender,
+            slot_queue_sender,
+            self.block_metadata_queue_sender.clone(),
+            self.transaction_queue_sender.clone(),
+            self.recorder.clone(),
+            metrics_sender,
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn delta(data_len: u64, ranges: &[(u64, &[u8])]) -> geyser_proto::AccountDataDelta {
+        geyser_proto::AccountDataDelta {
+            data_len,
+            ranges: ranges
+                .iter()
+                .map(|(offset, data)| geyser_proto::DataRange {
+                    offset: *offset,
+                    data: data.to_vec(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_apply_data_delta() {
+        let previous = vec![1, 2, 3];
+        assert_eq!(
+            apply_data_delta(Some(&previous), &delta(4, &[(1, &[5]), (3, &[6])])).unwrap(),
+            vec![1, 5, 3, 6]
+        );
+        assert_eq!(
+            apply_data_delta(Some(&previous), &delta(2, &[])).unwrap(),
+            vec![1, 2]
+        );
+        assert!(apply_data_delta(None, &delta(3, &[])).is_err());
+        assert!(apply_data_delta(Some(&previous), &delta(3, &[(2, &[5, 6])])).is_err());
+        // must not overflow or allocate whatever the plugin claims
+        assert!(apply_data_delta(Some(&previous), &delta(3, &[(u64::MAX, &[5])])).is_err());
+        assert!(apply_data_delta(Some(&previous), &delta(u64::MAX, &[])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resume_slot() {
+        use geyser_proto::{slot_update::Status, update::UpdateOneof};
+        let metrics_tx = metrics::start(crate::MetricsConfig {
+            output_stdout: false,
+            prometheus_bind_address: None,
+        })
+        .unwrap();
+        let mut stream_metrics = StreamMetrics::new(&metrics_tx, "test");
+        let mut state = StreamState::new(&geyser_proto::SubscribeRequest::default(), false);
+        let mut resume_slot = None;
+        let mut process = |state: &mut StreamState, update_oneof| {
+            let update = geyser_proto::Update {
+                update_oneof: Some(update_oneof),
+                ..geyser_proto::Update::default()
+            };
+            state
+                .process(
+                    update,
+                    SystemTime::now(),
+                    0,
+                    &mut resume_slot,
+                    &mut stream_metrics,
+                )
+                .unwrap();
+            resume_slot
+        };
+        let rooted = |slot| {
+            UpdateOneof::SlotUpdate(geyser_proto::SlotUpdate {
+                slot,
+                status: Status::Rooted as i32,
+                ..geyser_proto::SlotUpdate::default()
+            })
+        };
+        let write = |slot| {
+            UpdateOneof::AccountWrite(geyser_proto::AccountWrite {
+                slot,
+                pubkey: vec![1; 32],
+                owner: vec![2; 32],
+                write_version: slot,
+                ..geyser_proto::AccountWrite::default()
+            })
+        };
+
+        process(
+            &mut state,
+            UpdateOneof::SubscribeResponse(geyser_proto::SubscribeResponse {
+                highest_write_slot: 9,
+                snapshot: true,
+                ..geyser_proto::SubscribeResponse::default()
+            }),
+        );
+        let snapshot = UpdateOneof::AccountsSnapshot(geyser_proto::AccountsSnapshot {
+            slot: 10,
+            accounts: vec![],
+            is_last: true,
+        });
+        assert_eq!(process(&mut state, snapshot), Some(11));
+
+        // writes in slots that aren't rooted yet may be incomplete
+        assert_eq!(process(&mut state, write(13)), Some(11));
+        assert_eq!(process(&mut state, rooted(12)), Some(13));
+        assert_eq!(process(&mut state, write(14)), Some(13));
+    }
+
+    #[tokio::test]
+    async fn test_unrequested_replay() {
+        let metrics_tx = metrics::start(crate::MetricsConfig {
+            output_stdout: false,
+            prometheus_bind_address: None,
+        })
+        .unwrap();
+        let mut stream_metrics = StreamMetrics::new(&metrics_tx, "test");
+        let mut state = StreamState::new(&geyser_proto::SubscribeRequest::default(), false);
+        let update = geyser_proto::Update {
+            update_oneof: Some(geyser_proto::update::UpdateOneof::SubscribeResponse(
+                geyser_proto::SubscribeResponse {
+                    replaying: true,
+                    ..geyser_proto::SubscribeResponse::default()
+                },
+            )),
+            ..geyser_proto::Update::default()
+        };
+        assert!(state
+            .process(update, SystemTime::now(), 0, &mut None, &mut stream_metrics)
+            .is_err());
+    }
+}
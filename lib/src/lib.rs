@@ -4,6 +4,9 @@ pub mod memory_target;
 pub mod metrics;
 pub mod postgres_target;
 pub mod postgres_types_numeric;
+pub mod recording;
+pub mod snapshot;
+pub mod snapshot_archive;
 pub mod websocket_source;
 
 pub use chain_data::SlotStatus;
@@ -11,8 +14,8 @@ pub use chain_data::SlotStatus;
 use {
     async_trait::async_trait,
     serde_derive::Deserialize,
-    solana_sdk::{account::Account, pubkey::Pubkey},
-    std::sync::Arc,
+    solana_sdk::{account::Account, pubkey::Pubkey, signature::Signature},
+    std::{sync::Arc, time::SystemTime},
 };
 
 trait AnyhowWrap {
@@ -38,6 +41,10 @@ pub struct AccountWrite {
     pub rent_epoch: u64,
     pub data: Vec<u8>,
     pub is_selected: bool,
+    /// When the source sent the write, if known
+    pub send_timestamp: Option<SystemTime>,
+    /// When the connector received the write, None for snapshot writes
+    pub receive_timestamp: Option<SystemTime>,
 }
 
 impl AccountWrite {
@@ -52,15 +59,58 @@ impl AccountWrite {
             rent_epoch: account.rent_epoch,
             data: account.data,
             is_selected: true,
+            send_timestamp: None,
+            receive_timestamp: None,
         }
     }
 }
 
+#[derive(Clone, PartialEq, Debug)]
+pub struct TransactionUpdate {
+    pub signature: Signature,
+    pub slot: u64,
+    pub account_keys: Vec<Pubkey>,
+    /// The error the transaction failed with, if any
+    pub err: Option<String>,
+    pub log_messages: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RewardType {
+    Fee,
+    Rent,
+    Staking,
+    Voting,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Reward {
+    pub pubkey: Pubkey,
+    pub lamports: i64,
+    pub post_balance: u64,
+    pub reward_type: Option<RewardType>,
+    pub commission: Option<u8>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct BlockMetadata {
+    pub slot: u64,
+    pub blockhash: String,
+    /// Unix timestamp of the block
+    pub block_time: Option<i64>,
+    pub block_height: Option<u64>,
+    pub rewards: Vec<Reward>,
+}
+
 #[derive(Clone, Debug)]
 pub struct SlotUpdate {
     pub slot: u64,
     pub parent: Option<u64>,
     pub status: chain_data::SlotStatus,
+    /// When the source sent the update, if known
+    pub send_timestamp: Option<SystemTime>,
+    /// When the connector received the update
+    pub receive_timestamp: Option<SystemTime>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -74,6 +124,13 @@ pub struct PostgresConfig {
     pub account_write_max_queue_size: usize,
     /// Number of parallel postgres connections used for slot insertions
     pub slot_update_connection_count: u64,
+    /// Number of parallel postgres connections used for transaction insertions (0 to disable)
+    #[serde(default)]
+    pub transaction_connection_count: u64,
+    /// Store block rewards in the block_reward table. The other block metadata
+    /// is always stored in the slot table.
+    #[serde(default)]
+    pub store_block_rewards: bool,
     /// Number of queries retries before fatal error
     pub retry_query_max_count: u64,
     /// Seconds to sleep between query retries
@@ -100,32 +157,231 @@ pub struct TlsConfig {
     pub domain_name: String,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct MemcmpConfig {
+    pub offset: u64,
+    /// Base58 encoded bytes that the account data must contain at offset
+    pub bytes: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountFilterConfig {
+    DataSize(u64),
+    Memcmp(MemcmpConfig),
+}
+
+/// Limits the account writes a geyser plugin sends to this connector
+#[derive(Clone, Debug, Deserialize)]
+pub struct SubscriptionFilterConfig {
+    /// Send writes to these accounts or to accounts owned by these owners.
+    /// If both are empty, the plugin's account selection is used.
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    #[serde(default)]
+    pub owners: Vec<String>,
+    /// Account data must match all of these filters
+    #[serde(default)]
+    pub filters: Vec<AccountFilterConfig>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct GrpcSourceConfig {
     pub name: String,
     pub connection_string: String,
     pub retry_connection_sleep_secs: u64,
     pub tls: Option<TlsConfig>,
+    /// Sent as bearer token, if the plugin requires authentication
+    pub access_token: Option<String>,
+    /// Needs snapshot_from_plugin: an rpc snapshot would also load the accounts
+    /// the filter excludes, and they'd never be updated
+    pub filter: Option<SubscriptionFilterConfig>,
+    /// Ask the plugin for the snapshot instead of calling getProgramAccounts
+    #[serde(default)]
+    pub snapshot_from_plugin: bool,
+    /// Reconnect and take a new snapshot when an account write arrives too late
+    /// or out of order. By default, such writes are dropped and counted in
+    /// grpc_source_write_anomalies.
+    #[serde(default)]
+    pub strict_write_versions: bool,
+    /// Accept a compressed stream, if the plugin is configured to send one
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Ask the plugin to send changed bytes instead of the full account data,
+    /// needs a copy of all account data on this side
+    #[serde(default)]
+    pub delta_account_data: bool,
+}
+
+/// Encoding of the plugin's update stream
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+}
+
+/// Where account writes and slot updates come from
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    /// The gRPC geyser plugin, see grpc_sources
+    Grpc,
+    /// JSON-RPC websocket subscriptions, see rpc_ws_url
+    Websocket,
+    /// A file written with record_path, see replay
+    Replay,
+}
+
+impl Default for SourceKind {
+    fn default() -> Self {
+        SourceKind::Grpc
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct SourceConfig {
+    #[serde(default)]
+    pub kind: SourceKind,
     pub dedup_queue_size: usize,
     pub grpc_sources: Vec<GrpcSourceConfig>,
     pub snapshot: SnapshotSourceConfig,
     pub rpc_ws_url: String,
+    /// Record everything the gRPC sources receive to this file
+    #[serde(default)]
+    pub record_path: Option<String>,
+    #[serde(default)]
+    pub replay: Option<ReplayConfig>,
+}
+
+impl SourceConfig {
+    /// Fails on settings that can't work together, call after loading
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self.kind {
+            SourceKind::Grpc if self.grpc_sources.is_empty() => {
+                anyhow::bail!("the grpc source needs grpc_sources")
+            }
+            SourceKind::Websocket if self.rpc_ws_url.is_empty() => {
+                anyhow::bail!("the websocket source needs rpc_ws_url")
+            }
+            SourceKind::Replay if self.replay.is_none() => {
+                anyhow::bail!("the replay source needs a [source.replay] section")
+            }
+            _ => {}
+        }
+        for grpc_source in self.grpc_sources.iter() {
+            if grpc_source.filter.is_some() && !grpc_source.snapshot_from_plugin {
+                anyhow::bail!(
+                    "grpc source {} sets filter, which needs snapshot_from_plugin",
+                    grpc_source.name
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReplayConfig {
+    /// A file written with record_path
+    pub path: String,
+    /// 1.0 replays at the original speed, 10.0 ten times faster and 0 as fast
+    /// as possible
+    pub speed: f64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SnapshotProgramConfig {
+    pub program_id: String,
+    /// Only accounts that match all filters are part of the snapshot
+    #[serde(default)]
+    pub filters: Vec<AccountFilterConfig>,
+    /// Fetch the program's accounts in several smaller requests
+    #[serde(default)]
+    pub partition: Option<SnapshotPartitionConfig>,
+}
+
+/// Splits a program snapshot into one getProgramAccounts request per value of
+/// the byte at `offset`, like the account type discriminator. Accounts with no
+/// data at `offset` are fetched with one request per data size up to `offset`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SnapshotPartitionConfig {
+    pub offset: u64,
+    /// The byte values to request, all 256 if empty. Accounts with other values
+    /// are not part of the snapshot.
+    #[serde(default)]
+    pub values: Vec<u8>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct SnapshotSourceConfig {
+    #[serde(default)]
     pub rpc_http_url: String,
+    /// Further rpc nodes; failed snapshot requests are retried on the next one
+    #[serde(default)]
+    pub rpc_http_urls: Vec<String>,
+    /// Shorthand for a `programs` entry without filters, may be empty
+    #[serde(default)]
     pub program_id: String,
+    #[serde(default)]
+    pub programs: Vec<SnapshotProgramConfig>,
+    /// Individual accounts, fetched with getMultipleAccounts
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    /// Read snapshots from the newest snapshot-*.tar.zst archive (and the newest
+    /// incremental archive on top of it) in this directory instead of calling
+    /// getProgramAccounts on rpc_http_url
+    #[serde(default)]
+    pub archive_dir: Option<String>,
+}
+
+impl SnapshotSourceConfig {
+    /// programs, plus program_id if it is set
+    pub fn all_programs(&self) -> Vec<SnapshotProgramConfig> {
+        let mut programs = self.programs.clone();
+        if !self.program_id.is_empty() {
+            programs.push(SnapshotProgramConfig {
+                program_id: self.program_id.clone(),
+                filters: vec![],
+                partition: None,
+            });
+        }
+        programs
+    }
+
+    /// rpc_http_url, if it is set, followed by rpc_http_urls
+    pub fn all_rpc_http_urls(&self) -> Vec<String> {
+        let mut urls = vec![];
+        if !self.rpc_http_url.is_empty() {
+            urls.push(self.rpc_http_url.clone());
+        }
+        urls.extend(self.rpc_http_urls.iter().cloned());
+        urls
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MetricsConfig {
+    /// Log all metrics every 60 seconds
+    pub output_stdout: bool,
+    /// Serve metrics in the Prometheus text format on http://<address>/metrics
+    pub prometheus_bind_address: Option<String>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            output_stdout: true,
+            prometheus_bind_address: None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub postgres_target: PostgresConfig,
     pub source: SourceConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
 }
 
 #[async_trait]
@@ -140,6 +396,45 @@ pub trait AccountTable: Sync + Send {
 
 pub type AccountTables = Vec<Arc<dyn AccountTable>>;
 
+#[async_trait]
+pub trait Source: Sync + Send {
+    /// Streams account writes and slot updates into the senders, reconnecting
+    /// on failure. Does not return.
+    async fn run(
+        &self,
+        account_write_queue_sender: async_channel::Sender<AccountWrite>,
+        slot_queue_sender: async_channel::Sender<SlotUpdate>,
+        metrics_sender: metrics::Metrics,
+    );
+}
+
+/// Creates the source selected by `config.kind`
+///
+/// Block metadata and transactions are only available from the gRPC and replay
+/// sources.
+pub fn make_source(
+    config: &SourceConfig,
+    block_metadata_queue_sender: Option<async_channel::Sender<BlockMetadata>>,
+    transaction_queue_sender: Option<async_channel::Sender<TransactionUpdate>>,
+) -> Box<dyn Source> {
+    match config.kind {
+        SourceKind::Grpc => Box::new(grpc_plugin_source::GrpcSource {
+            config: config.clone(),
+            block_metadata_queue_sender,
+            transaction_queue_sender,
+            recorder: None,
+        }),
+        SourceKind::Websocket => Box::new(websocket_source::WebsocketSource {
+            config: config.clone(),
+        }),
+        SourceKind::Replay => Box::new(recording::ReplaySource {
+            config: config.clone(),
+            block_metadata_queue_sender,
+            transaction_queue_sender,
+        }),
+    }
+}
+
 pub struct RawAccountTable {}
 
 pub fn encode_address(addr: &Pubkey) -> String {
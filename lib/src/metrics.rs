@@ -1,15 +1,116 @@
 use {
+    crate::MetricsConfig,
+    hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server, StatusCode,
+    },
     log::*,
     std::collections::HashMap,
+    std::convert::Infallible,
+    std::fmt::{self, Write},
+    std::net::SocketAddr,
     std::sync::{atomic, Arc, Mutex, RwLock},
     tokio::time,
 };
 
+/// Histogram buckets for latencies, in seconds
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How a u64 metric should be interpreted by monitoring systems
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricType {
+    /// Only ever increases
+    Counter,
+    /// Can go up and down
+    Gauge,
+}
+
+/// A metric name together with its labels
+///
+/// Metrics with the same name and different labels form a family.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct MetricKey {
+    name: String,
+    labels: Vec<(String, String)>,
+}
+
+impl MetricKey {
+    fn new(name: String, labels: &[(&str, &str)]) -> Self {
+        MetricKey {
+            name,
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for MetricKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.labels.is_empty() {
+            let labels: Vec<String> = self
+                .labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, v))
+                .collect();
+            write!(f, "{{{}}}", labels.join(","))?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
+struct HistogramState {
+    /// Upper bounds of the buckets, ascending
+    bounds: Vec<f64>,
+    /// Observations per bucket, not cumulative; the last entry counts values
+    /// above the largest bound
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl HistogramState {
+    fn new(bounds: &[f64]) -> Self {
+        HistogramState {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Upper bound of the bucket that contains the quantile, None if it is
+    /// above the largest bound or there are no observations
+    fn quantile_bound(&self, quantile: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (quantile * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            seen += count;
+            if seen >= rank {
+                return Some(*bound);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Clone, Debug)]
 enum Value {
-    U64(Arc<atomic::AtomicU64>),
+    U64 {
+        value: Arc<atomic::AtomicU64>,
+        metric_type: MetricType,
+    },
     I64(Arc<atomic::AtomicI64>),
     String(Arc<Mutex<String>>),
+    Histogram(Arc<Mutex<HistogramState>>),
 }
 
 #[derive(Debug)]
@@ -17,6 +118,7 @@ enum PrevValue {
     U64(u64),
     I64(i64),
     String(String),
+    Histogram(u64),
 }
 
 #[derive(Clone)]
@@ -78,60 +180,328 @@ impl MetricString {
     }
 }
 
+#[derive(Clone)]
+pub struct MetricHistogram {
+    value: Arc<Mutex<HistogramState>>,
+}
+
+impl MetricHistogram {
+    pub fn observe(&self, value: f64) {
+        let mut state = self.value.lock().unwrap();
+        let bucket = state
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(state.bounds.len());
+        state.counts[bucket] += 1;
+        state.sum += value;
+        state.count += 1;
+    }
+
+    pub fn observe_duration(&self, duration: std::time::Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+}
+
+/// A u64 metric that is split by labels, like `grpc_source_status{source="a"}`
+#[derive(Clone)]
+pub struct MetricFamilyU64 {
+    metrics: Metrics,
+    name: String,
+    metric_type: MetricType,
+}
+
+impl MetricFamilyU64 {
+    pub fn with_labels(&self, labels: &[(&str, &str)]) -> MetricU64 {
+        self.metrics
+            .register_u64_key(MetricKey::new(self.name.clone(), labels), self.metric_type)
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricFamilyString {
+    metrics: Metrics,
+    name: String,
+}
+
+impl MetricFamilyString {
+    pub fn with_labels(&self, labels: &[(&str, &str)]) -> MetricString {
+        self.metrics
+            .register_string_key(MetricKey::new(self.name.clone(), labels))
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricFamilyHistogram {
+    metrics: Metrics,
+    name: String,
+    buckets: Vec<f64>,
+}
+
+impl MetricFamilyHistogram {
+    pub fn with_labels(&self, labels: &[(&str, &str)]) -> MetricHistogram {
+        self.metrics
+            .register_histogram_key(MetricKey::new(self.name.clone(), labels), &self.buckets)
+    }
+}
+
 #[derive(Clone)]
 pub struct Metrics {
-    registry: Arc<RwLock<HashMap<String, Value>>>,
+    registry: Arc<RwLock<HashMap<MetricKey, Value>>>,
 }
 
 impl Metrics {
-    pub fn register_u64(&self, name: String) -> MetricU64 {
+    /// Returns the value registered for key, registering it first if needed
+    fn register(&self, key: MetricKey, value: impl FnOnce() -> Value) -> Value {
         let mut registry = self.registry.write().unwrap();
-        let value = registry
-            .entry(name)
-            .or_insert(Value::U64(Arc::new(atomic::AtomicU64::new(0))));
+        registry.entry(key).or_insert_with(value).clone()
+    }
+
+    fn register_u64_key(&self, key: MetricKey, metric_type: MetricType) -> MetricU64 {
+        let value = self.register(key, || Value::U64 {
+            value: Arc::new(atomic::AtomicU64::new(0)),
+            metric_type,
+        });
         MetricU64 {
             value: match value {
-                Value::U64(v) => v.clone(),
+                Value::U64 { value: v, .. } => v,
                 _ => panic!("bad metric type"),
             },
         }
     }
 
-    pub fn register_i64(&self, name: String) -> MetricI64 {
-        let mut registry = self.registry.write().unwrap();
-        let value = registry
-            .entry(name)
-            .or_insert(Value::I64(Arc::new(atomic::AtomicI64::new(0))));
-        MetricI64 {
+    fn register_string_key(&self, key: MetricKey) -> MetricString {
+        let value = self.register(key, || Value::String(Arc::new(Mutex::new(String::new()))));
+        MetricString {
             value: match value {
-                Value::I64(v) => v.clone(),
+                Value::String(v) => v,
                 _ => panic!("bad metric type"),
             },
         }
     }
 
-    pub fn register_string(&self, name: String) -> MetricString {
-        let mut registry = self.registry.write().unwrap();
-        let value = registry
-            .entry(name)
-            .or_insert(Value::String(Arc::new(Mutex::new(String::new()))));
-        MetricString {
+    fn register_histogram_key(&self, key: MetricKey, buckets: &[f64]) -> MetricHistogram {
+        let value = self.register(key, || {
+            Value::Histogram(Arc::new(Mutex::new(HistogramState::new(buckets))))
+        });
+        MetricHistogram {
             value: match value {
-                Value::String(v) => v.clone(),
+                Value::Histogram(v) => v,
                 _ => panic!("bad metric type"),
             },
         }
     }
+
+    pub fn register_u64(&self, name: String, metric_type: MetricType) -> MetricU64 {
+        self.register_u64_key(MetricKey::new(name, &[]), metric_type)
+    }
+
+    pub fn register_u64_family(&self, name: String, metric_type: MetricType) -> MetricFamilyU64 {
+        MetricFamilyU64 {
+            metrics: self.clone(),
+            name,
+            metric_type,
+        }
+    }
+
+    pub fn register_i64(&self, name: String) -> MetricI64 {
+        let value = self.register(MetricKey::new(name, &[]), || {
+            Value::I64(Arc::new(atomic::AtomicI64::new(0)))
+        });
+        MetricI64 {
+            value: match value {
+                Value::I64(v) => v,
+                _ => panic!("bad metric type"),
+            },
+        }
+    }
+
+    pub fn register_string(&self, name: String) -> MetricString {
+        self.register_string_key(MetricKey::new(name, &[]))
+    }
+
+    pub fn register_string_family(&self, name: String) -> MetricFamilyString {
+        MetricFamilyString {
+            metrics: self.clone(),
+            name,
+        }
+    }
+
+    /// Registers a histogram with the given ascending bucket upper bounds
+    pub fn register_histogram(&self, name: String, buckets: &[f64]) -> MetricHistogram {
+        self.register_histogram_key(MetricKey::new(name, &[]), buckets)
+    }
+
+    pub fn register_histogram_family(
+        &self,
+        name: String,
+        buckets: &[f64],
+    ) -> MetricFamilyHistogram {
+        MetricFamilyHistogram {
+            metrics: self.clone(),
+            name,
+            buckets: buckets.to_vec(),
+        }
+    }
+}
+
+/// Prometheus metric names may only contain [a-zA-Z0-9_:]
+fn prometheus_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
 }
 
-pub fn start() -> Metrics {
+fn prometheus_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders a label set like `{source="a",le="0.5"}`, or nothing if there are no labels
+fn prometheus_labels(labels: &[(String, String)], extra: Option<(&str, &str)>) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .chain(extra)
+        .map(|(k, v)| format!("{}=\"{}\"", prometheus_name(k), prometheus_escape(v)))
+        .collect();
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+/// Renders all metrics in the Prometheus text exposition format
+///
+/// String metrics become gauges with the current value as a label, like
+/// `grpc_source_status{source="a",value="connected"} 1`.
+fn render_prometheus(registry: &HashMap<MetricKey, Value>) -> String {
+    let mut keys: Vec<&MetricKey> = registry.keys().collect();
+    keys.sort();
+
+    let mut out = String::new();
+    let mut previous_name = None;
+    for key in keys {
+        let name = prometheus_name(&key.name);
+        let value = &registry[key];
+        if previous_name != Some(&key.name) {
+            let prom_type = match value {
+                Value::U64 {
+                    metric_type: MetricType::Counter,
+                    ..
+                } => "counter",
+                Value::Histogram(_) => "histogram",
+                _ => "gauge",
+            };
+            let _ = writeln!(out, "# TYPE {} {}", name, prom_type);
+            previous_name = Some(&key.name);
+        }
+
+        let labels = prometheus_labels(&key.labels, None);
+        let _ = match value {
+            Value::U64 { value, .. } => writeln!(
+                out,
+                "{}{} {}",
+                name,
+                labels,
+                value.load(atomic::Ordering::Acquire)
+            ),
+            Value::I64(value) => writeln!(
+                out,
+                "{}{} {}",
+                name,
+                labels,
+                value.load(atomic::Ordering::Acquire)
+            ),
+            Value::String(value) => writeln!(
+                out,
+                "{}{} 1",
+                name,
+                prometheus_labels(&key.labels, Some(("value", &value.lock().unwrap())))
+            ),
+            Value::Histogram(value) => {
+                let state = value.lock().unwrap();
+                let mut cumulative = 0;
+                for (bound, count) in state.bounds.iter().zip(state.counts.iter()) {
+                    cumulative += count;
+                    let _ = writeln!(
+                        out,
+                        "{}_bucket{} {}",
+                        name,
+                        prometheus_labels(&key.labels, Some(("le", &bound.to_string()))),
+                        cumulative
+                    );
+                }
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    name,
+                    prometheus_labels(&key.labels, Some(("le", "+Inf"))),
+                    state.count
+                );
+                let _ = writeln!(out, "{}_sum{} {}", name, labels, state.sum);
+                writeln!(out, "{}_count{} {}", name, labels, state.count)
+            }
+        };
+    }
+    out
+}
+
+fn start_prometheus_server(
+    bind_address: &str,
+    registry: Arc<RwLock<HashMap<MetricKey, Value>>>,
+) -> anyhow::Result<()> {
+    let addr: SocketAddr = bind_address.parse()?;
+    let make_service = make_service_fn(move |_| {
+        let registry = registry.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = if request.uri().path() == "/metrics" {
+                    let body = render_prometheus(&registry.read().unwrap());
+                    Response::new(Body::from(body))
+                } else {
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::NOT_FOUND;
+                    response
+                };
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    info!("serving prometheus metrics on http://{}/metrics", addr);
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            error!("prometheus metrics server error: {:?}", err);
+        }
+    });
+    Ok(())
+}
+
+pub fn start(config: MetricsConfig) -> anyhow::Result<Metrics> {
     let mut write_interval = time::interval(time::Duration::from_secs(60));
 
-    let registry = Arc::new(RwLock::new(HashMap::<String, Value>::new()));
+    let registry = Arc::new(RwLock::new(HashMap::<MetricKey, Value>::new()));
     let registry_c = Arc::clone(&registry);
 
+    if let Some(bind_address) = &config.prometheus_bind_address {
+        start_prometheus_server(bind_address, registry.clone())?;
+    }
+    if !config.output_stdout {
+        return Ok(Metrics { registry });
+    }
+
     tokio::spawn(async move {
-        let mut previous_values = HashMap::<String, PrevValue>::new();
+        let mut previous_values = HashMap::<MetricKey, PrevValue>::new();
         loop {
             write_interval.tick().await;
 
@@ -141,7 +511,7 @@ pub fn start() -> Metrics {
             for (name, value) in metrics.iter() {
                 let previous_value = previous_values.get_mut(name);
                 match value {
-                    Value::U64(v) => {
+                    Value::U64 { value: v, .. } => {
                         let new_value = v.load(atomic::Ordering::Acquire);
                         let previous_value = if let Some(PrevValue::U64(v)) = previous_value {
                             let prev = *v;
@@ -187,10 +557,39 @@ pub fn start() -> Metrics {
                             );
                         }
                     }
+                    Value::Histogram(v) => {
+                        let state = v.lock().unwrap();
+                        let previous_value = if let Some(PrevValue::Histogram(v)) = previous_value {
+                            let prev = *v;
+                            *v = state.count;
+                            prev
+                        } else {
+                            previous_values.insert(name.clone(), PrevValue::Histogram(state.count));
+                            0
+                        };
+                        let mean = if state.count > 0 {
+                            state.sum / state.count as f64
+                        } else {
+                            0.0
+                        };
+                        let p99 = match state.quantile_bound(0.99) {
+                            Some(bound) => format!("<= {}", bound),
+                            None if state.count > 0 => "> largest bucket".to_string(),
+                            None => "-".to_string(),
+                        };
+                        info!(
+                            "metric: {}: count {} ({:+}), mean {:.6}, p99 {}",
+                            name,
+                            state.count,
+                            state.count.wrapping_sub(previous_value) as i64,
+                            mean,
+                            p99
+                        );
+                    }
                 }
             }
         }
     });
 
-    Metrics { registry }
+    Ok(Metrics { registry })
 }
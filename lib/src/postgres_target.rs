@@ -3,16 +3,25 @@ use log::*;
 use native_tls::TlsConnector;
 use postgres_native_tls::MakeTlsConnector;
 use postgres_query::{query, query_dyn};
-use std::{collections::HashMap, convert::TryFrom, time::Duration};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    time::{Duration, Instant, SystemTime},
+};
 
-use crate::{metrics, AccountTables, AccountWrite, PostgresConfig, SlotStatus, SlotUpdate};
+use crate::{
+    encode_address, metrics, AccountTables, AccountWrite, BlockMetadata, PostgresConfig,
+    RewardType, SlotStatus, SlotUpdate, TransactionUpdate,
+};
 
 mod pg {
     #[derive(Clone, Copy, Debug, PartialEq, postgres_types::ToSql)]
     pub enum SlotStatus {
         Rooted,
         Confirmed,
+        Frozen,
         Processed,
+        Dead,
     }
 
     impl From<super::SlotStatus> for SlotStatus {
@@ -20,12 +29,36 @@ mod pg {
             match status {
                 super::SlotStatus::Rooted => SlotStatus::Rooted,
                 super::SlotStatus::Confirmed => SlotStatus::Confirmed,
+                super::SlotStatus::Frozen => SlotStatus::Frozen,
                 super::SlotStatus::Processed => SlotStatus::Processed,
+                super::SlotStatus::Dead => SlotStatus::Dead,
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, postgres_types::ToSql)]
+    pub enum RewardType {
+        Fee,
+        Rent,
+        Staking,
+        Voting,
+    }
+
+    impl From<super::RewardType> for RewardType {
+        fn from(reward_type: super::RewardType) -> RewardType {
+            match reward_type {
+                super::RewardType::Fee => RewardType::Fee,
+                super::RewardType::Rent => RewardType::Rent,
+                super::RewardType::Staking => RewardType::Staking,
+                super::RewardType::Voting => RewardType::Voting,
             }
         }
     }
 }
 
+/// Histogram buckets for the number of account writes per batch
+const BATCH_SIZE_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0];
+
 async fn postgres_connection(
     config: &PostgresConfig,
     metric_retries: metrics::MetricU64,
@@ -105,21 +138,110 @@ async fn process_account_write(
     client: &postgres_query::Caching<tokio_postgres::Client>,
     write: &AccountWrite,
     account_tables: &AccountTables,
+    metric_durations: &[metrics::MetricHistogram],
 ) -> anyhow::Result<()> {
-    futures::future::try_join_all(
-        account_tables
-            .iter()
-            .map(|table| table.insert_account_write(client, write)),
-    )
+    futures::future::try_join_all(account_tables.iter().zip(metric_durations).map(
+        |(table, metric_duration)| async move {
+            let start = Instant::now();
+            let result = table.insert_account_write(client, write).await;
+            metric_duration.observe_duration(start.elapsed());
+            result
+        },
+    ))
     .await?;
     Ok(())
 }
 
+async fn process_transaction(
+    client: &postgres_query::Caching<tokio_postgres::Client>,
+    transaction: &TransactionUpdate,
+) -> anyhow::Result<()> {
+    let signature = transaction.signature.to_string();
+    let slot = transaction.slot as i64;
+    let account_keys: Vec<String> = transaction
+        .account_keys
+        .iter()
+        .map(encode_address)
+        .collect();
+    let query = query!(
+        "INSERT INTO transaction
+            (signature, slot, err, account_keys, log_messages)
+        VALUES
+            ($signature, $slot, $err, $account_keys, $log_messages)
+        ON CONFLICT (signature, slot) DO NOTHING",
+        signature,
+        slot,
+        err = transaction.err,
+        account_keys,
+        log_messages = transaction.log_messages,
+    );
+    let _ = query
+        .execute(client)
+        .await
+        .context("inserting transaction")?;
+    Ok(())
+}
+
+async fn process_block_rewards(
+    client: &postgres_query::Caching<tokio_postgres::Client>,
+    block_metadata: &BlockMetadata,
+) -> anyhow::Result<()> {
+    let slot = block_metadata.slot as i64;
+
+    // Replaces the rewards from an earlier attempt or another source
+    let query = query!("DELETE FROM block_reward WHERE slot = $slot", slot);
+    let _ = query
+        .execute(client)
+        .await
+        .context("deleting block rewards")?;
+    if block_metadata.rewards.is_empty() {
+        return Ok(());
+    }
+    let rewards = &block_metadata.rewards;
+    let pubkeys: Vec<String> = rewards
+        .iter()
+        .map(|reward| encode_address(&reward.pubkey))
+        .collect();
+    let lamports: Vec<i64> = rewards.iter().map(|reward| reward.lamports).collect();
+    let post_balances: Vec<i64> = rewards
+        .iter()
+        .map(|reward| reward.post_balance as i64)
+        .collect();
+    let reward_types: Vec<Option<pg::RewardType>> = rewards
+        .iter()
+        .map(|reward| reward.reward_type.map(pg::RewardType::from))
+        .collect();
+    let commissions: Vec<Option<i16>> = rewards
+        .iter()
+        .map(|reward| reward.commission.map(i16::from))
+        .collect();
+    let query = query!(
+        "INSERT INTO block_reward
+            (slot, pubkey, lamports, post_balance, reward_type, commission)
+        SELECT $slot, * FROM unnest(
+            $pubkeys::VARCHAR(44)[], $lamports::BIGINT[], $post_balances::BIGINT[],
+            $reward_types::\"RewardType\"[], $commissions::SMALLINT[])",
+        slot,
+        pubkeys,
+        lamports,
+        post_balances,
+        reward_types,
+        commissions,
+    );
+    let _ = query
+        .execute(client)
+        .await
+        .context("inserting block rewards")?;
+    Ok(())
+}
+
 struct Slots {
     // non-rooted only
     slots: HashMap<u64, SlotUpdate>,
     newest_processed_slot: Option<u64>,
     newest_rooted_slot: Option<u64>,
+    // block metadata of slots without a slot update yet
+    pending_blocks: HashMap<u64, BlockMetadata>,
 }
 
 #[derive(Default)]
@@ -129,6 +251,10 @@ struct SlotPreprocessing {
     new_processed_head: bool,
     new_rooted_head: bool,
     parent_update: bool,
+    /// Block metadata to store with the slot
+    block: Option<BlockMetadata>,
+    /// Only store the block metadata, leave the slot's status and parent alone
+    block_only: bool,
 }
 
 impl Slots {
@@ -137,16 +263,35 @@ impl Slots {
             slots: HashMap::new(),
             newest_processed_slot: None,
             newest_rooted_slot: None,
+            pending_blocks: HashMap::new(),
         }
     }
 
-    fn add(&mut self, update: &SlotUpdate) -> SlotPreprocessing {
+    /// Marks the update as dead when its parent is
+    fn add(&mut self, update: &mut SlotUpdate) -> SlotPreprocessing {
         let mut result = SlotPreprocessing::default();
 
+        // Descendants of dead slots die with them
+        if update
+            .parent
+            .and_then(|parent| self.slots.get(&parent))
+            .map(|parent| parent.status == SlotStatus::Dead)
+            .unwrap_or(false)
+        {
+            update.status = SlotStatus::Dead;
+        }
+
         if let Some(previous) = self.slots.get_mut(&update.slot) {
             if previous.status == update.status && previous.parent == update.parent {
                 result.discard_duplicate = true;
             }
+            // Dead slots stay dead and a late Frozen must not undo a confirmation
+            if previous.status == SlotStatus::Dead
+                || (update.status == SlotStatus::Frozen && previous.status != SlotStatus::Processed)
+            {
+                result.discard_duplicate = true;
+                return result;
+            }
 
             previous.status = update.status;
             if update.parent.is_some() && previous.parent != update.parent {
@@ -157,6 +302,8 @@ impl Slots {
             || update.slot > self.newest_rooted_slot.unwrap()
         {
             self.slots.insert(update.slot, update.clone());
+            // the block metadata may have arrived first
+            result.block = self.pending_blocks.remove(&update.slot);
         } else {
             result.discard_old = true;
         }
@@ -171,13 +318,25 @@ impl Slots {
             for old_slot in old_slots {
                 self.slots.remove(&old_slot);
             }
+            self.pending_blocks.retain(|slot, _| *slot > update.slot);
             if self.newest_rooted_slot.is_none() || self.newest_rooted_slot.unwrap() < update.slot {
                 self.newest_rooted_slot = Some(update.slot);
                 result.new_rooted_head = true;
             }
         }
 
-        if self.newest_processed_slot.is_none() || self.newest_processed_slot.unwrap() < update.slot
+        if update.status == SlotStatus::Dead {
+            self.mark_dead_descendants(update.slot);
+            // A dead slot never becomes the head of the chain
+            self.newest_processed_slot = self
+                .slots
+                .values()
+                .filter(|s| s.status != SlotStatus::Dead)
+                .map(|s| s.slot)
+                .max()
+                .or(self.newest_rooted_slot);
+        } else if self.newest_processed_slot.is_none()
+            || self.newest_processed_slot.unwrap() < update.slot
         {
             self.newest_processed_slot = Some(update.slot);
             result.new_processed_head = true;
@@ -185,9 +344,58 @@ impl Slots {
 
         result
     }
+
+    /// The slot update that stores the block metadata with its slot, or None if
+    /// that has to wait for the slot's first update
+    ///
+    /// Metadata of rooted and dead slots is dropped. The validator sends it
+    /// when the block is frozen, long before it is rooted.
+    fn add_block(&mut self, block: BlockMetadata) -> Option<(SlotUpdate, SlotPreprocessing)> {
+        if let Some(slot) = self.slots.get(&block.slot) {
+            if slot.status == SlotStatus::Dead {
+                return None;
+            }
+            // the latency was measured with the slot's own updates
+            let update = SlotUpdate {
+                send_timestamp: None,
+                receive_timestamp: None,
+                ..slot.clone()
+            };
+            let preprocessing = SlotPreprocessing {
+                block: Some(block),
+                block_only: true,
+                ..SlotPreprocessing::default()
+            };
+            return Some((update, preprocessing));
+        }
+        if self
+            .newest_rooted_slot
+            .map(|rooted| block.slot > rooted)
+            .unwrap_or(true)
+        {
+            self.pending_blocks.insert(block.slot, block);
+        }
+        None
+    }
+
+    fn mark_dead_descendants(&mut self, slot: u64) {
+        let mut dead_slots = vec![slot];
+        while let Some(dead_slot) = dead_slots.pop() {
+            for s in self.slots.values_mut() {
+                if s.parent == Some(dead_slot) && s.status != SlotStatus::Dead {
+                    s.status = SlotStatus::Dead;
+                    dead_slots.push(s.slot);
+                }
+            }
+        }
+    }
 }
 
-fn make_cleanup_steps(tables: &Vec<String>) -> HashMap<String, String> {
+fn make_cleanup_steps(
+    tables: &Vec<String>,
+    transactions: bool,
+    block_rewards: bool,
+) -> HashMap<String, String> {
     let mut steps = HashMap::<String, String>::new();
 
     // Delete all account writes that came before the newest rooted slot except
@@ -242,6 +450,17 @@ fn make_cleanup_steps(tables: &Vec<String>) -> HashMap<String, String> {
             .collect::<HashMap<String, String>>(),
     );
 
+    // Delete writes in dead slots right away, they will never become rooted
+    steps.extend(tables.iter().map(|table_name| {
+        let sql = format!(
+            "DELETE FROM {table} AS data
+            USING slot
+            WHERE data.slot = slot.slot AND slot.status = 'Dead'",
+            table = table_name,
+        );
+        (format!("delete dead writes in {}", table_name), sql)
+    }));
+
     // Delete information about older slots
     steps.insert(
         "delete old slots".into(),
@@ -251,15 +470,43 @@ fn make_cleanup_steps(tables: &Vec<String>) -> HashMap<String, String> {
             .into(),
     );
 
+    // Delete transactions from slots that didn't make it into the rooted chain
+    if transactions {
+        steps.insert(
+            "delete uncle transactions".into(),
+            "DELETE FROM transaction
+             USING slot, (SELECT max(slot) as newest_rooted_slot FROM slot WHERE status = 'Rooted') s
+             WHERE transaction.slot = slot.slot
+             AND (slot.status = 'Dead' OR (slot.uncle AND slot.slot < newest_rooted_slot))"
+                .into(),
+        );
+    }
+
+    // Same for block rewards
+    if block_rewards {
+        steps.insert(
+            "delete uncle block rewards".into(),
+            "DELETE FROM block_reward
+             USING slot, (SELECT max(slot) as newest_rooted_slot FROM slot WHERE status = 'Rooted') s
+             WHERE block_reward.slot = slot.slot
+             AND (slot.status = 'Dead' OR (slot.uncle AND slot.slot < newest_rooted_slot))"
+                .into(),
+        );
+    }
+
     steps
 }
 
 #[derive(Clone)]
-struct SlotsProcessing {}
+struct SlotsProcessing {
+    store_block_rewards: bool,
+}
 
 impl SlotsProcessing {
-    fn new() -> Self {
-        Self {}
+    fn new(store_block_rewards: bool) -> Self {
+        Self {
+            store_block_rewards,
+        }
     }
 
     async fn process(
@@ -268,20 +515,28 @@ impl SlotsProcessing {
         update: &SlotUpdate,
         meta: &SlotPreprocessing,
     ) -> anyhow::Result<()> {
+        if meta.block_only {
+            let block = meta.block.as_ref().expect("block_only comes with a block");
+            return self.process_block(client, update, block).await;
+        }
+
         let slot = update.slot as i64;
         let status: pg::SlotStatus = update.status.into();
+        // Dead slots are uncles right away
+        let uncle = update.status == SlotStatus::Dead;
         if let Some(parent) = update.parent {
             let parent = parent as i64;
             let query = query!(
                 "INSERT INTO slot
                     (slot, parent, status, uncle)
                 VALUES
-                    ($slot, $parent, $status, FALSE)
+                    ($slot, $parent, $status, $uncle)
                 ON CONFLICT (slot) DO UPDATE SET
-                    parent=$parent, status=$status",
+                    parent=$parent, status=$status, uncle=slot.uncle OR $uncle",
                 slot,
                 parent,
                 status,
+                uncle,
             );
             let _ = query.execute(client).await.context("updating slot row")?;
         } else {
@@ -289,15 +544,20 @@ impl SlotsProcessing {
                 "INSERT INTO slot
                     (slot, parent, status, uncle)
                 VALUES
-                    ($slot, NULL, $status, FALSE)
+                    ($slot, NULL, $status, $uncle)
                 ON CONFLICT (slot) DO UPDATE SET
-                    status=$status",
+                    status=$status, uncle=slot.uncle OR $uncle",
                 slot,
                 status,
+                uncle,
             );
             let _ = query.execute(client).await.context("updating slot row")?;
         }
 
+        if let Some(block) = &meta.block {
+            self.process_block(client, update, block).await?;
+        }
+
         if meta.new_rooted_head {
             let slot = update.slot as i64;
             // Mark preceeding non-uncle slots as rooted
@@ -305,7 +565,8 @@ impl SlotsProcessing {
                 "UPDATE slot SET status = 'Rooted'
                 WHERE slot < $newest_final_slot
                 AND (NOT uncle)
-                AND status != 'Rooted'",
+                AND status != 'Rooted'
+                AND status != 'Dead'",
                 newest_final_slot = slot
             );
             let _ = query
@@ -314,14 +575,36 @@ impl SlotsProcessing {
                 .context("updating preceding non-rooted slots")?;
         }
 
-        if meta.new_processed_head || meta.parent_update {
+        if update.status == SlotStatus::Dead {
+            // their descendants are dead too
+            let query = query!(
+                "WITH RECURSIVE
+                    descendants AS (
+                        SELECT slot FROM slot WHERE parent = $slot
+                        UNION
+                        SELECT s.slot FROM slot s
+                            INNER JOIN descendants d ON s.parent = d.slot
+                    )
+                UPDATE slot SET status = 'Dead', uncle = TRUE
+                    FROM descendants
+                    WHERE slot.slot = descendants.slot AND slot.status != 'Rooted'",
+                slot,
+            );
+            let _ = query
+                .execute(client)
+                .await
+                .context("marking descendants of dead slot")?;
+        }
+
+        if meta.new_processed_head || meta.parent_update || update.status == SlotStatus::Dead {
             // update the uncle column for the chain of slots from the
-            // newest down the the first rooted slot
+            // newest live slot down the the first rooted slot, dead slots and
+            // their descendants are not part of it
             let query = query!(
                 "WITH RECURSIVE
                     liveslots AS (
                         SELECT slot.*, 0 AS depth FROM slot
-                            WHERE slot = (SELECT max(slot) FROM slot)
+                            WHERE slot = (SELECT max(slot) FROM slot WHERE status != 'Dead')
                         UNION ALL
                         SELECT s.*, depth + 1 FROM slot s
                             INNER JOIN liveslots l ON s.slot = l.parent
@@ -342,6 +625,48 @@ impl SlotsProcessing {
         trace!("slot update done {}", update.slot);
         Ok(())
     }
+
+    /// Stores the block metadata in the slot's row
+    ///
+    /// Another worker may not have inserted the row yet, or may be writing a
+    /// newer status. The row is inserted as the update has it if it is
+    /// missing, an existing row only gets the block columns.
+    async fn process_block(
+        &self,
+        client: &postgres_query::Caching<tokio_postgres::Client>,
+        update: &SlotUpdate,
+        block: &BlockMetadata,
+    ) -> anyhow::Result<()> {
+        let slot = update.slot as i64;
+        let parent = update.parent.map(|parent| parent as i64);
+        let status: pg::SlotStatus = update.status.into();
+        let uncle = update.status == SlotStatus::Dead;
+        let block_time = block.block_time.map(unix_timestamp_to_time);
+        let block_height = block.block_height.map(|height| height as i64);
+        let query = query!(
+            "INSERT INTO slot
+                (slot, parent, status, uncle, blockhash, block_time, block_height)
+            VALUES
+                ($slot, $parent, $status, $uncle, $blockhash, $block_time, $block_height)
+            ON CONFLICT (slot) DO UPDATE SET
+                blockhash=$blockhash, block_time=$block_time, block_height=$block_height",
+            slot,
+            parent,
+            status,
+            uncle,
+            blockhash = block.blockhash,
+            block_time,
+            block_height,
+        );
+        let _ = query
+            .execute(client)
+            .await
+            .context("updating slot block metadata")?;
+        if self.store_block_rewards {
+            process_block_rewards(client, block).await?;
+        }
+        Ok(())
+    }
 }
 
 fn secs_since_epoch() -> u64 {
@@ -354,6 +679,15 @@ fn epoch_secs_to_time(secs: u64) -> std::time::SystemTime {
     std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs)
 }
 
+/// Like epoch_secs_to_time, for timestamps that may be before the epoch
+fn unix_timestamp_to_time(secs: i64) -> std::time::SystemTime {
+    if secs >= 0 {
+        epoch_secs_to_time(secs as u64)
+    } else {
+        std::time::UNIX_EPOCH - std::time::Duration::from_secs(secs.unsigned_abs())
+    }
+}
+
 pub async fn init(
     config: &PostgresConfig,
     account_tables: AccountTables,
@@ -361,6 +695,8 @@ pub async fn init(
 ) -> anyhow::Result<(
     async_channel::Sender<AccountWrite>,
     async_channel::Sender<SlotUpdate>,
+    async_channel::Sender<BlockMetadata>,
+    Option<async_channel::Sender<TransactionUpdate>>,
 )> {
     // The actual message may want to also contain a retry count, if it self-reinserts on failure?
     let (account_write_queue_sender, account_write_queue_receiver) =
@@ -369,11 +705,48 @@ pub async fn init(
     // Slot updates flowing from the outside into the single processing thread. From
     // there they'll flow into the postgres sending thread.
     let (slot_queue_sender, slot_queue_receiver) = async_channel::unbounded::<SlotUpdate>();
+    // Block metadata joins the slot updates there, to be stored with its slot
+    let (block_metadata_queue_sender, block_metadata_queue_receiver) =
+        async_channel::unbounded::<BlockMetadata>();
     let (slot_inserter_sender, slot_inserter_receiver) =
         async_channel::unbounded::<(SlotUpdate, SlotPreprocessing)>();
 
-    let metric_con_retries = metrics_sender.register_u64("postgres_connection_retries".into());
-    let metric_con_live = metrics_sender.register_u64("postgres_connections_alive".into());
+    let metric_con_retries = metrics_sender.register_u64(
+        "postgres_connection_retries".into(),
+        metrics::MetricType::Counter,
+    );
+    let metric_con_live = metrics_sender.register_u64(
+        "postgres_connections_alive".into(),
+        metrics::MetricType::Gauge,
+    );
+    // labeled by account table name, or by the kind of update for other queries
+    let metric_query_durations = metrics_sender.register_histogram_family(
+        "postgres_query_duration_seconds".into(),
+        metrics::LATENCY_BUCKETS,
+    );
+    let metric_batch_size = metrics_sender.register_histogram(
+        "postgres_account_write_batch_size".into(),
+        BATCH_SIZE_BUCKETS,
+    );
+    // Time from the connector receiving an account write to it being committed
+    let metric_commit_latency = metrics_sender.register_histogram(
+        "postgres_account_write_commit_latency_seconds".into(),
+        metrics::LATENCY_BUCKETS,
+    );
+    // Time from the source sending an account write to it being committed
+    let metric_end_to_end_latency = metrics_sender.register_histogram(
+        "postgres_account_write_end_to_end_latency_seconds".into(),
+        metrics::LATENCY_BUCKETS,
+    );
+    // The same for slot updates
+    let metric_slot_commit_latency = metrics_sender.register_histogram(
+        "postgres_slot_update_commit_latency_seconds".into(),
+        metrics::LATENCY_BUCKETS,
+    );
+    let metric_slot_end_to_end_latency = metrics_sender.register_histogram(
+        "postgres_slot_update_end_to_end_latency_seconds".into(),
+        metrics::LATENCY_BUCKETS,
+    );
 
     // postgres account write sending worker threads
     for _ in 0..config.account_write_connection_count {
@@ -383,10 +756,21 @@ pub async fn init(
         let account_write_queue_receiver_c = account_write_queue_receiver.clone();
         let account_tables_c = account_tables.clone();
         let config = config.clone();
-        let mut metric_retries =
-            metrics_sender.register_u64("postgres_account_write_retries".into());
-        let mut metric_last_write =
-            metrics_sender.register_u64("postgres_account_write_last_write_timestamp".into());
+        let mut metric_retries = metrics_sender.register_u64(
+            "postgres_account_write_retries".into(),
+            metrics::MetricType::Counter,
+        );
+        let mut metric_last_write = metrics_sender.register_u64(
+            "postgres_account_write_last_write_timestamp".into(),
+            metrics::MetricType::Gauge,
+        );
+        let metric_durations: Vec<_> = account_tables
+            .iter()
+            .map(|table| metric_query_durations.with_labels(&[("query", table.table_name())]))
+            .collect();
+        let metric_batch_size = metric_batch_size.clone();
+        let metric_commit_latency = metric_commit_latency.clone();
+        let metric_end_to_end_latency = metric_end_to_end_latency.clone();
         tokio::spawn(async move {
             let mut client_opt = None;
             loop {
@@ -413,18 +797,34 @@ pub async fn init(
                     write_batch.len(),
                     account_write_queue_receiver_c.len(),
                 );
+                metric_batch_size.observe(write_batch.len() as f64);
 
                 let mut error_count = 0;
                 loop {
                     let client =
                         update_postgres_client(&mut client_opt, &postgres_account_writes, &config)
                             .await;
-                    let mut results = futures::future::join_all(
-                        write_batch
-                            .iter()
-                            .map(|write| process_account_write(client, &write, &account_tables_c)),
-                    )
+                    let mut results = futures::future::join_all(write_batch.iter().map(|write| {
+                        process_account_write(client, &write, &account_tables_c, &metric_durations)
+                    }))
                     .await;
+                    let commit_timestamp = SystemTime::now();
+                    for (write, _) in write_batch.iter().zip(&results).filter(|(_, r)| r.is_ok()) {
+                        // clocks may be skewed, never report negative latencies
+                        let latency_since = |timestamp: SystemTime| {
+                            commit_timestamp
+                                .duration_since(timestamp)
+                                .unwrap_or_default()
+                        };
+                        if let Some(receive_timestamp) = write.receive_timestamp {
+                            metric_commit_latency
+                                .observe_duration(latency_since(receive_timestamp));
+                        }
+                        if let Some(send_timestamp) = write.send_timestamp {
+                            metric_end_to_end_latency
+                                .observe_duration(latency_since(send_timestamp));
+                        }
+                    }
                     let mut iter = results.iter();
                     write_batch.retain(|_| iter.next().unwrap().is_err());
                     if write_batch.len() > 0 {
@@ -449,26 +849,37 @@ pub async fn init(
     }
 
     // slot update handling thread
-    let mut metric_slot_queue = metrics_sender.register_u64("slot_insert_queue".into());
+    let mut metric_slot_queue =
+        metrics_sender.register_u64("slot_insert_queue".into(), metrics::MetricType::Gauge);
     tokio::spawn(async move {
         let mut slots = Slots::new();
 
         loop {
-            let update = slot_queue_receiver
-                .recv()
-                .await
-                .expect("sender must stay alive");
-            trace!(
-                "slot update {}, channel size {}",
-                update.slot,
-                slot_queue_receiver.len()
-            );
+            let (update, slot_preprocessing) = tokio::select! {
+                update = slot_queue_receiver.recv() => {
+                    let mut update = update.expect("sender must stay alive");
+                    trace!(
+                        "slot update {}, channel size {}",
+                        update.slot,
+                        slot_queue_receiver.len()
+                    );
 
-            // Check if we already know about the slot, or it is outdated
-            let slot_preprocessing = slots.add(&update);
-            if slot_preprocessing.discard_duplicate || slot_preprocessing.discard_old {
-                continue;
-            }
+                    // Check if we already know about the slot, or it is outdated
+                    let slot_preprocessing = slots.add(&mut update);
+                    if slot_preprocessing.discard_duplicate || slot_preprocessing.discard_old {
+                        continue;
+                    }
+                    (update, slot_preprocessing)
+                },
+                block_metadata = block_metadata_queue_receiver.recv() => {
+                    let block_metadata = block_metadata.expect("sender must stay alive");
+                    trace!("block metadata, slot {}", block_metadata.slot);
+                    match slots.add_block(block_metadata) {
+                        Some(update) => update,
+                        None => continue,
+                    }
+                },
+            };
 
             slot_inserter_sender
                 .send((update, slot_preprocessing))
@@ -479,16 +890,26 @@ pub async fn init(
     });
 
     // postgres slot update worker threads
-    let slots_processing = SlotsProcessing::new();
+    let slots_processing = SlotsProcessing::new(config.store_block_rewards);
     for _ in 0..config.slot_update_connection_count {
         let postgres_slot =
             postgres_connection(config, metric_con_retries.clone(), metric_con_live.clone())
                 .await?;
         let receiver_c = slot_inserter_receiver.clone();
         let config = config.clone();
-        let mut metric_retries = metrics_sender.register_u64("postgres_slot_update_retries".into());
-        let mut metric_last_write =
-            metrics_sender.register_u64("postgres_slot_last_write_timestamp".into());
+        let mut metric_retries = metrics_sender.register_u64(
+            "postgres_slot_update_retries".into(),
+            metrics::MetricType::Counter,
+        );
+        let mut metric_last_write = metrics_sender.register_u64(
+            "postgres_slot_last_write_timestamp".into(),
+            metrics::MetricType::Gauge,
+        );
+        let metric_duration = metric_query_durations.with_labels(&[("query", "slot_update")]);
+        let metric_block_duration =
+            metric_query_durations.with_labels(&[("query", "block_metadata")]);
+        let metric_commit_latency = metric_slot_commit_latency.clone();
+        let metric_end_to_end_latency = metric_slot_end_to_end_latency.clone();
         let slots_processing = slots_processing.clone();
         tokio::spawn(async move {
             let mut client_opt = None;
@@ -501,10 +922,16 @@ pub async fn init(
                 loop {
                     let client =
                         update_postgres_client(&mut client_opt, &postgres_slot, &config).await;
-                    if let Err(err) = slots_processing
+                    let start = Instant::now();
+                    let result = slots_processing
                         .process(client, &update, &preprocessing)
-                        .await
-                    {
+                        .await;
+                    if preprocessing.block_only {
+                        metric_block_duration.observe_duration(start.elapsed());
+                    } else {
+                        metric_duration.observe_duration(start.elapsed());
+                    }
+                    if let Err(err) = result {
                         metric_retries.increment();
                         error_count += 1;
                         if error_count - 1 < config.retry_query_max_count {
@@ -517,6 +944,19 @@ pub async fn init(
                             std::process::exit(1);
                         }
                     };
+                    let commit_timestamp = SystemTime::now();
+                    // clocks may be skewed, never report negative latencies
+                    let latency_since = |timestamp: SystemTime| {
+                        commit_timestamp
+                            .duration_since(timestamp)
+                            .unwrap_or_default()
+                    };
+                    if let Some(receive_timestamp) = update.receive_timestamp {
+                        metric_commit_latency.observe_duration(latency_since(receive_timestamp));
+                    }
+                    if let Some(send_timestamp) = update.send_timestamp {
+                        metric_end_to_end_latency.observe_duration(latency_since(send_timestamp));
+                    }
                     break;
                 }
                 metric_last_write.set_max(secs_since_epoch());
@@ -524,21 +964,86 @@ pub async fn init(
         });
     }
 
+    // postgres transaction worker threads
+    let transaction_queue_sender = if config.transaction_connection_count > 0 {
+        let (transaction_queue_sender, transaction_queue_receiver) =
+            async_channel::bounded::<TransactionUpdate>(config.account_write_max_queue_size);
+        for _ in 0..config.transaction_connection_count {
+            let postgres_transactions =
+                postgres_connection(config, metric_con_retries.clone(), metric_con_live.clone())
+                    .await?;
+            let receiver_c = transaction_queue_receiver.clone();
+            let config = config.clone();
+            let mut metric_retries = metrics_sender.register_u64(
+                "postgres_transaction_retries".into(),
+                metrics::MetricType::Counter,
+            );
+            let metric_duration = metric_query_durations.with_labels(&[("query", "transaction")]);
+            tokio::spawn(async move {
+                let mut client_opt = None;
+                loop {
+                    let transaction = receiver_c.recv().await.expect("sender must stay alive");
+                    trace!("transaction insertion, slot {}", transaction.slot);
+
+                    let mut error_count = 0;
+                    loop {
+                        let client = update_postgres_client(
+                            &mut client_opt,
+                            &postgres_transactions,
+                            &config,
+                        )
+                        .await;
+                        let start = Instant::now();
+                        let result = process_transaction(client, &transaction).await;
+                        metric_duration.observe_duration(start.elapsed());
+                        if let Err(err) = result {
+                            metric_retries.increment();
+                            error_count += 1;
+                            if error_count - 1 < config.retry_query_max_count {
+                                warn!("failed to process transaction, retrying: {:?}", err);
+                                tokio::time::sleep(Duration::from_secs(
+                                    config.retry_query_sleep_secs,
+                                ))
+                                .await;
+                                continue;
+                            } else {
+                                error!("failed to process transaction, exiting");
+                                std::process::exit(1);
+                            }
+                        };
+                        break;
+                    }
+                }
+            });
+        }
+        Some(transaction_queue_sender)
+    } else {
+        None
+    };
+
     // postgres cleanup thread
     if config.cleanup_interval_secs > 0 {
         let table_names: Vec<String> = account_tables
             .iter()
             .map(|table| table.table_name().to_string())
             .collect();
-        let cleanup_steps = make_cleanup_steps(&table_names);
+        let cleanup_steps = make_cleanup_steps(
+            &table_names,
+            config.transaction_connection_count > 0,
+            config.store_block_rewards,
+        );
 
         let postgres_con =
             postgres_connection(config, metric_con_retries.clone(), metric_con_live.clone())
                 .await?;
-        let mut metric_last_cleanup =
-            metrics_sender.register_u64("postgres_cleanup_last_success_timestamp".into());
-        let mut metric_cleanup_errors =
-            metrics_sender.register_u64("postgres_cleanup_errors".into());
+        let mut metric_last_cleanup = metrics_sender.register_u64(
+            "postgres_cleanup_last_success_timestamp".into(),
+            metrics::MetricType::Gauge,
+        );
+        let mut metric_cleanup_errors = metrics_sender.register_u64(
+            "postgres_cleanup_errors".into(),
+            metrics::MetricType::Counter,
+        );
         let config = config.clone();
         tokio::spawn(async move {
             let mut client_opt = None;
@@ -567,12 +1072,18 @@ pub async fn init(
         let postgres_con =
             postgres_connection(config, metric_con_retries.clone(), metric_con_live.clone())
                 .await?;
-        let metric_slot_last_write =
-            metrics_sender.register_u64("postgres_slot_last_write_timestamp".into());
-        let metric_account_write_last_write =
-            metrics_sender.register_u64("postgres_account_write_last_write_timestamp".into());
-        let metric_account_queue = metrics_sender.register_u64("account_write_queue".into());
-        let metric_slot_queue = metrics_sender.register_u64("slot_insert_queue".into());
+        let metric_slot_last_write = metrics_sender.register_u64(
+            "postgres_slot_last_write_timestamp".into(),
+            metrics::MetricType::Gauge,
+        );
+        let metric_account_write_last_write = metrics_sender.register_u64(
+            "postgres_account_write_last_write_timestamp".into(),
+            metrics::MetricType::Gauge,
+        );
+        let metric_account_queue =
+            metrics_sender.register_u64("account_write_queue".into(), metrics::MetricType::Gauge);
+        let metric_slot_queue =
+            metrics_sender.register_u64("slot_insert_queue".into(), metrics::MetricType::Gauge);
         let config = config.clone();
         tokio::spawn(async move {
             let mut client_opt = None;
@@ -617,5 +1128,77 @@ pub async fn init(
         });
     }
 
-    Ok((account_write_queue_sender, slot_queue_sender))
+    Ok((
+        account_write_queue_sender,
+        slot_queue_sender,
+        block_metadata_queue_sender,
+        transaction_queue_sender,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot_update(slot: u64, parent: Option<u64>, status: SlotStatus) -> SlotUpdate {
+        SlotUpdate {
+            slot,
+            parent,
+            status,
+            send_timestamp: Some(SystemTime::now()),
+            receive_timestamp: Some(SystemTime::now()),
+        }
+    }
+
+    fn block(slot: u64) -> BlockMetadata {
+        BlockMetadata {
+            slot,
+            blockhash: format!("hash{}", slot),
+            block_time: Some(1_650_000_000),
+            block_height: Some(slot),
+            rewards: vec![],
+        }
+    }
+
+    fn block_slot(preprocessing: &SlotPreprocessing) -> Option<u64> {
+        preprocessing.block.as_ref().map(|block| block.slot)
+    }
+
+    #[test]
+    fn test_block_metadata_with_slot() {
+        let mut slots = Slots::new();
+        slots.add(&mut slot_update(10, None, SlotStatus::Rooted));
+        slots.add(&mut slot_update(11, Some(10), SlotStatus::Processed));
+
+        // a known slot only gets its block columns written, without timestamps
+        let (update, preprocessing) = slots.add_block(block(11)).unwrap();
+        assert!(preprocessing.block_only);
+        assert_eq!(update.slot, 11);
+        assert_eq!(update.parent, Some(10));
+        assert_eq!(update.status, SlotStatus::Processed);
+        assert!(update.send_timestamp.is_none() && update.receive_timestamp.is_none());
+        assert_eq!(block_slot(&preprocessing), Some(11));
+        assert!(!preprocessing.new_processed_head && !preprocessing.new_rooted_head);
+
+        // metadata before the slot's first update waits for it
+        assert!(slots.add_block(block(12)).is_none());
+        assert_eq!(
+            block_slot(&slots.add(&mut slot_update(12, Some(11), SlotStatus::Processed))),
+            Some(12)
+        );
+        assert_eq!(
+            block_slot(&slots.add(&mut slot_update(12, Some(11), SlotStatus::Confirmed))),
+            None
+        );
+
+        // rooted slots and dead slots don't get it
+        assert!(slots.add_block(block(10)).is_none());
+        slots.add(&mut slot_update(13, Some(12), SlotStatus::Dead));
+        assert!(slots.add_block(block(13)).is_none());
+
+        // pending metadata is dropped once its slot can't get an update anymore
+        assert!(slots.add_block(block(14)).is_none());
+        slots.add(&mut slot_update(15, Some(12), SlotStatus::Rooted));
+        assert!(slots.pending_blocks.is_empty());
+    }
 }
@@ -0,0 +1,481 @@
+//! Recording of the updates that the gRPC sources receive, and a source that
+//! replays them
+//!
+//! Updates are recorded as the plugin sent them, before write versions are
+//! rewritten and deltas applied, and the replay processes them again.
+//!
+//! A recording starts with `MAGIC`, the number of sources as u32 and each
+//! source name as a u32 length followed by its bytes. Then one record follows
+//! per message:
+//!
+//! - kind: u8, `KIND_REQUEST`, `KIND_UPDATE` or `KIND_SNAPSHOT`
+//! - source index: u32
+//! - receive time in microseconds since the unix epoch: u64
+//! - length of the protobuf: u32
+//! - the protobuf: the `SubscribeRequest` of a new connection, an `Update`, or
+//!   an rpc snapshot stored as an `AccountsSnapshot` update
+//!
+//! All integers are little endian.
+
+use {
+    crate::{
+        grpc_plugin_source::{
+            account_from_write, geyser_proto, process_messages, Message, StreamMetrics, StreamState,
+        },
+        metrics,
+        snapshot::SnapshotData,
+        AccountWrite, BlockMetadata, SlotUpdate, Source, SourceConfig, TransactionUpdate,
+    },
+    log::*,
+    prost::Message as _,
+    std::{
+        fs::File,
+        io::{self, BufReader, BufWriter, Read, Write},
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    },
+};
+
+const MAGIC: &[u8; 8] = b"GEYSREC2";
+const KIND_UPDATE: u8 = 0;
+const KIND_SNAPSHOT: u8 = 1;
+const KIND_REQUEST: u8 = 2;
+
+/// Records that may wait for the writer thread before the sources wait too
+const RECORD_QUEUE_SIZE: usize = 10_000;
+
+fn to_us(timestamp: SystemTime) -> u64 {
+    timestamp
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+fn encode_snapshot(snapshot: &SnapshotData) -> geyser_proto::Update {
+    let accounts = snapshot
+        .accounts
+        .iter()
+        .map(|(pubkey, account)| geyser_proto::AccountWrite {
+            slot: snapshot.slot,
+            pubkey: pubkey.to_bytes().to_vec(),
+            lamports: account.lamports,
+            owner: account.owner.to_bytes().to_vec(),
+            executable: account.executable,
+            rent_epoch: account.rent_epoch,
+            data: account.data.clone(),
+            is_selected: true,
+            ..geyser_proto::AccountWrite::default()
+        })
+        .collect();
+    geyser_proto::Update {
+        update_oneof: Some(geyser_proto::update::UpdateOneof::AccountsSnapshot(
+            geyser_proto::AccountsSnapshot {
+                slot: snapshot.slot,
+                accounts,
+                is_last: true,
+            },
+        )),
+        ..geyser_proto::Update::default()
+    }
+}
+
+fn encode_record(kind: u8, source: u32, receive_timestamp: SystemTime, message: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(17 + message.len());
+    record.push(kind);
+    record.extend_from_slice(&source.to_le_bytes());
+    record.extend_from_slice(&to_us(receive_timestamp).to_le_bytes());
+    record.extend_from_slice(&(message.len() as u32).to_le_bytes());
+    record.extend_from_slice(message);
+    record
+}
+
+enum WriterCommand {
+    Record(Vec<u8>),
+    /// Answers once everything before it is written to the file
+    Flush(async_channel::Sender<()>),
+}
+
+/// Writes the updates of the gRPC sources to a recording file from a separate
+/// thread
+///
+/// No record is dropped: when the writer falls behind, the sources wait for it.
+/// After a write error the writer stops and the recording ends early.
+#[derive(Clone)]
+pub struct Recorder {
+    path: String,
+    sender: async_channel::Sender<WriterCommand>,
+}
+
+impl Recorder {
+    pub fn create(path: &str, source_names: &[String]) -> anyhow::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(source_names.len() as u32).to_le_bytes())?;
+        for name in source_names {
+            writer.write_all(&(name.len() as u32).to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+        }
+        writer.flush()?;
+
+        let (sender, receiver) = async_channel::bounded::<WriterCommand>(RECORD_QUEUE_SIZE);
+        let path_c = path.to_string();
+        std::thread::spawn(move || {
+            while let Ok(command) = futures::executor::block_on(receiver.recv()) {
+                let result = match command {
+                    WriterCommand::Record(record) => writer.write_all(&record).and_then(|()| {
+                        if receiver.is_empty() {
+                            writer.flush()?;
+                        }
+                        Ok(())
+                    }),
+                    WriterCommand::Flush(done) => writer.flush().map(|()| {
+                        let _ = done.try_send(());
+                    }),
+                };
+                if let Err(err) = result {
+                    error!(
+                        "could not write recording {}, stopping, it is incomplete: {:?}",
+                        path_c, err
+                    );
+                    return;
+                }
+            }
+        });
+        info!("recording source updates to {}", path);
+        Ok(Recorder {
+            path: path.to_string(),
+            sender,
+        })
+    }
+
+    async fn record(&mut self, record: Vec<u8>) {
+        // fails only after the writer thread stopped on an error
+        let _ = self.sender.send(WriterCommand::Record(record)).await;
+    }
+
+    /// Waits until everything recorded so far is written to the file
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let (done_sender, done_receiver) = async_channel::bounded(1);
+        let stopped = || anyhow::anyhow!("recording {} stopped after a write error", self.path);
+        self.sender
+            .send(WriterCommand::Flush(done_sender))
+            .await
+            .map_err(|_| stopped())?;
+        done_receiver.recv().await.map_err(|_| stopped())
+    }
+
+    /// A source connected with this request
+    pub(crate) async fn record_request(
+        &mut self,
+        source: usize,
+        request: &geyser_proto::SubscribeRequest,
+    ) {
+        let record = encode_record(
+            KIND_REQUEST,
+            source as u32,
+            SystemTime::now(),
+            &request.encode_to_vec(),
+        );
+        self.record(record).await;
+    }
+
+    pub(crate) async fn record_update(
+        &mut self,
+        source: usize,
+        update: &geyser_proto::Update,
+        receive_timestamp: SystemTime,
+    ) {
+        let record = encode_record(
+            KIND_UPDATE,
+            source as u32,
+            receive_timestamp,
+            &update.encode_to_vec(),
+        );
+        self.record(record).await;
+    }
+
+    /// A snapshot or resync from rpc
+    pub(crate) async fn record_snapshot(&mut self, snapshot: &SnapshotData) {
+        let record = encode_record(
+            KIND_SNAPSHOT,
+            u32::MAX,
+            SystemTime::now(),
+            &encode_snapshot(snapshot).encode_to_vec(),
+        );
+        self.record(record).await;
+    }
+}
+
+enum Record {
+    Request {
+        source: usize,
+        request: geyser_proto::SubscribeRequest,
+    },
+    Update {
+        source: usize,
+        update: geyser_proto::Update,
+    },
+    Snapshot(SnapshotData),
+}
+
+struct RecordedMessage {
+    receive_timestamp: SystemTime,
+    record: Record,
+}
+
+struct RecordingReader {
+    reader: BufReader<File>,
+    source_names: Vec<String>,
+}
+
+impl RecordingReader {
+    fn open(path: &str) -> anyhow::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            anyhow::bail!("{} is not a recording", path);
+        }
+        let source_count = read_u32(&mut reader)?;
+        let source_names = (0..source_count)
+            .map(|_| {
+                let mut name = vec![0u8; read_u32(&mut reader)? as usize];
+                reader.read_exact(&mut name)?;
+                Ok(String::from_utf8(name)?)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(RecordingReader {
+            reader,
+            source_names,
+        })
+    }
+
+    /// The next message, None at the end of the recording
+    fn next(&mut self) -> anyhow::Result<Option<RecordedMessage>> {
+        let mut kind = [0u8; 1];
+        match self.reader.read_exact(&mut kind) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        let source = read_u32(&mut self.reader)? as usize;
+        let mut timestamp = [0u8; 8];
+        self.reader.read_exact(&mut timestamp)?;
+        let receive_timestamp = UNIX_EPOCH + Duration::from_micros(u64::from_le_bytes(timestamp));
+        let mut message = vec![0u8; read_u32(&mut self.reader)? as usize];
+        self.reader.read_exact(&mut message)?;
+
+        if kind[0] != KIND_SNAPSHOT && source >= self.source_names.len() {
+            anyhow::bail!("bad source index {} in recording", source);
+        }
+        let record = match kind[0] {
+            KIND_REQUEST => Record::Request {
+                source,
+                request: geyser_proto::SubscribeRequest::decode(message.as_slice())?,
+            },
+            KIND_UPDATE => Record::Update {
+                source,
+                update: geyser_proto::Update::decode(message.as_slice())?,
+            },
+            KIND_SNAPSHOT => match geyser_proto::Update::decode(message.as_slice())?.update_oneof {
+                Some(geyser_proto::update::UpdateOneof::AccountsSnapshot(snapshot)) => {
+                    Record::Snapshot(SnapshotData {
+                        slot: snapshot.slot,
+                        accounts: snapshot
+                            .accounts
+                            .into_iter()
+                            .map(account_from_write)
+                            .collect(),
+                    })
+                }
+                _ => anyhow::bail!("bad snapshot record in recording"),
+            },
+            kind => anyhow::bail!("bad record kind {} in recording", kind),
+        };
+        Ok(Some(RecordedMessage {
+            receive_timestamp,
+            record,
+        }))
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Moves the send time of an update by the same amount as its receive time, to
+/// keep the latency metrics meaningful during a replay
+fn shift_send_timestamp(update: &mut geyser_proto::Update, shift_us: i64) {
+    use geyser_proto::update::UpdateOneof;
+    let send_timestamp_us = match update.update_oneof.as_mut() {
+        Some(UpdateOneof::AccountWrite(write)) => &mut write.send_timestamp_us,
+        Some(UpdateOneof::SlotUpdate(slot_update)) => &mut slot_update.send_timestamp_us,
+        _ => return,
+    };
+    if *send_timestamp_us != 0 {
+        *send_timestamp_us = (*send_timestamp_us as i64 + shift_us) as u64;
+    }
+}
+
+async fn replay(
+    path: &str,
+    speed: f64,
+    sender: async_channel::Sender<Message>,
+    reader: RecordingReader,
+    metrics_sender: metrics::Metrics,
+) {
+    let source_names = reader.source_names.clone();
+
+    // read on a separate thread, the file may be large
+    let (record_sender, record_receiver) = async_channel::bounded::<RecordedMessage>(1000);
+    let path_c = path.to_string();
+    std::thread::spawn(move || {
+        let mut reader = reader;
+        loop {
+            match reader.next() {
+                Ok(Some(record)) => {
+                    if futures::executor::block_on(record_sender.send(record)).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(err) => {
+                    error!("could not read recording {}, stopping: {:?}", path_c, err);
+                    return;
+                }
+            }
+        }
+    });
+
+    // Each source's current connection, None after it failed
+    let mut states: Vec<Option<StreamState>> = source_names.iter().map(|_| None).collect();
+    let mut resume_slots: Vec<Option<u64>> = vec![None; source_names.len()];
+    let mut stream_metrics: Vec<_> = source_names
+        .iter()
+        .map(|name| StreamMetrics::new(&metrics_sender, name))
+        .collect();
+
+    let mut start: Option<(Instant, SystemTime)> = None;
+    let mut message_count = 0u64;
+    while let Ok(recorded) = record_receiver.recv().await {
+        let (start_instant, start_timestamp) =
+            *start.get_or_insert((Instant::now(), recorded.receive_timestamp));
+        if speed > 0.0 {
+            let offset = recorded
+                .receive_timestamp
+                .duration_since(start_timestamp)
+                .unwrap_or_default();
+            tokio::time::sleep_until((start_instant + offset.div_f64(speed)).into()).await;
+        }
+
+        let message = match recorded.record {
+            Record::Request { source, request } => {
+                // Strictness doesn't matter here: the recorded connection
+                // ended where a strict source failed.
+                states[source] = Some(StreamState::new(&request, false));
+                continue;
+            }
+            Record::Update { source, mut update } => {
+                let state = match states[source].as_mut() {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let now = SystemTime::now();
+                shift_send_timestamp(
+                    &mut update,
+                    to_us(now) as i64 - to_us(recorded.receive_timestamp) as i64,
+                );
+                let result = state.process(
+                    update,
+                    now,
+                    source,
+                    &mut resume_slots[source],
+                    &mut stream_metrics[source],
+                );
+                // the accounts fetched again were recorded as snapshots
+                state.resync_pubkeys.clear();
+                match result {
+                    Ok(Some(message)) => message,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        warn!(
+                            "recorded connection of {} failed: {:?}",
+                            source_names[source], err
+                        );
+                        states[source] = None;
+                        continue;
+                    }
+                }
+            }
+            Record::Snapshot(snapshot) => Message::Snapshot(snapshot),
+        };
+        sender
+            .send(message)
+            .await
+            .expect("receiver must stay alive");
+        message_count += 1;
+    }
+    info!("replay of {} done, {} messages", path, message_count);
+}
+
+/// Replays a file written by a gRPC source with `record_path` set
+pub struct ReplaySource {
+    pub config: SourceConfig,
+    pub block_metadata_queue_sender: Option<async_channel::Sender<BlockMetadata>>,
+    pub transaction_queue_sender: Option<async_channel::Sender<TransactionUpdate>>,
+}
+
+#[async_trait::async_trait]
+impl Source for ReplaySource {
+    async fn run(
+        &self,
+        account_write_queue_sender: async_channel::Sender<AccountWrite>,
+        slot_queue_sender: async_channel::Sender<SlotUpdate>,
+        metrics_sender: metrics::Metrics,
+    ) {
+        let replay_config = match &self.config.replay {
+            Some(replay_config) => replay_config.clone(),
+            None => {
+                error!("the replay source needs a [source.replay] section");
+                return;
+            }
+        };
+        let reader = match RecordingReader::open(&replay_config.path) {
+            Ok(reader) => reader,
+            Err(err) => {
+                error!("could not open recording {}: {:?}", replay_config.path, err);
+                return;
+            }
+        };
+        let source_names = reader.source_names.clone();
+
+        let (msg_sender, msg_receiver) =
+            async_channel::bounded::<Message>(self.config.dedup_queue_size);
+        // Like the other sources, don't return once the replay is done: the
+        // caller may exit before the targets caught up
+        let _idle_sender = msg_sender.clone();
+        let replay_metrics = metrics_sender.clone();
+        tokio::spawn(async move {
+            replay(
+                &replay_config.path,
+                replay_config.speed,
+                msg_sender,
+                reader,
+                replay_metrics,
+            )
+            .await;
+        });
+
+        process_messages(
+            &source_names,
+            msg_receiver,
+            account_write_queue_sender,
+            slot_queue_sender,
+            self.block_metadata_queue_sender.clone(),
+            self.transaction_queue_sender.clone(),
+            metrics_sender,
+        )
+        .await;
+    }
+}
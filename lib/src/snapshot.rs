@@ -0,0 +1,666 @@
+//! Initial account state for the sources, see SnapshotSourceConfig
+
+use jsonrpc_core_client::transports::http;
+
+use solana_account_decoder::{UiAccount, UiAccountEncoding};
+use solana_client::{
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
+    rpc_response::RpcKeyedAccount,
+};
+use solana_rpc::{rpc::rpc_accounts::AccountsDataClient, rpc::OptionalContext};
+use solana_sdk::{account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey};
+
+use log::*;
+use std::{collections::HashSet, path::Path, str::FromStr, time::Duration};
+
+use crate::{metrics, snapshot_archive, AccountFilterConfig, AnyhowWrap, SnapshotSourceConfig};
+
+/// Maximum number of accounts per getMultipleAccounts request
+const MULTIPLE_ACCOUNTS_CHUNK_SIZE: usize = 100;
+/// Number of tries for each chunk of a snapshot
+const CHUNK_ATTEMPTS: u32 = 5;
+/// Backoff between tries, doubling each time
+const CHUNK_RETRY_SLEEP_MIN: Duration = Duration::from_secs(1);
+const CHUNK_RETRY_SLEEP_MAX: Duration = Duration::from_secs(30);
+/// How often to look for a new enough snapshot archive
+const ARCHIVE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+pub(crate) struct SnapshotData {
+    pub slot: u64,
+    pub accounts: Vec<(Pubkey, Account)>,
+}
+
+#[derive(Clone, Debug)]
+enum AccountFilter {
+    DataSize(u64),
+    Memcmp { offset: usize, bytes: Vec<u8> },
+}
+
+impl AccountFilter {
+    fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            AccountFilter::DataSize(size) => data.len() as u64 == *size,
+            AccountFilter::Memcmp { offset, bytes } => data
+                .get(*offset..offset + bytes.len())
+                .map(|slice| slice == bytes.as_slice())
+                .unwrap_or(false),
+        }
+    }
+
+    fn to_rpc_filter(&self) -> RpcFilterType {
+        match self {
+            AccountFilter::DataSize(size) => RpcFilterType::DataSize(*size),
+            AccountFilter::Memcmp { offset, bytes } => RpcFilterType::Memcmp(Memcmp {
+                offset: *offset,
+                bytes: MemcmpEncodedBytes::Base58(bs58::encode(bytes).into_string()),
+                encoding: None,
+            }),
+        }
+    }
+}
+
+/// The byte at offset must be one of values, if the data reaches that far
+#[derive(Clone, Debug)]
+struct Partition {
+    offset: usize,
+    values: Vec<u8>,
+}
+
+impl Partition {
+    fn matches(&self, data: &[u8]) -> bool {
+        data.get(self.offset)
+            .map(|value| self.values.contains(value))
+            .unwrap_or(true)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ProgramSelection {
+    program_id: Pubkey,
+    filters: Vec<AccountFilter>,
+    partition: Option<Partition>,
+}
+
+impl ProgramSelection {
+    fn matches(&self, data: &[u8]) -> bool {
+        self.filters.iter().all(|f| f.matches(data))
+            && self
+                .partition
+                .as_ref()
+                .map(|partition| partition.matches(data))
+                .unwrap_or(true)
+    }
+}
+
+/// One getProgramAccounts or getMultipleAccounts request of a snapshot
+enum SnapshotChunk {
+    Program {
+        program_id: Pubkey,
+        config: RpcProgramAccountsConfig,
+    },
+    Accounts(Vec<Pubkey>),
+}
+
+/// The accounts that a snapshot contains
+#[derive(Clone, Debug)]
+pub(crate) struct AccountSelection {
+    programs: Vec<ProgramSelection>,
+    accounts: Vec<Pubkey>,
+}
+
+impl AccountSelection {
+    pub fn from_config(config: &SnapshotSourceConfig) -> anyhow::Result<Self> {
+        let programs = config
+            .all_programs()
+            .iter()
+            .map(|program| {
+                let filters = program
+                    .filters
+                    .iter()
+                    .map(|filter| {
+                        Ok(match filter {
+                            AccountFilterConfig::DataSize(size) => AccountFilter::DataSize(*size),
+                            AccountFilterConfig::Memcmp(memcmp) => AccountFilter::Memcmp {
+                                offset: memcmp.offset as usize,
+                                bytes: bs58::decode(&memcmp.bytes).into_vec().map_err_anyhow()?,
+                            },
+                        })
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let partition = program.partition.as_ref().map(|partition| Partition {
+                    offset: partition.offset as usize,
+                    values: if partition.values.is_empty() {
+                        (0..=255).collect()
+                    } else {
+                        partition.values.clone()
+                    },
+                });
+                Ok(ProgramSelection {
+                    program_id: Pubkey::from_str(&program.program_id)?,
+                    filters,
+                    partition,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let accounts = config
+            .accounts
+            .iter()
+            .map(|account| Ok(Pubkey::from_str(account)?))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(AccountSelection { programs, accounts })
+    }
+
+    /// The programSubscribe config for each program
+    pub fn program_accounts_configs<'a>(
+        &'a self,
+        account_config: &'a RpcAccountInfoConfig,
+    ) -> impl Iterator<Item = (&'a Pubkey, RpcProgramAccountsConfig)> + 'a {
+        self.programs.iter().map(move |program| {
+            (
+                &program.program_id,
+                program_accounts_config(
+                    program.filters.iter().map(|f| f.to_rpc_filter()).collect(),
+                    account_config,
+                ),
+            )
+        })
+    }
+
+    /// Just the given accounts
+    pub fn from_pubkeys(accounts: Vec<Pubkey>) -> Self {
+        AccountSelection {
+            programs: vec![],
+            accounts,
+        }
+    }
+
+    pub fn accounts(&self) -> &[Pubkey] {
+        &self.accounts
+    }
+
+    /// The requests that together fetch all selected accounts
+    fn chunks(&self, account_config: &RpcAccountInfoConfig) -> Vec<SnapshotChunk> {
+        let mut chunks = vec![];
+        for program in self.programs.iter() {
+            let filters: Vec<RpcFilterType> =
+                program.filters.iter().map(|f| f.to_rpc_filter()).collect();
+            match &program.partition {
+                None => chunks.push(SnapshotChunk::Program {
+                    program_id: program.program_id,
+                    config: program_accounts_config(filters, account_config),
+                }),
+                Some(partition) => {
+                    for value in partition.values.iter() {
+                        let mut filters = filters.clone();
+                        filters.push(
+                            AccountFilter::Memcmp {
+                                offset: partition.offset,
+                                bytes: vec![*value],
+                            }
+                            .to_rpc_filter(),
+                        );
+                        chunks.push(SnapshotChunk::Program {
+                            program_id: program.program_id,
+                            config: program_accounts_config(filters, account_config),
+                        });
+                    }
+                    // accounts too short to have a byte at offset, by size
+                    for size in 0..=partition.offset as u64 {
+                        let mut filters = filters.clone();
+                        filters.push(AccountFilter::DataSize(size).to_rpc_filter());
+                        chunks.push(SnapshotChunk::Program {
+                            program_id: program.program_id,
+                            config: program_accounts_config(filters, account_config),
+                        });
+                    }
+                }
+            }
+        }
+        for accounts in self.accounts.chunks(MULTIPLE_ACCOUNTS_CHUNK_SIZE) {
+            chunks.push(SnapshotChunk::Accounts(accounts.to_vec()));
+        }
+        chunks
+    }
+
+    fn matcher(&self) -> impl Fn(&Pubkey, &Pubkey, &[u8]) -> bool + '_ {
+        let accounts: HashSet<Pubkey> = self.accounts.iter().cloned().collect();
+        move |pubkey, owner, data| {
+            accounts.contains(pubkey)
+                || self
+                    .programs
+                    .iter()
+                    .any(|program| program.program_id == *owner && program.matches(data))
+        }
+    }
+}
+
+fn program_accounts_config(
+    filters: Vec<RpcFilterType>,
+    account_config: &RpcAccountInfoConfig,
+) -> RpcProgramAccountsConfig {
+    RpcProgramAccountsConfig {
+        filters: if filters.is_empty() {
+            None
+        } else {
+            Some(filters)
+        },
+        with_context: Some(true),
+        account_config: account_config.clone(),
+    }
+}
+
+fn decode_account(pubkey: &str, account: &UiAccount) -> anyhow::Result<(Pubkey, Account)> {
+    let account: Account = account
+        .decode()
+        .ok_or_else(|| anyhow::anyhow!("could not decode snapshot account {}", pubkey))?;
+    Ok((Pubkey::from_str(pubkey)?, account))
+}
+
+/// Decodes an account of a snapshot, accounts that can't be decoded are
+/// skipped and counted
+fn decode_or_skip(
+    pubkey: &str,
+    account: &UiAccount,
+    metric_decode_errors: &mut metrics::MetricU64,
+) -> Option<(Pubkey, Account)> {
+    match decode_account(pubkey, account) {
+        Ok(v) => Some(v),
+        Err(err) => {
+            metric_decode_errors.increment();
+            warn!("skipping snapshot account: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Fetches one chunk, returns its slot and accounts
+async fn get_chunk(
+    rpc_client: &AccountsDataClient,
+    chunk: &SnapshotChunk,
+    account_config: &RpcAccountInfoConfig,
+    metric_decode_errors: &mut metrics::MetricU64,
+) -> anyhow::Result<(u64, Vec<(Pubkey, Account)>)> {
+    match chunk {
+        SnapshotChunk::Program { program_id, config } => {
+            let response = rpc_client
+                .get_program_accounts(program_id.to_string(), Some(config.clone()))
+                .await
+                .map_err_anyhow()?;
+            let response = match response {
+                OptionalContext::Context(response) => response,
+                OptionalContext::NoContext(_) => anyhow::bail!("bad snapshot format"),
+            };
+            let accounts = response
+                .value
+                .iter()
+                .filter_map(|RpcKeyedAccount { pubkey, account }| {
+                    decode_or_skip(pubkey, account, metric_decode_errors)
+                })
+                .collect();
+            Ok((response.context.slot, accounts))
+        }
+        SnapshotChunk::Accounts(pubkeys) => {
+            let response = rpc_client
+                .get_multiple_accounts(
+                    pubkeys.iter().map(|pubkey| pubkey.to_string()).collect(),
+                    Some(account_config.clone()),
+                )
+                .await
+                .map_err_anyhow()?;
+            let mut accounts = vec![];
+            for (pubkey, account) in pubkeys.iter().zip(response.value.iter()) {
+                // missing accounts don't exist (yet)
+                if let Some(account) = account {
+                    accounts.extend(decode_or_skip(
+                        &pubkey.to_string(),
+                        account,
+                        metric_decode_errors,
+                    ));
+                }
+            }
+            Ok((response.context.slot, accounts))
+        }
+    }
+}
+
+/// Connection to one of several rpc nodes, moving on to the next after errors
+struct RpcClients<'a> {
+    urls: &'a [String],
+    index: usize,
+    client: Option<AccountsDataClient>,
+}
+
+impl<'a> RpcClients<'a> {
+    fn url(&self) -> &str {
+        &self.urls[self.index]
+    }
+
+    async fn client(&mut self) -> anyhow::Result<&AccountsDataClient> {
+        if self.client.is_none() {
+            let client = http::connect_with_options::<AccountsDataClient>(self.url(), true)
+                .await
+                .map_err_anyhow()?;
+            self.client = Some(client);
+        }
+        Ok(self.client.as_ref().unwrap())
+    }
+
+    fn fail_over(&mut self) {
+        self.index = (self.index + 1) % self.urls.len();
+        self.client = None;
+    }
+}
+
+/// Fetches the selected accounts with getProgramAccounts and getMultipleAccounts
+///
+/// The snapshot is split into chunks that are fetched one after another. Failed
+/// chunks are retried with backoff, moving on to the next of rpc_http_urls each
+/// time, while the chunks that were already fetched are kept.
+///
+/// The chunks return data for different slots. The snapshot is for the oldest
+/// of them: account writes for later slots take precedence anyway.
+///
+/// Accounts that can't be decoded are left out and counted in
+/// metric_decode_errors.
+pub(crate) async fn get_snapshot_from_rpc(
+    rpc_http_urls: &[String],
+    selection: &AccountSelection,
+    commitment: CommitmentConfig,
+    mut metric_decode_errors: metrics::MetricU64,
+) -> anyhow::Result<SnapshotData> {
+    if rpc_http_urls.is_empty() {
+        anyhow::bail!("no rpc_http_url configured for the snapshot");
+    }
+    let mut rpc_clients = RpcClients {
+        urls: rpc_http_urls,
+        index: 0,
+        client: None,
+    };
+
+    let account_info_config = RpcAccountInfoConfig {
+        encoding: Some(UiAccountEncoding::Base64),
+        commitment: Some(commitment),
+        data_slice: None,
+    };
+
+    let chunks = selection.chunks(&account_info_config);
+    if chunks.is_empty() {
+        anyhow::bail!("no programs or accounts configured for the snapshot");
+    }
+    info!("requesting snapshot in {} chunks", chunks.len());
+
+    let mut slot = u64::MAX;
+    let mut accounts = vec![];
+    for (i, chunk) in chunks.iter().enumerate() {
+        let mut retry_sleep = CHUNK_RETRY_SLEEP_MIN;
+        let mut attempt = 1;
+        loop {
+            let result = match rpc_clients.client().await {
+                Ok(client) => {
+                    get_chunk(
+                        client,
+                        chunk,
+                        &account_info_config,
+                        &mut metric_decode_errors,
+                    )
+                    .await
+                }
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok((chunk_slot, chunk_accounts)) => {
+                    debug!(
+                        "snapshot chunk {}/{}: {} accounts at slot {}",
+                        i + 1,
+                        chunks.len(),
+                        chunk_accounts.len(),
+                        chunk_slot
+                    );
+                    slot = slot.min(chunk_slot);
+                    accounts.extend(chunk_accounts);
+                    break;
+                }
+                Err(err) if attempt < CHUNK_ATTEMPTS => {
+                    warn!(
+                        "snapshot chunk {}/{} failed on {}, retrying in {:?}: {:?}",
+                        i + 1,
+                        chunks.len(),
+                        rpc_clients.url(),
+                        retry_sleep,
+                        err
+                    );
+                    rpc_clients.fail_over();
+                    tokio::time::sleep(retry_sleep).await;
+                    retry_sleep = (retry_sleep * 2).min(CHUNK_RETRY_SLEEP_MAX);
+                    attempt += 1;
+                }
+                Err(err) => {
+                    return Err(err.context(format!(
+                        "snapshot chunk {}/{} failed {} times",
+                        i + 1,
+                        chunks.len(),
+                        attempt
+                    )));
+                }
+            }
+        }
+    }
+
+    // accounts that are selected by pubkey and by their program are fetched twice
+    let mut seen = HashSet::new();
+    accounts.retain(|(pubkey, _)| seen.insert(*pubkey));
+
+    info!("snapshot received");
+    Ok(SnapshotData { slot, accounts })
+}
+
+/// Reads the selected accounts from the newest snapshot archives in archive_dir
+pub(crate) async fn get_snapshot_from_archives(
+    archive_dir: String,
+    selection: AccountSelection,
+    min_slot: u64,
+) -> anyhow::Result<SnapshotData> {
+    // The file names tell the slot: wait for new enough archives before
+    // reading any accounts
+    let archives = loop {
+        let dir = archive_dir.clone();
+        let archives =
+            tokio::task::spawn_blocking(move || snapshot_archive::newest_archives(Path::new(&dir)))
+                .await??;
+        let slot = archives.last().expect("at least one archive").slot;
+        if slot >= min_slot {
+            break archives;
+        }
+        info!(
+            "newest snapshot archive is for slot {}, waiting for slot {}",
+            slot, min_slot
+        );
+        tokio::time::sleep(ARCHIVE_POLL_INTERVAL).await;
+    };
+    tokio::task::spawn_blocking(move || {
+        let slot = archives.last().expect("at least one archive").slot;
+        info!("reading snapshot for slot {} from archives", slot);
+        let accounts = snapshot_archive::read_accounts(&archives, selection.matcher())?;
+        info!("snapshot read");
+        Ok(SnapshotData { slot, accounts })
+    })
+    .await?
+}
+
+/// Gets a snapshot from the archives or the rpc node, as configured
+///
+/// Archives are only read once there is one for min_slot or later. The rpc
+/// node's snapshot may be for an older slot.
+pub(crate) async fn get_snapshot(
+    config: SnapshotSourceConfig,
+    selection: AccountSelection,
+    min_slot: u64,
+    metric_decode_errors: metrics::MetricU64,
+) -> anyhow::Result<SnapshotData> {
+    match config.archive_dir {
+        Some(archive_dir) => get_snapshot_from_archives(archive_dir, selection, min_slot).await,
+        None => {
+            get_snapshot_from_rpc(
+                &config.all_rpc_http_urls(),
+                &selection,
+                CommitmentConfig::finalized(),
+                metric_decode_errors,
+            )
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn selection(config: serde_json::Value) -> AccountSelection {
+        AccountSelection::from_config(&serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_account_filter_matches() {
+        let data_size = AccountFilter::DataSize(3);
+        assert!(data_size.matches(&[1, 2, 3]));
+        assert!(!data_size.matches(&[1, 2]));
+        assert!(!data_size.matches(&[1, 2, 3, 4]));
+
+        let memcmp = AccountFilter::Memcmp {
+            offset: 1,
+            bytes: vec![2, 3],
+        };
+        assert!(memcmp.matches(&[1, 2, 3]));
+        assert!(memcmp.matches(&[0, 2, 3, 4]));
+        assert!(!memcmp.matches(&[1, 2, 4]));
+        // too short for the bytes at offset
+        assert!(!memcmp.matches(&[1, 2]));
+        assert!(!memcmp.matches(&[]));
+    }
+
+    #[test]
+    fn test_selection_matcher() {
+        let program = Pubkey::new_unique();
+        let filtered_program = Pubkey::new_unique();
+        let account = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let selection = selection(serde_json::json!({
+            "program_id": program.to_string(),
+            "programs": [{
+                "program_id": filtered_program.to_string(),
+                "filters": [
+                    { "data_size": 3 },
+                    { "memcmp": { "offset": 0, "bytes": bs58::encode([7]).into_string() } },
+                ],
+            }],
+            "accounts": [account.to_string()],
+        }));
+        let matcher = selection.matcher();
+
+        // any account of the program without filters
+        assert!(matcher(&other, &program, &[]));
+        // all filters must match
+        assert!(matcher(&other, &filtered_program, &[7, 0, 0]));
+        assert!(!matcher(&other, &filtered_program, &[7, 0]));
+        assert!(!matcher(&other, &filtered_program, &[8, 0, 0]));
+        // listed accounts match whatever their owner and data
+        assert!(matcher(&account, &other, &[]));
+        assert!(matcher(&account, &filtered_program, &[8]));
+        assert!(!matcher(&other, &other, &[7, 0, 0]));
+    }
+
+    #[test]
+    fn test_partition_matches() {
+        let partition = Partition {
+            offset: 1,
+            values: vec![3, 5],
+        };
+        assert!(partition.matches(&[0, 3]));
+        assert!(partition.matches(&[0, 5, 0]));
+        assert!(!partition.matches(&[3, 4]));
+        // fetched by the chunks for short accounts
+        assert!(partition.matches(&[3]));
+        assert!(partition.matches(&[]));
+    }
+
+    #[test]
+    fn test_selection_chunks() {
+        let program = Pubkey::new_unique();
+        let accounts: Vec<_> = (0..MULTIPLE_ACCOUNTS_CHUNK_SIZE + 1)
+            .map(|_| Pubkey::new_unique())
+            .collect();
+        let selection = selection(serde_json::json!({
+            "programs": [{
+                "program_id": program.to_string(),
+                "filters": [{ "data_size": 3 }],
+                "partition": { "offset": 1, "values": [3, 5] },
+            }],
+            "accounts": accounts.iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+        }));
+        let account_config = RpcAccountInfoConfig::default();
+        let chunks = selection.chunks(&account_config);
+
+        let program_filters: Vec<_> = chunks
+            .iter()
+            .filter_map(|chunk| match chunk {
+                SnapshotChunk::Program { program_id, config } => {
+                    assert_eq!(*program_id, program);
+                    assert_eq!(config.account_config, account_config);
+                    Some(config.filters.clone().unwrap())
+                }
+                _ => None,
+            })
+            .collect();
+        let memcmp = |value: u8| {
+            AccountFilter::Memcmp {
+                offset: 1,
+                bytes: vec![value],
+            }
+            .to_rpc_filter()
+        };
+        let data_size = RpcFilterType::DataSize(3);
+        assert_eq!(
+            program_filters,
+            vec![
+                vec![data_size.clone(), memcmp(3)],
+                vec![data_size.clone(), memcmp(5)],
+                vec![data_size.clone(), RpcFilterType::DataSize(0)],
+                vec![data_size, RpcFilterType::DataSize(1)],
+            ]
+        );
+
+        let account_chunks: Vec<_> = chunks
+            .iter()
+            .filter_map(|chunk| match chunk {
+                SnapshotChunk::Accounts(pubkeys) => Some(pubkeys.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            account_chunks,
+            vec![
+                accounts[..MULTIPLE_ACCOUNTS_CHUNK_SIZE].to_vec(),
+                accounts[MULTIPLE_ACCOUNTS_CHUNK_SIZE..].to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rpc_clients_fail_over() {
+        let urls = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let mut rpc_clients = RpcClients {
+            urls: &urls,
+            index: 0,
+            client: None,
+        };
+        let mut visited = vec![];
+        for _ in 0..4 {
+            visited.push(rpc_clients.url().to_string());
+            rpc_clients.fail_over();
+            assert!(rpc_clients.client.is_none());
+        }
+        // wraps around to the first url
+        assert_eq!(visited, vec!["a", "b", "c", "a"]);
+    }
+}
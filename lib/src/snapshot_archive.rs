@@ -0,0 +1,216 @@
+//! Reading accounts from the snapshot archives that validators write
+//!
+//! An archive is a zstd compressed tar file. The account data is stored in the
+//! `accounts/<slot>.<id>` append vec files, which hold a sequence of:
+//!
+//! - write_version: u64, data_len: u64, pubkey: [u8; 32]
+//! - lamports: u64, rent_epoch: u64, owner: [u8; 32], executable: u8, 7 bytes padding
+//! - hash: [u8; 32]
+//! - data, padded to a multiple of 8 bytes
+//!
+//! All integers are little endian. The append vec files can be longer than the
+//! data they contain and are zero filled at the end.
+
+use {
+    log::*,
+    solana_sdk::{account::Account, pubkey::Pubkey},
+    std::{
+        collections::{HashMap, HashSet},
+        convert::TryInto,
+        fs::File,
+        io::Read,
+        path::{Path, PathBuf},
+    },
+};
+
+const ACCOUNT_HEADER_SIZE: usize = 136;
+/// Same as the runtime's limit, larger values mean the file is corrupt
+pub(crate) const MAX_ACCOUNT_DATA_LEN: u64 = 10 * 1024 * 1024;
+
+/// A snapshot archive, identified by its file name
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchiveInfo {
+    pub path: PathBuf,
+    pub slot: u64,
+    /// The slot of the full snapshot that an incremental snapshot is based on
+    pub base_slot: Option<u64>,
+}
+
+impl ArchiveInfo {
+    /// Parses `snapshot-<slot>-<hash>.tar.zst` and
+    /// `incremental-snapshot-<base slot>-<slot>-<hash>.tar.zst`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.strip_suffix(".tar.zst")?;
+        let parts: Vec<&str> = name.split('-').collect();
+        let (slot, base_slot) = match parts.as_slice() {
+            ["snapshot", slot, _hash] => (slot.parse().ok()?, None),
+            ["incremental", "snapshot", base_slot, slot, _hash] => {
+                (slot.parse().ok()?, Some(base_slot.parse().ok()?))
+            }
+            _ => return None,
+        };
+        Some(ArchiveInfo {
+            path: path.to_path_buf(),
+            slot,
+            base_slot,
+        })
+    }
+}
+
+/// The newest full snapshot archive in dir, followed by the newest incremental
+/// archive that is based on it, if there is one
+pub fn newest_archives(dir: &Path) -> anyhow::Result<Vec<ArchiveInfo>> {
+    let mut archives = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        if let Some(archive) = ArchiveInfo::from_path(&entry?.path()) {
+            archives.push(archive);
+        }
+    }
+    let full = archives
+        .iter()
+        .filter(|a| a.base_slot.is_none())
+        .max_by_key(|a| a.slot)
+        .ok_or_else(|| anyhow::anyhow!("no snapshot archive in {}", dir.display()))?
+        .clone();
+    let incremental = archives
+        .iter()
+        .filter(|a| a.base_slot == Some(full.slot))
+        .max_by_key(|a| a.slot)
+        .cloned();
+    Ok(std::iter::once(full).chain(incremental).collect())
+}
+
+struct StoredAccount<'a> {
+    write_version: u64,
+    pubkey: Pubkey,
+    lamports: u64,
+    rent_epoch: u64,
+    owner: Pubkey,
+    executable: bool,
+    data: &'a [u8],
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Calls f for each account stored in an append vec, stops at the zero filled end
+fn for_each_stored_account(append_vec: &[u8], mut f: impl FnMut(StoredAccount)) {
+    let mut offset = 0;
+    while offset + ACCOUNT_HEADER_SIZE <= append_vec.len() {
+        let header = &append_vec[offset..offset + ACCOUNT_HEADER_SIZE];
+        let data_len = u64_at(header, 8);
+        let data_start = offset + ACCOUNT_HEADER_SIZE;
+        if data_len > MAX_ACCOUNT_DATA_LEN || data_start + data_len as usize > append_vec.len() {
+            break;
+        }
+        let account = StoredAccount {
+            write_version: u64_at(header, 0),
+            pubkey: Pubkey::new(&header[16..48]),
+            lamports: u64_at(header, 48),
+            rent_epoch: u64_at(header, 56),
+            owner: Pubkey::new(&header[64..96]),
+            executable: header[96] != 0,
+            data: &append_vec[data_start..data_start + data_len as usize],
+        };
+        if account.pubkey == Pubkey::default() && account.lamports == 0 && data_len == 0 {
+            break;
+        }
+        f(account);
+        // align to 8 bytes
+        offset = (data_start + data_len as usize + 7) & !7;
+    }
+}
+
+/// Calls f with the slot and contents of each append vec in the archives
+fn for_each_append_vec(
+    archives: &[ArchiveInfo],
+    mut f: impl FnMut(u64, &[u8]),
+) -> anyhow::Result<()> {
+    let mut buffer = vec![];
+    for archive in archives {
+        info!("reading snapshot archive {}", archive.path.display());
+        let decoder = zstd::Decoder::new(File::open(&archive.path)?)?;
+        let mut tar = tar::Archive::new(decoder);
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            if !path.starts_with("accounts") {
+                continue;
+            }
+            // the file name is <slot>.<id>
+            let slot = match path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split('.').next())
+                .and_then(|slot| slot.parse::<u64>().ok())
+            {
+                Some(slot) => slot,
+                None => continue,
+            };
+            buffer.clear();
+            entry.read_to_end(&mut buffer)?;
+            f(slot, &buffer);
+        }
+    }
+    Ok(())
+}
+
+/// Latest state of all live accounts for which select(pubkey, owner, data) is true
+///
+/// An archive can contain several versions of an account, the one with the
+/// highest slot and write_version is current. Accounts that no longer match in
+/// their current version, like ones that were reassigned to a different owner,
+/// and closed accounts are left out. To do that without tracking every account
+/// on the chain, the archives are read twice.
+pub fn read_accounts(
+    archives: &[ArchiveInfo],
+    select: impl Fn(&Pubkey, &Pubkey, &[u8]) -> bool,
+) -> anyhow::Result<Vec<(Pubkey, Account)>> {
+    // all accounts that were selected at some point
+    let mut candidates = HashSet::<Pubkey>::new();
+    for_each_append_vec(archives, |_, append_vec| {
+        for_each_stored_account(append_vec, |account| {
+            if select(&account.pubkey, &account.owner, account.data) {
+                candidates.insert(account.pubkey);
+            }
+        })
+    })?;
+
+    // pubkey -> (slot, write_version, account)
+    let mut latest = HashMap::<Pubkey, (u64, u64, Account)>::new();
+    for_each_append_vec(archives, |slot, append_vec| {
+        for_each_stored_account(append_vec, |account| {
+            if !candidates.contains(&account.pubkey) {
+                return;
+            }
+            if let Some((latest_slot, latest_write_version, _)) = latest.get(&account.pubkey) {
+                if (*latest_slot, *latest_write_version) > (slot, account.write_version) {
+                    return;
+                }
+            }
+            latest.insert(
+                account.pubkey,
+                (
+                    slot,
+                    account.write_version,
+                    Account {
+                        lamports: account.lamports,
+                        data: account.data.to_vec(),
+                        owner: account.owner,
+                        executable: account.executable,
+                        rent_epoch: account.rent_epoch,
+                    },
+                ),
+            );
+        })
+    })?;
+
+    Ok(latest
+        .into_iter()
+        .filter(|(pubkey, (_, _, account))| {
+            account.lamports > 0 && select(pubkey, &account.owner, &account.data)
+        })
+        .map(|(pubkey, (_, _, account))| (pubkey, account))
+        .collect())
+}
@@ -1,198 +1,361 @@
 use jsonrpc_core::futures::StreamExt;
-use jsonrpc_core_client::transports::{http, ws};
+use jsonrpc_core_client::transports::ws;
 
 use solana_account_decoder::UiAccountEncoding;
 use solana_client::{
-    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
-    //rpc_filter::RpcFilterType,
+    rpc_config::RpcAccountInfoConfig,
     rpc_response::{Response, RpcKeyedAccount},
 };
-use solana_rpc::{
-    rpc::rpc_accounts::AccountsDataClient, rpc::OptionalContext, rpc_pubsub::RpcSolPubSubClient,
-};
+use solana_rpc::rpc_pubsub::RpcSolPubSubClient;
 use solana_sdk::{account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey};
 
 use log::*;
 use std::{
+    collections::HashMap,
     str::FromStr,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    metrics,
+    snapshot::{self, AccountSelection, SnapshotData},
+    AccountWrite, AnyhowWrap, SlotStatus, SlotUpdate, Source, SourceConfig,
 };
 
-use crate::{AccountWrite, AnyhowWrap, SlotStatus, SlotUpdate, SourceConfig};
+/// Reconnect backoff starts here and doubles on each failure
+const RETRY_SLEEP_MIN: Duration = Duration::from_secs(1);
+/// Maximum reconnect backoff; connections that stay up longer reset it
+const RETRY_SLEEP_MAX: Duration = Duration::from_secs(60);
+/// Reconnect if no data arrived for this long
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Number of slots to keep write version counters for
+const WRITE_VERSION_RETENTION: u64 = 50;
 
 enum WebsocketMessage {
     SingleUpdate(Response<RpcKeyedAccount>),
-    SnapshotUpdate(Response<Vec<RpcKeyedAccount>>),
+    SnapshotUpdate(SnapshotData),
     SlotUpdate(Arc<solana_client::rpc_response::SlotUpdate>),
 }
 
-// TODO: the reconnecting should be part of this
 async fn feed_data(
     config: &SourceConfig,
     sender: async_channel::Sender<WebsocketMessage>,
+    metric_decode_errors: metrics::MetricU64,
 ) -> anyhow::Result<()> {
-    let program_id = Pubkey::from_str(&config.snapshot.program_id)?;
-    let snapshot_duration = Duration::from_secs(300);
+    let selection = AccountSelection::from_config(&config.snapshot)?;
 
     let connect = ws::try_connect::<RpcSolPubSubClient>(&config.rpc_ws_url).map_err_anyhow()?;
     let client = connect.await.map_err_anyhow()?;
 
-    let rpc_client =
-        http::connect_with_options::<AccountsDataClient>(&config.snapshot.rpc_http_url, true)
-            .await
-            .map_err_anyhow()?;
-
     let account_info_config = RpcAccountInfoConfig {
         encoding: Some(UiAccountEncoding::Base64),
         commitment: Some(CommitmentConfig::processed()),
         data_slice: None,
     };
-    let program_accounts_config = RpcProgramAccountsConfig {
-        filters: None,
-        with_context: Some(true),
-        account_config: account_info_config.clone(),
-    };
 
-    let mut update_sub = client
-        .program_subscribe(
-            program_id.to_string(),
-            Some(program_accounts_config.clone()),
-        )
-        .map_err_anyhow()?;
+    // one subscription per program and per account, merged into one stream
+    let mut account_subs = vec![];
+    for (program_id, program_accounts_config) in
+        selection.program_accounts_configs(&account_info_config)
+    {
+        let sub = client
+            .program_subscribe(program_id.to_string(), Some(program_accounts_config))
+            .map_err_anyhow()?;
+        account_subs.push(sub.boxed());
+    }
+    for pubkey in selection.accounts() {
+        let pubkey = pubkey.to_string();
+        let sub = client
+            .account_subscribe(pubkey.clone(), Some(account_info_config.clone()))
+            .map_err_anyhow()?;
+        account_subs.push(
+            sub.map(move |update| {
+                update.map(|response| Response {
+                    context: response.context,
+                    value: RpcKeyedAccount {
+                        pubkey: pubkey.clone(),
+                        account: response.value,
+                    },
+                })
+            })
+            .boxed(),
+        );
+    }
+    let mut update_sub = futures::stream::select_all(account_subs);
     let mut slot_sub = client.slots_updates_subscribe().map_err_anyhow()?;
 
-    let mut last_snapshot = Instant::now() - snapshot_duration;
+    // Subscriptions are live, so a snapshot taken now misses no intermediate
+    // writes. One snapshot per connection is enough.
+    let rpc_http_urls = config.snapshot.all_rpc_http_urls();
+    let mut snapshot_future = Box::pin(snapshot::get_snapshot_from_rpc(
+        &rpc_http_urls,
+        &selection,
+        CommitmentConfig::processed(),
+        metric_decode_errors,
+    ));
+    let mut snapshot_done = false;
 
     loop {
-        // occasionally cause a new snapshot to be produced
-        // including the first time
-        if last_snapshot + snapshot_duration <= Instant::now() {
-            let account_snapshot = rpc_client
-                .get_program_accounts(
-                    program_id.to_string(),
-                    Some(program_accounts_config.clone()),
-                )
-                .await
-                .map_err_anyhow()?;
-            if let OptionalContext::Context(account_snapshot_response) = account_snapshot {
+        tokio::select! {
+            snapshot = &mut snapshot_future, if !snapshot_done => {
+                snapshot_done = true;
                 sender
-                    .send(WebsocketMessage::SnapshotUpdate(account_snapshot_response))
+                    .send(WebsocketMessage::SnapshotUpdate(snapshot?))
                     .await
                     .expect("sending must succeed");
-            }
-            last_snapshot = Instant::now();
-        }
-
-        tokio::select! {
+            },
             account = update_sub.next() => {
-                match account {
-                    Some(account) => {
-                        sender.send(WebsocketMessage::SingleUpdate(account.map_err_anyhow()?)).await.expect("sending must succeed");
-                    },
-                    None => {
-                        warn!("account stream closed");
-                        return Ok(());
-                    },
-                }
+                let account = account.ok_or_else(|| anyhow::anyhow!("account stream closed"))?;
+                sender.send(WebsocketMessage::SingleUpdate(account.map_err_anyhow()?)).await.expect("sending must succeed");
             },
             slot_update = slot_sub.next() => {
-                match slot_update {
-                    Some(slot_update) => {
-                        sender.send(WebsocketMessage::SlotUpdate(slot_update.map_err_anyhow()?)).await.expect("sending must succeed");
-                    },
-                    None => {
-                        warn!("slot update stream closed");
-                        return Ok(());
-                    },
-                }
+                let slot_update = slot_update.ok_or_else(|| anyhow::anyhow!("slot update stream closed"))?;
+                sender.send(WebsocketMessage::SlotUpdate(slot_update.map_err_anyhow()?)).await.expect("sending must succeed");
             },
-            _ = tokio::time::sleep(Duration::from_secs(60)) => {
-                warn!("websocket timeout");
-                return Ok(())
+            _ = tokio::time::sleep(RECEIVE_TIMEOUT) => {
+                anyhow::bail!("websocket timeout");
             }
         }
     }
 }
 
-// TODO: rename / split / rework
+/// Assigns write versions to account writes
+///
+/// The websocket notifications carry no write_version. Like the gRPC source,
+/// writes are numbered per (slot, pubkey) starting at 1, so that several writes
+/// to an account in one slot are all kept. 0 is reserved for snapshots.
+#[derive(Default)]
+struct WriteVersions {
+    // slot -> (pubkey -> last assigned write version)
+    slots: HashMap<u64, HashMap<Pubkey, u64>>,
+    newest_slot: u64,
+}
+
+impl WriteVersions {
+    fn next(&mut self, slot: u64, pubkey: Pubkey) -> u64 {
+        if slot > self.newest_slot {
+            self.newest_slot = slot;
+            let retain_from = slot.saturating_sub(WRITE_VERSION_RETENTION);
+            self.slots.retain(|&s, _| s >= retain_from);
+        }
+        let write_version = self
+            .slots
+            .entry(slot)
+            .or_default()
+            .entry(pubkey)
+            .or_insert(0);
+        *write_version += 1;
+        *write_version
+    }
+}
+
+fn decode_keyed_account(keyed_account: &RpcKeyedAccount) -> anyhow::Result<(Pubkey, Account)> {
+    let pubkey = Pubkey::from_str(&keyed_account.pubkey)?;
+    let account: Account = keyed_account
+        .account
+        .decode()
+        .ok_or_else(|| anyhow::anyhow!("could not decode account {}", pubkey))?;
+    Ok((pubkey, account))
+}
+
 pub async fn process_events(
     config: &SourceConfig,
     account_write_queue_sender: async_channel::Sender<AccountWrite>,
     slot_queue_sender: async_channel::Sender<SlotUpdate>,
+    metrics_sender: metrics::Metrics,
 ) {
+    let mut metric_decode_errors = metrics_sender.register_u64(
+        "websocket_decode_errors".into(),
+        metrics::MetricType::Counter,
+    );
+
     // Subscribe to program account updates websocket
-    let (update_sender, update_receiver) = async_channel::unbounded::<WebsocketMessage>();
-    let config = config.clone();
-    tokio::spawn(async move {
-        // if the websocket disconnects, we get no data in a while etc, reconnect and try again
-        loop {
-            let out = feed_data(&config, update_sender.clone());
-            let _ = out.await;
-        }
-    });
+    let (update_sender, update_receiver) =
+        async_channel::bounded::<WebsocketMessage>(config.dedup_queue_size);
+    {
+        let config = config.clone();
+        let mut metric_retries = metrics_sender.register_u64(
+            "websocket_source_connection_retries".into(),
+            metrics::MetricType::Counter,
+        );
+        let metric_status = metrics_sender.register_string("websocket_source_status".into());
+        let metric_decode_errors = metric_decode_errors.clone();
+        tokio::spawn(async move {
+            // if the websocket disconnects, we get no data in a while etc, reconnect and try again
+            let mut retry_sleep = RETRY_SLEEP_MIN;
+            loop {
+                metric_status.set("connected".into());
+                let connected_at = Instant::now();
+                if let Err(err) =
+                    feed_data(&config, update_sender.clone(), metric_decode_errors.clone()).await
+                {
+                    warn!("error during websocket communication. retrying. {:?}", err);
+                }
+
+                metric_status.set("disconnected".into());
+                metric_retries.increment();
 
-    //
-    // The thread that pulls updates and forwards them to postgres
-    //
+                if connected_at.elapsed() > RETRY_SLEEP_MAX {
+                    retry_sleep = RETRY_SLEEP_MIN;
+                }
+                tokio::time::sleep(retry_sleep).await;
+                retry_sleep = (retry_sleep * 2).min(RETRY_SLEEP_MAX);
+            }
+        });
+    }
+
+    let mut write_versions = WriteVersions::default();
+
+    let mut metric_account_writes = metrics_sender.register_u64(
+        "websocket_account_writes".into(),
+        metrics::MetricType::Counter,
+    );
+    let mut metric_account_queue =
+        metrics_sender.register_u64("account_write_queue".into(), metrics::MetricType::Gauge);
+    let mut metric_slot_queue =
+        metrics_sender.register_u64("slot_update_queue".into(), metrics::MetricType::Gauge);
+    let mut metric_slot_updates = metrics_sender.register_u64(
+        "websocket_slot_updates".into(),
+        metrics::MetricType::Counter,
+    );
+    let mut metric_snapshots =
+        metrics_sender.register_u64("websocket_snapshots".into(), metrics::MetricType::Counter);
+    let mut metric_snapshot_account_writes = metrics_sender.register_u64(
+        "websocket_snapshot_account_writes".into(),
+        metrics::MetricType::Counter,
+    );
 
     // copy websocket updates into the postgres account write queue
     loop {
-        let update = update_receiver.recv().await.unwrap();
-        info!("got update message");
+        let update = update_receiver.recv().await.expect("sender must not close");
 
         match update {
             WebsocketMessage::SingleUpdate(update) => {
-                info!("single update");
-                let account: Account = update.value.account.decode().unwrap();
-                let pubkey = Pubkey::from_str(&update.value.pubkey).unwrap();
+                let receive_timestamp = SystemTime::now();
+                let (pubkey, account) = match decode_keyed_account(&update.value) {
+                    Ok(v) => v,
+                    Err(err) => {
+                        metric_decode_errors.increment();
+                        warn!("skipping account update: {:?}", err);
+                        continue;
+                    }
+                };
+
+                metric_account_writes.increment();
+                metric_account_queue.set(account_write_queue_sender.len() as u64);
+
+                let slot = update.context.slot;
+                let write_version = write_versions.next(slot, pubkey);
+                let mut write = AccountWrite::from(pubkey, slot, write_version, account);
+                write.receive_timestamp = Some(receive_timestamp);
                 account_write_queue_sender
-                    .send(AccountWrite::from(pubkey, update.context.slot, 0, account))
+                    .send(write)
                     .await
                     .expect("send success");
             }
-            WebsocketMessage::SnapshotUpdate(update) => {
-                info!("snapshot update");
-                for keyed_account in update.value {
-                    let account: Account = keyed_account.account.decode().unwrap();
-                    let pubkey = Pubkey::from_str(&keyed_account.pubkey).unwrap();
+            WebsocketMessage::SnapshotUpdate(snapshot) => {
+                metric_snapshots.increment();
+                info!("processing snapshot for slot {}...", snapshot.slot);
+                for (pubkey, account) in snapshot.accounts {
+                    metric_snapshot_account_writes.increment();
+                    metric_account_queue.set(account_write_queue_sender.len() as u64);
+
                     account_write_queue_sender
-                        .send(AccountWrite::from(pubkey, update.context.slot, 0, account))
+                        .send(AccountWrite::from(pubkey, snapshot.slot, 0, account))
                         .await
                         .expect("send success");
                 }
+                info!("processing snapshot done");
             }
             WebsocketMessage::SlotUpdate(update) => {
-                info!("slot update");
+                let receive_timestamp = SystemTime::now();
                 let message = match *update {
                     solana_client::rpc_response::SlotUpdate::CreatedBank {
                         slot, parent, ..
-                    } => Some(SlotUpdate {
-                        slot,
-                        parent: Some(parent),
-                        status: SlotStatus::Processed,
-                    }),
+                    } => Some((slot, Some(parent), SlotStatus::Processed)),
+                    solana_client::rpc_response::SlotUpdate::Frozen { slot, .. } => {
+                        Some((slot, None, SlotStatus::Frozen))
+                    }
+                    solana_client::rpc_response::SlotUpdate::Dead { slot, ref err, .. } => {
+                        warn!("slot {} is dead: {}", slot, err);
+                        Some((slot, None, SlotStatus::Dead))
+                    }
                     solana_client::rpc_response::SlotUpdate::OptimisticConfirmation {
                         slot,
                         ..
-                    } => Some(SlotUpdate {
-                        slot,
-                        parent: None,
-                        status: SlotStatus::Confirmed,
-                    }),
+                    } => Some((slot, None, SlotStatus::Confirmed)),
                     solana_client::rpc_response::SlotUpdate::Root { slot, .. } => {
-                        Some(SlotUpdate {
-                            slot,
-                            parent: None,
-                            status: SlotStatus::Rooted,
-                        })
+                        Some((slot, None, SlotStatus::Rooted))
                     }
                     _ => None,
                 };
-                if let Some(message) = message {
-                    slot_queue_sender.send(message).await.expect("send success");
+                if let Some((slot, parent, status)) = message {
+                    metric_slot_updates.increment();
+                    metric_slot_queue.set(slot_queue_sender.len() as u64);
+                    slot_queue_sender
+                        .send(SlotUpdate {
+                            slot,
+                            parent,
+                            status,
+                            send_timestamp: None,
+                            receive_timestamp: Some(receive_timestamp),
+                        })
+                        .await
+                        .expect("send success");
                 }
             }
         }
     }
 }
+
+/// Streams from `config.rpc_ws_url`, with a snapshot from `config.snapshot`
+/// after each connect
+pub struct WebsocketSource {
+    pub config: SourceConfig,
+}
+
+#[async_trait::async_trait]
+impl Source for WebsocketSource {
+    async fn run(
+        &self,
+        account_write_queue_sender: async_channel::Sender<AccountWrite>,
+        slot_queue_sender: async_channel::Sender<SlotUpdate>,
+        metrics_sender: metrics::Metrics,
+    ) {
+        process_events(
+            &self.config,
+            account_write_queue_sender,
+            slot_queue_sender,
+            metrics_sender,
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_versions() {
+        let a = Pubkey::new_unique();
+        let b = Pubkey::new_unique();
+        let mut write_versions = WriteVersions::default();
+
+        // numbered per slot and account, starting at 1
+        assert_eq!(write_versions.next(10, a), 1);
+        assert_eq!(write_versions.next(10, a), 2);
+        assert_eq!(write_versions.next(10, b), 1);
+        assert_eq!(write_versions.next(11, a), 1);
+        // older slots keep counting while they are retained
+        assert_eq!(write_versions.next(10, a), 3);
+
+        // slots more than WRITE_VERSION_RETENTION behind the newest are forgotten
+        let newest = 10 + WRITE_VERSION_RETENTION + 1;
+        assert_eq!(write_versions.next(newest, b), 1);
+        assert!(!write_versions.slots.contains_key(&10));
+        assert!(write_versions.slots.contains_key(&11));
+        assert_eq!(write_versions.next(11, a), 2);
+    }
+}
@@ -0,0 +1,94 @@
+//! Helpers for the tests that run sources against the plugin's scenario test
+//! server
+
+use {
+    solana_geyser_connector_lib::{
+        chain_data::ChainData, memory_target, metrics, Compression, GrpcSourceConfig,
+        MetricsConfig, SnapshotSourceConfig, Source, SourceConfig, SourceKind,
+    },
+    solana_geyser_connector_plugin_grpc::scenario::{Scenario, TestServer},
+    solana_sdk::{account::ReadableAccount, pubkey::Pubkey},
+    std::{
+        str::FromStr,
+        sync::{Arc, RwLock},
+        time::{Duration, Instant},
+    },
+};
+
+/// A source config with one gRPC source connecting to the server
+pub fn source_config(
+    server: &TestServer,
+    kind: SourceKind,
+    compression: Option<Compression>,
+) -> SourceConfig {
+    SourceConfig {
+        kind,
+        dedup_queue_size: 1000,
+        grpc_sources: vec![GrpcSourceConfig {
+            name: "test-server".into(),
+            connection_string: format!("http://{}", server.local_addr),
+            retry_connection_sleep_secs: 0,
+            tls: None,
+            access_token: None,
+            filter: None,
+            snapshot_from_plugin: true,
+            strict_write_versions: false,
+            compression,
+            delta_account_data: true,
+        }],
+        snapshot: SnapshotSourceConfig {
+            rpc_http_url: "".into(),
+            rpc_http_urls: vec![],
+            program_id: "mv3ekLzLbnVPNxjSKvqBpU3ZeZXPQdEC3bp5MDEBG68".into(),
+            programs: vec![],
+            accounts: vec![],
+            archive_dir: None,
+        },
+        rpc_ws_url: "".into(),
+        record_path: None,
+        replay: None,
+    }
+}
+
+pub async fn start_server(scenario: &Scenario) -> TestServer {
+    TestServer::start(
+        "127.0.0.1:0".parse().unwrap(),
+        scenario.service_config.clone(),
+    )
+    .await
+    .unwrap()
+}
+
+/// Starts a source that feeds into the returned ChainData
+pub async fn start_source(source: Box<dyn Source>) -> Arc<RwLock<ChainData>> {
+    let chain_data = Arc::new(RwLock::new(ChainData::new()));
+    let (account_write_queue_sender, slot_queue_sender) =
+        memory_target::init(chain_data.clone()).await.unwrap();
+    let metrics_tx = metrics::start(MetricsConfig {
+        output_stdout: false,
+        prometheus_bind_address: None,
+    })
+    .unwrap();
+    tokio::spawn(async move {
+        source
+            .run(account_write_queue_sender, slot_queue_sender, metrics_tx)
+            .await;
+    });
+    chain_data
+}
+
+pub fn account_data(chain_data: &RwLock<ChainData>, pubkey: &str) -> Option<(u64, Vec<u8>)> {
+    let chain_data = chain_data.read().unwrap();
+    chain_data
+        .account(&Pubkey::from_str(pubkey).unwrap())
+        .ok()
+        .map(|data| (data.account.lamports(), data.account.data().to_vec()))
+}
+
+pub async fn wait_for(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
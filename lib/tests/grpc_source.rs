@@ -0,0 +1,238 @@
+//! Runs the gRPC source against the plugin's scenario test server
+
+mod common;
+
+use {
+    common::{account_data, source_config, start_server, wait_for},
+    solana_geyser_connector_lib::{chain_data::ChainData, make_source, Compression, SourceKind},
+    solana_geyser_connector_plugin_grpc::scenario::{Scenario, Step, TestServer},
+    std::sync::{Arc, RwLock},
+};
+
+/// Starts a gRPC source that feeds into the returned ChainData
+async fn start_source(server: &TestServer) -> Arc<RwLock<ChainData>> {
+    let config = source_config(server, SourceKind::Grpc, Some(Compression::Gzip));
+    common::start_source(make_source(&config, None, None)).await
+}
+
+#[tokio::test]
+async fn test_example_scenario() {
+    let scenario = Scenario::load(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../geyser-plugin-grpc/example-scenario.json"
+    ))
+    .unwrap();
+    let mut server = start_server(&scenario).await;
+    let chain_data = start_source(&server).await;
+    server.run(&scenario.steps).await.unwrap();
+
+    // The write in slot 101 is on a fork that was abandoned, and the
+    // writes after the disconnect were resumed from the plugin
+    wait_for(|| {
+        account_data(&chain_data, "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v")
+            == Some((3, vec![2]))
+    })
+    .await;
+    assert_eq!(
+        account_data(&chain_data, "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin"),
+        Some((1, vec![3]))
+    );
+}
+
+#[tokio::test]
+async fn test_snapshot_from_plugin() {
+    let scenario: Scenario = serde_json::from_str(
+        r#"{
+            "bind_address": "",
+            "service_config": {
+                "broadcast_buffer_size": 100,
+                "subscriber_buffer_size": 100,
+                "replay_buffer_size": 0,
+                "serve_snapshots": true
+            },
+            "steps": [
+                { "slot": { "slot": 100, "parent": 99, "status": "processed" } },
+                { "account_write": { "slot": 100, "pubkey": "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin", "owner": "mv3ekLzLbnVPNxjSKvqBpU3ZeZXPQdEC3bp5MDEBG68", "lamports": 1, "data": "2" } },
+                { "slot": { "slot": 100, "parent": 99, "status": "rooted" } },
+                { "slot": { "slot": 101, "parent": 100, "status": "processed" } },
+                { "account_write": { "slot": 101, "pubkey": "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin", "owner": "mv3ekLzLbnVPNxjSKvqBpU3ZeZXPQdEC3bp5MDEBG68", "lamports": 2, "data": "3" } }
+            ]
+        }"#,
+    )
+    .unwrap();
+    let (first_steps, later_steps) = scenario.steps.split_at(3);
+
+    // Everything before the source connects arrives in the plugin's snapshot
+    let mut server = start_server(&scenario).await;
+    server.run(first_steps).await.unwrap();
+    let chain_data = start_source(&server).await;
+    wait_for(|| {
+        account_data(&chain_data, "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin")
+            == Some((1, vec![1]))
+    })
+    .await;
+
+    // Without a replay buffer, the reconnecting source needs a new snapshot
+    server.disconnect();
+    server.run(later_steps).await.unwrap();
+    server
+        .run_step(&Step::WaitForSubscribers { count: 1 })
+        .await
+        .unwrap();
+    wait_for(|| {
+        account_data(&chain_data, "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin")
+            == Some((2, vec![2]))
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_delta_account_data() {
+    let scenario: Scenario = serde_json::from_str(
+        r#"{
+            "bind_address": "",
+            "service_config": {
+                "broadcast_buffer_size": 100,
+                "subscriber_buffer_size": 100,
+                "serve_snapshots": true,
+                "delta_keyframe_interval": 10
+            },
+            "steps": [
+                { "slot": { "slot": 100, "parent": 99, "status": "processed" } },
+                { "account_write": { "slot": 100, "pubkey": "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin", "owner": "mv3ekLzLbnVPNxjSKvqBpU3ZeZXPQdEC3bp5MDEBG68", "lamports": 1, "data": "11111111111111111111111111111111" } },
+                { "slot": { "slot": 100, "parent": 99, "status": "rooted" } },
+                { "wait_for_subscribers": { "count": 1 } },
+                { "slot": { "slot": 101, "parent": 100, "status": "processed" } },
+                { "account_write": { "slot": 101, "pubkey": "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin", "owner": "mv3ekLzLbnVPNxjSKvqBpU3ZeZXPQdEC3bp5MDEBG68", "lamports": 1, "data": "11111111111111111111111111111112" } },
+                { "account_write": { "slot": 101, "pubkey": "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin", "owner": "mv3ekLzLbnVPNxjSKvqBpU3ZeZXPQdEC3bp5MDEBG68", "lamports": 2, "data": "1111111111111111111111111111119r" } }
+            ]
+        }"#,
+    )
+    .unwrap();
+    let (first_steps, later_steps) = scenario.steps.split_at(3);
+
+    // The writes in slot 101 are deltas against the snapshot and each other
+    let mut server = start_server(&scenario).await;
+    server.run(first_steps).await.unwrap();
+    let chain_data = start_source(&server).await;
+    server.run(later_steps).await.unwrap();
+    let mut expected = vec![0; 32];
+    expected[30] = 2;
+    expected[31] = 1;
+    wait_for(|| {
+        account_data(&chain_data, "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin")
+            == Some((2, expected.clone()))
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_out_of_order_write_is_dropped() {
+    let scenario: Scenario = serde_json::from_str(
+        r#"{
+            "bind_address": "",
+            "service_config": {
+                "broadcast_buffer_size": 100,
+                "subscriber_buffer_size": 100,
+                "replay_buffer_size": 0,
+                "serve_snapshots": true
+            },
+            "steps": [
+                { "wait_for_subscribers": { "count": 1 } },
+                { "slot": { "slot": 100, "parent": 99, "status": "processed" } },
+                { "account_write": { "slot": 100, "pubkey": "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin", "owner": "mv3ekLzLbnVPNxjSKvqBpU3ZeZXPQdEC3bp5MDEBG68", "lamports": 1, "data": "2", "write_version": 10 } },
+                { "account_write": { "slot": 100, "pubkey": "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin", "owner": "mv3ekLzLbnVPNxjSKvqBpU3ZeZXPQdEC3bp5MDEBG68", "lamports": 2, "data": "3", "write_version": 5 } },
+                { "slot": { "slot": 101, "parent": 100, "status": "processed" } },
+                { "account_write": { "slot": 101, "pubkey": "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v", "owner": "mv3ekLzLbnVPNxjSKvqBpU3ZeZXPQdEC3bp5MDEBG68", "lamports": 3, "data": "4" } }
+            ]
+        }"#,
+    )
+    .unwrap();
+
+    // The older write is dropped without reconnecting, which would bring in
+    // the plugin's latest state with a new snapshot
+    let mut server = start_server(&scenario).await;
+    let chain_data = start_source(&server).await;
+    server.run(&scenario.steps).await.unwrap();
+    wait_for(|| {
+        account_data(&chain_data, "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v").is_some()
+    })
+    .await;
+    assert_eq!(
+        account_data(&chain_data, "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin"),
+        Some((1, vec![1]))
+    );
+}
+
+#[tokio::test]
+async fn test_lost_update_is_replayed() {
+    let scenario: Scenario = serde_json::from_str(
+        r#"{
+            "bind_address": "",
+            "service_config": {
+                "broadcast_buffer_size": 100,
+                "subscriber_buffer_size": 100,
+                "replay_buffer_size": 100,
+                "serve_snapshots": true
+            },
+            "steps": [
+                { "wait_for_subscribers": { "count": 1 } },
+                { "slot": { "slot": 100, "parent": 99, "status": "rooted" } },
+                { "slot": { "slot": 101, "parent": 100, "status": "processed" } },
+                { "account_write": { "slot": 101, "pubkey": "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin", "owner": "mv3ekLzLbnVPNxjSKvqBpU3ZeZXPQdEC3bp5MDEBG68", "lamports": 1, "data": "2", "lost": true } },
+                { "account_write": { "slot": 101, "pubkey": "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v", "owner": "mv3ekLzLbnVPNxjSKvqBpU3ZeZXPQdEC3bp5MDEBG68", "lamports": 2, "data": "3" } }
+            ]
+        }"#,
+    )
+    .unwrap();
+
+    // The source notices the gap and reconnects, the plugin replays slot 101
+    let mut server = start_server(&scenario).await;
+    let chain_data = start_source(&server).await;
+    server.run(&scenario.steps).await.unwrap();
+    wait_for(|| {
+        account_data(&chain_data, "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin")
+            == Some((1, vec![1]))
+    })
+    .await;
+    assert_eq!(
+        account_data(&chain_data, "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v"),
+        Some((2, vec![2]))
+    );
+}
+
+#[tokio::test]
+async fn test_stale_write_without_rpc_resnapshots() {
+    let scenario: Scenario = serde_json::from_str(
+        r#"{
+            "bind_address": "",
+            "service_config": {
+                "broadcast_buffer_size": 100,
+                "subscriber_buffer_size": 100,
+                "replay_buffer_size": 0,
+                "serve_snapshots": true
+            },
+            "steps": [
+                { "wait_for_subscribers": { "count": 1 } },
+                { "slot": { "slot": 100, "parent": 99, "status": "processed" } },
+                { "slot": { "slot": 101, "parent": 100, "status": "processed" } },
+                { "account_write": { "slot": 101, "pubkey": "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin", "owner": "mv3ekLzLbnVPNxjSKvqBpU3ZeZXPQdEC3bp5MDEBG68", "lamports": 1, "data": "2" } },
+                { "slot": { "slot": 150, "parent": 101, "status": "rooted" } },
+                { "account_write": { "slot": 101, "pubkey": "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin", "owner": "mv3ekLzLbnVPNxjSKvqBpU3ZeZXPQdEC3bp5MDEBG68", "lamports": 2, "data": "3" } },
+                { "slot": { "slot": 151, "parent": 150, "status": "rooted" } }
+            ]
+        }"#,
+    )
+    .unwrap();
+
+    // The late write is too far behind the rooted slot to be forwarded. There
+    // is no rpc to fetch the account from, so the plugin's next snapshot has it.
+    let mut server = start_server(&scenario).await;
+    let chain_data = start_source(&server).await;
+    server.run(&scenario.steps).await.unwrap();
+    wait_for(|| {
+        account_data(&chain_data, "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin")
+            == Some((2, vec![2]))
+    })
+    .await;
+}
@@ -0,0 +1,57 @@
+//! Records the gRPC source against the plugin's scenario test server and
+//! replays the recording
+
+mod common;
+
+use {
+    common::{account_data, source_config, start_server, start_source, wait_for},
+    solana_geyser_connector_lib::{
+        chain_data::ChainData, grpc_plugin_source::GrpcSource, make_source, recording::Recorder,
+        ReplayConfig, SourceConfig, SourceKind,
+    },
+    solana_geyser_connector_plugin_grpc::scenario::Scenario,
+    std::sync::RwLock,
+};
+
+#[tokio::test]
+async fn test_record_and_replay() {
+    let scenario = Scenario::load(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../geyser-plugin-grpc/example-scenario.json"
+    ))
+    .unwrap();
+    let path = std::env::temp_dir().join(format!("geyser-recording-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut server = start_server(&scenario).await;
+    let expected = |chain_data: &RwLock<ChainData>| {
+        account_data(chain_data, "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v")
+            == Some((3, vec![2]))
+            && account_data(chain_data, "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin")
+                == Some((1, vec![3]))
+    };
+
+    let recorder = Recorder::create(path, &["test-server".to_string()]).unwrap();
+    let recorded = start_source(Box::new(GrpcSource {
+        config: source_config(&server, SourceKind::Grpc, None),
+        block_metadata_queue_sender: None,
+        transaction_queue_sender: None,
+        recorder: Some(recorder.clone()),
+    }))
+    .await;
+    server.run(&scenario.steps).await.unwrap();
+    wait_for(|| expected(&recorded)).await;
+    recorder.flush().await.unwrap();
+
+    // The replay goes through the disconnect, the plugin's replay after it
+    // and the abandoned fork again
+    let replay_config = SourceConfig {
+        replay: Some(ReplayConfig {
+            path: path.into(),
+            speed: 0.0,
+        }),
+        ..source_config(&server, SourceKind::Replay, None)
+    };
+    let replayed = start_source(make_source(&replay_config, None, None)).await;
+    wait_for(|| expected(&replayed)).await;
+    std::fs::remove_file(path).unwrap();
+}
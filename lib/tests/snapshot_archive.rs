@@ -0,0 +1,140 @@
+//! Reads archives written in the validator's snapshot archive format
+
+use {
+    solana_geyser_connector_lib::snapshot_archive::{newest_archives, read_accounts},
+    solana_sdk::{account::Account, pubkey::Pubkey},
+    std::{fs::File, path::Path},
+};
+
+struct Stored {
+    write_version: u64,
+    pubkey: Pubkey,
+    lamports: u64,
+    owner: Pubkey,
+    data: Vec<u8>,
+}
+
+fn append_vec(accounts: &[Stored]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for account in accounts {
+        bytes.extend_from_slice(&account.write_version.to_le_bytes());
+        bytes.extend_from_slice(&(account.data.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&account.pubkey.to_bytes());
+        bytes.extend_from_slice(&account.lamports.to_le_bytes());
+        bytes.extend_from_slice(&0u64.to_le_bytes()); // rent_epoch
+        bytes.extend_from_slice(&account.owner.to_bytes());
+        bytes.extend_from_slice(&[0u8; 8]); // executable, padding
+        bytes.extend_from_slice(&[0u8; 32]); // hash
+        bytes.extend_from_slice(&account.data);
+        bytes.resize((bytes.len() + 7) & !7, 0);
+    }
+    // like the files in a real archive, zero filled at the end
+    bytes.resize(bytes.len() + 1024, 0);
+    bytes
+}
+
+fn write_archive(dir: &Path, name: &str, append_vecs: &[(&str, Vec<u8>)]) {
+    let encoder = zstd::Encoder::new(File::create(dir.join(name)).unwrap(), 0).unwrap();
+    let mut tar = tar::Builder::new(encoder);
+    for (path, data) in append_vecs {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, path, data.as_slice()).unwrap();
+    }
+    tar.into_inner().unwrap().finish().unwrap();
+}
+
+fn pubkey(byte: u8) -> Pubkey {
+    Pubkey::new(&[byte; 32])
+}
+
+#[test]
+fn test_read_accounts() {
+    let dir = std::env::temp_dir().join(format!("snapshot-archive-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let program = pubkey(100);
+    let other = pubkey(101);
+    let stored = |write_version, key, lamports, owner, data: &[u8]| Stored {
+        write_version,
+        pubkey: pubkey(key),
+        lamports,
+        owner,
+        data: data.to_vec(),
+    };
+
+    // an older full snapshot and an incremental one for it, both ignored
+    write_archive(&dir, "snapshot-50-hash.tar.zst", &[]);
+    write_archive(&dir, "incremental-snapshot-50-90-hash.tar.zst", &[]);
+
+    write_archive(
+        &dir,
+        "snapshot-100-hash.tar.zst",
+        &[
+            (
+                "accounts/20.2",
+                append_vec(&[
+                    stored(5, 1, 1, program, &[2, 2]),
+                    stored(6, 2, 1, other, &[]),
+                    stored(7, 3, 0, program, &[]),
+                ]),
+            ),
+            (
+                "accounts/10.1",
+                append_vec(&[
+                    stored(1, 1, 1, program, &[1]),
+                    stored(2, 2, 1, program, &[1]),
+                    stored(3, 3, 1, program, &[1]),
+                    stored(4, 4, 1, other, &[1]),
+                    stored(4, 5, 1, program, &[1, 2, 3, 4, 5, 6, 7, 8, 9]),
+                ]),
+            ),
+            ("snapshots/100/100", vec![0; 16]),
+        ],
+    );
+    write_archive(
+        &dir,
+        "incremental-snapshot-100-130-hash.tar.zst",
+        &[(
+            "accounts/120.3",
+            append_vec(&[stored(8, 1, 2, program, &[3])]),
+        )],
+    );
+
+    let archives = newest_archives(&dir).unwrap();
+    assert_eq!(
+        archives.iter().map(|a| a.slot).collect::<Vec<_>>(),
+        vec![100, 130]
+    );
+
+    let read = |select: &dyn Fn(&Pubkey, &Pubkey, &[u8]) -> bool| {
+        let mut accounts = read_accounts(&archives, select).unwrap();
+        accounts.sort_by_key(|(pubkey, _)| *pubkey);
+        accounts
+    };
+    let account = |lamports, owner, data: &[u8]| Account {
+        lamports,
+        data: data.to_vec(),
+        owner,
+        executable: false,
+        rent_epoch: 0,
+    };
+
+    // 2 was reassigned, 3 was closed and 4 never belonged to the program
+    assert_eq!(
+        read(&|_, owner, _| *owner == program),
+        vec![
+            (pubkey(1), account(2, program, &[3])),
+            (pubkey(5), account(1, program, &[1, 2, 3, 4, 5, 6, 7, 8, 9])),
+        ]
+    );
+
+    // 1 had two bytes of data before, but no longer matches; 4 is selected by pubkey
+    assert_eq!(
+        read(&|key, owner, data| { (*owner == program && data.len() == 2) || *key == pubkey(4) }),
+        vec![(pubkey(4), account(1, other, &[1]))]
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -35,6 +35,8 @@ pub struct Config {
     pub source: SourceConfig,
     pub pnl: PnlConfig,
     pub jsonrpc_server: JsonRpcConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
 }
 
 type PnlData = Vec<(Pubkey, [I80F48; MAX_PAIRS])>;
@@ -193,11 +195,12 @@ async fn main() -> anyhow::Result<()> {
         file.read_to_string(&mut contents)?;
         toml::from_str(&contents).unwrap()
     };
+    config.source.validate()?;
 
     solana_logger::setup_with_default("info");
     info!("startup");
 
-    let metrics_tx = metrics::start();
+    let metrics_tx = metrics::start(config.metrics.clone())?;
 
     let chain_data = Arc::new(RwLock::new(ChainData::new()));
     let pnl_data = Arc::new(RwLock::new(PnlData::new()));
@@ -207,15 +210,11 @@ async fn main() -> anyhow::Result<()> {
     // dropping the handle would exit the server
     let _http_server_handle = start_jsonrpc_server(config.jsonrpc_server.clone(), pnl_data)?;
 
-    // start filling chain_data from the grpc plugin source
+    // start filling chain_data from the configured source
     let (account_write_queue_sender, slot_queue_sender) = memory_target::init(chain_data).await?;
-    grpc_plugin_source::process_events(
-        &config.source,
-        account_write_queue_sender,
-        slot_queue_sender,
-        metrics_tx,
-    )
-    .await;
+    make_source(&config.source, None, None)
+        .run(account_write_queue_sender, slot_queue_sender, metrics_tx)
+        .await;
 
     Ok(())
 }